@@ -1,7 +1,7 @@
 use std::io::Read;
 use std::marker::PhantomData;
 use byteorder::{BigEndian, ReadBytesExt};
-use slice_pair::SlicePairMut;
+use slice_pair::{SlicePair, SlicePairMut};
 
 pub const PREFIX_BYTES: usize = 4;
 
@@ -78,12 +78,44 @@ impl<T: Alloc> BringT<T> {
     self.head_idx >= self.next_idx
   }
 
+  /// Size of the front blob without copying it out. If there's no blobs left, return None.
+  pub fn front_size_bytes(&self) -> Option<usize> {
+    if self.count == 0 { return None; }
+
+    // If no wrapping has occured, it's easy
+    if self.head_idx < self.next_idx {
+      Some(self.buffer[self.head_idx..self.head_idx+PREFIX_BYTES].as_ref().read_u32::<BigEndian>().unwrap() as usize)
+    } else {
+      // Represent our used space as a buffer wrapping from head to tail
+      let (back, front) = self.buffer.split_at(self.head_idx);
+      let pair = SlicePair::new(front, back);
+      Some(pair.range(..PREFIX_BYTES).read_u32::<BigEndian>().unwrap() as usize)
+    }
+  }
+
   pub fn front<'a>(&'a mut self, dst: &mut [u8]) -> Option<Front<'a, T>> {
     self.peek_front(dst).map(move |(src_size_bytes, dst_size_bytes)| {
       Front { bring: self, src_size_bytes, size_bytes: dst_size_bytes }
     })
   }
 
+  /// Like front, but hands then a borrowed view of the front blob rather than copying it out.
+  /// The view wraps around the end of the ring if the blob does. If there's no blobs left, return None
+  pub fn with_front<F, R>(&mut self, then: F) -> Option<R>
+  where F: FnOnce(SlicePair<u8>) -> (R, WithOpt) {
+    let dst_size_bytes = self.front_size_bytes()?;
+    let src_size_bytes = PREFIX_BYTES + dst_size_bytes;
+
+    // Represent our used space as a buffer wrapping from head to tail. Without wrapping, the blob lies in the front half
+    let (back, front) = self.buffer.split_at(self.head_idx);
+    let pair = SlicePair::new(front, back);
+    let (res, opt) = then(pair.range(PREFIX_BYTES..src_size_bytes));
+    if let WithOpt::Pop = opt {
+      self.drop_front(src_size_bytes);
+    }
+    Some(res)
+  }
+
   fn drop_front(&mut self, src_size_bytes: usize) {
     self.count -= 1;
     self.remaining += src_size_bytes;
@@ -112,6 +144,13 @@ impl<T: Alloc> BringT<T> {
     }
   }
 
+  /// Drop the front blob without copying it out. If there's no blobs left, return None. Otherwise return size of blob
+  pub fn skip_front(&mut self) -> Option<usize> {
+    let dst_size_bytes = self.front_size_bytes()?;
+    self.drop_front(PREFIX_BYTES + dst_size_bytes);
+    Some(dst_size_bytes)
+  }
+
   /// Attempt to pop blob off front of ring and write it to dst. If there's no blobs left, return None. Otherwise return size of blob
   pub fn pop_front(&mut self, dst: &mut [u8]) -> Option<usize> {
     self.peek_front(dst).map(|(src_size_bytes, dst_size_bytes)| {
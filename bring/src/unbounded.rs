@@ -1,3 +1,4 @@
+use std::io::IoSlice;
 use byteorder::{BigEndian, WriteBytesExt};
 use slice_pair::SlicePairMut;
 
@@ -32,6 +33,30 @@ impl Bring {
     src.len()
   }
 
+  /// Push the concatenation of several slices to back of ring as a single blob. Allocates new space first if needed.
+  pub fn push_back_vectored(&mut self, srcs: &[IoSlice]) -> usize {
+    let src_len: usize = srcs.iter().map(|src| src.len()).sum();
+    let src_size_bytes = PREFIX_BYTES + src_len;
+    if src_size_bytes > self.remaining { self.grow(src_size_bytes) }
+    self.remaining -= src_size_bytes;
+    self.count += 1;
+
+    // Represent our remaining space as a buffer wrapping from past the end of tail to just before the start of head
+    // If nothing wraps, the blob simply lands in the front half of the pair
+    let (back, front) = self.buffer.split_at_mut(self.next_idx);
+    let mut pair = SlicePairMut::new(front, back);
+    pair.range(..PREFIX_BYTES).write_u32::<BigEndian>(src_len as u32).unwrap();
+    let mut offset = PREFIX_BYTES;
+    for src in srcs {
+      pair.range(offset..offset+src.len()).copy_from_slice(src);
+      offset += src.len();
+    }
+
+    // Update state accordingly
+    self.next_idx = (self.next_idx + src_size_bytes) % self.buffer.len();
+    src_len
+  }
+
   fn grow(&mut self, amount: usize) {
     // Grow the underlying vec. Then update the head and tail to be in their new positions with the same relative offset to the front and back
     let old_len = self.buffer.len();
@@ -178,4 +203,94 @@ mod tests {
       assert!(with_result.is_none());
       assert_eq!(ring.count(), 0);
     }
+
+    #[test]
+    fn front_size_bytes() {
+      // Small enough that the third push wraps around the end of the ring
+      let nums = vec![0u8; (4+3) + (4+4) + 2];
+      let mut dst =  [0u8; 5];
+
+      let mut ring = super::Bring::from_vec(nums);
+      assert_eq!(ring.front_size_bytes(), None);
+
+      ring.push_back(&[1,2,3]);
+      ring.push_back(&[4,5,6,7]);
+      assert_eq!(ring.front_size_bytes(), Some(3));
+
+      ring.pop_front(&mut dst);
+      ring.push_back(&[8,9,10,11,12]);
+      assert_eq!(ring.front_size_bytes(), Some(4));
+
+      ring.pop_front(&mut dst);
+      assert_eq!(ring.front_size_bytes(), Some(5));
+      assert_eq!(ring.count(), 1);
+    }
+
+    #[test]
+    fn with_front_views_in_place() {
+      // Small enough that the third push wraps around the end of the ring, two bytes into its payload
+      let nums = vec![0u8; (4+3) + (4+4) + 6];
+      let mut dst =  [0u8; 5];
+
+      let mut ring = super::Bring::from_vec(nums);
+      assert_eq!(ring.with_front(|_| ((), crate::WithOpt::Pop)), None);
+
+      ring.push_back(&[1,2,3]);
+      ring.push_back(&[4,5,6,7]);
+      let peeked = ring.with_front(|data| (data.to_vec(), crate::WithOpt::Peek));
+      assert_eq!(peeked, Some(vec![1,2,3]));
+
+      ring.pop_front(&mut dst);
+      ring.push_back(&[8,9,10,11,12]);
+      ring.with_front(|data| {
+        assert_eq!(data.as_slices(), (&[4u8,5,6,7][..], &[][..]));
+        ((), crate::WithOpt::Pop)
+      });
+
+      // The last blob wraps, so the view is split in two
+      ring.with_front(|data| {
+        assert_eq!(data.as_slices(), (&[8u8,9][..], &[10u8,11,12][..]));
+        ((), crate::WithOpt::Pop)
+      });
+      assert_eq!(ring.count(), 0);
+    }
+
+    #[test]
+    fn push_back_vectored() {
+      use std::io::IoSlice;
+      let mut dst =  [0u8; 5];
+
+      let mut ring = super::Bring::from_vec(vec![0u8; (4+3) + 2]);
+      ring.push_back(&[1,2,3]);
+      let pushed = ring.push_back_vectored(&[IoSlice::new(&[4,5]), IoSlice::new(&[]), IoSlice::new(&[6,7])]);
+      assert_eq!(pushed, 4);
+
+      ring.pop_front(&mut dst);
+      assert_eq!(dst[..3], [1,2,3]);
+
+      // Wraps around the end of the ring
+      ring.push_back_vectored(&[IoSlice::new(&[8,9,10]), IoSlice::new(&[11,12])]);
+
+      ring.pop_front(&mut dst);
+      assert_eq!(dst[..4], [4,5,6,7]);
+      ring.pop_front(&mut dst);
+      assert_eq!(dst[..5], [8,9,10,11,12]);
+      assert_eq!(ring.count(), 0);
+    }
+
+    #[test]
+    fn skip_front() {
+      let mut dst =  [0u8; 2];
+
+      let mut ring = super::Bring::from_vec(vec![]);
+      ring.push_back(&[1,2,3]);
+      ring.push_back(&[4,5]);
+
+      // Too large to pop, but can still be skipped
+      assert_eq!(ring.pop_front(&mut dst), None);
+      assert_eq!(ring.skip_front(), Some(3));
+      ring.pop_front(&mut dst);
+      assert_eq!(dst, [4,5]);
+      assert_eq!(ring.skip_front(), None);
+    }
 }
@@ -1,4 +1,5 @@
-use std::sync::{Mutex, MutexGuard, PoisonError, Condvar};
+use std::sync::{Mutex, MutexGuard, PoisonError, Condvar, WaitTimeoutResult};
+use std::time::Duration;
 use std::ops::{Deref, DerefMut};
 use std::fmt;
 
@@ -50,6 +51,17 @@ impl<T> CondMutex<T> {
     self.mx.lock()
       .map(|guard| CondMutexGuard { _tag: self._tag, guard, cv: &self.cv })
   }
+
+  // Like lock, but takes the lock even if it was poisoned by a panicking holder.
+  // The bool is true when it was, in which case the resource may be only partially updated.
+  // The lock stays poisoned for everyone else.
+  pub fn lock_recover(&self) -> (CondMutexGuard<'_, T>, bool) {
+    let (guard, poisoned) = match self.mx.lock() {
+      Ok(guard) => (guard, false),
+      Err(poisoned) => (poisoned.into_inner(), true)
+    };
+    (CondMutexGuard { _tag: self._tag, guard, cv: &self.cv }, poisoned)
+  }
 }
 
 #[derive(Debug)]
@@ -74,12 +86,23 @@ impl <'a, T> CondMutexGuard<'a, T> {
   }
 
   pub fn wait(self) -> Result<CondMutexGuard<'a, T>, LockError<'a, T>> {
-    let _tag = self._tag;
     let cv = self.cv;
     let guard = self.guard;
     let res = cv.wait(guard);
 
-    res.map(|guard| CondMutexGuard { _tag, guard, cv })
+    res.map(|guard| CondMutexGuard { _tag: (), guard, cv })
+  }
+
+  // Like wait, but gives up after the given duration.
+  // The WaitTimeoutResult reports whether the wait ended by timing out rather than by notification.
+  pub fn wait_timeout(self, dur: Duration) -> Result<(CondMutexGuard<'a, T>, WaitTimeoutResult), LockError<'a, T>> {
+    let cv = self.cv;
+    let guard = self.guard;
+    let res = cv.wait_timeout(guard, dur);
+
+    res
+      .map(|(guard, timeout)| (CondMutexGuard { _tag: (), guard, cv }, timeout))
+      .map_err(|poisoned| PoisonError::new(poisoned.into_inner().0))
   }
 
   pub fn notify_one(&self) {
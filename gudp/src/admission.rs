@@ -0,0 +1,140 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::fmt;
+
+use crate::service::Conf;
+use crate::constants::control;
+
+/// Verdict of an admission callback on a new peer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Admission {
+  Accept,
+  Reject
+}
+
+/// A CIDR-style range of ip addresses, such as 10.0.0.0/8 or ::1/128
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IpRange {
+  addr: IpAddr,
+  prefix_len: u8
+}
+
+impl IpRange {
+  // Returns None if the prefix length is too long for the address family
+  pub fn new(addr: IpAddr, prefix_len: u8) -> Option<IpRange> {
+    let max_len = match addr { IpAddr::V4(_) => 32, IpAddr::V6(_) => 128 };
+    if prefix_len > max_len { return None; }
+    Some(IpRange { addr, prefix_len })
+  }
+
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    match (self.addr, ip) {
+      (IpAddr::V4(range), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+        (u32::from(range) & mask) == (u32::from(ip) & mask)
+      },
+      (IpAddr::V6(range), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+        (u128::from(range) & mask) == (u128::from(ip) & mask)
+      },
+      _ => false
+    }
+  }
+}
+
+impl From<IpAddr> for IpRange {
+  // A range holding exactly one address
+  fn from(addr: IpAddr) -> IpRange {
+    let prefix_len = match addr { IpAddr::V4(_) => 32, IpAddr::V6(_) => 128 };
+    IpRange { addr, prefix_len }
+  }
+}
+
+#[derive(Debug)]
+pub struct ParseIpRangeError();
+
+impl fmt::Display for ParseIpRangeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "invalid ip range syntax")
+  }
+}
+
+impl std::error::Error for ParseIpRangeError {}
+
+impl FromStr for IpRange {
+  type Err = ParseIpRangeError;
+
+  // Either a bare address or <address>/<prefix length>
+  fn from_str(s: &str) -> Result<IpRange, ParseIpRangeError> {
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next()
+      .and_then(|addr| addr.parse::<IpAddr>().ok())
+      .ok_or(ParseIpRangeError())?;
+
+    match parts.next() {
+      None => Ok(IpRange::from(addr)),
+      Some(prefix_len) => prefix_len.parse::<u8>().ok()
+        .and_then(|prefix_len| IpRange::new(addr, prefix_len))
+        .ok_or(ParseIpRangeError())
+    }
+  }
+}
+
+// Why a new peer was turned away before any connection state was allocated for it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Refusal {
+  ServerFull,
+  Denied,
+  Blocked
+}
+
+impl Refusal {
+  // The disconnect reason to tell the peer, if we tell them anything at all
+  pub fn reason(self) -> Option<u8> {
+    match self {
+      Refusal::ServerFull => Some(control::reason::SERVER_FULL),
+      Refusal::Denied => Some(control::reason::DENIED),
+      Refusal::Blocked => None
+    }
+  }
+}
+
+// Decides whether a new peer may connect to a listener which already has `n_peers` peers
+pub fn check(conf: &mut Conf, peer_addr: SocketAddr, n_peers: usize, first_payload: &[u8]) -> Result<(), Refusal> {
+  let ip = peer_addr.ip();
+  if conf.deny.iter().any(|range| range.contains(ip)) { return Err(Refusal::Blocked); }
+  if !conf.allow.is_empty() && !conf.allow.iter().any(|range| range.contains(ip)) { return Err(Refusal::Blocked); }
+  if conf.max_peers.map(|max_peers| n_peers >= max_peers).unwrap_or(false) { return Err(Refusal::ServerFull); }
+
+  match conf.admit.as_mut().map(|f| f(peer_addr, first_payload)) {
+    Some(Admission::Reject) => Err(Refusal::Denied),
+    Some(Admission::Accept) | None => Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::IpRange;
+
+  #[test]
+  fn ip_range_contains() {
+    let range: IpRange = "10.1.0.0/16".parse().unwrap();
+    assert!(range.contains("10.1.2.3".parse().unwrap()));
+    assert!(!range.contains("10.2.0.1".parse().unwrap()));
+    assert!(!range.contains("::1".parse().unwrap()));
+
+    let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+    assert!(everything.contains("192.168.0.1".parse().unwrap()));
+
+    let single: IpRange = "::1".parse().unwrap();
+    assert!(single.contains("::1".parse().unwrap()));
+    assert!(!single.contains("::2".parse().unwrap()));
+  }
+
+  #[test]
+  fn ip_range_parse_errors() {
+    assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+    assert!("10.0.0/8".parse::<IpRange>().is_err());
+    assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+  }
+}
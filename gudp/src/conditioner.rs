@@ -0,0 +1,327 @@
+use std::cmp::{self, Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+
+use crate::constants::CONDITIONER_QUEUE_MAX;
+use crate::error;
+use crate::service::Conf;
+use crate::socket;
+use crate::transport::{Transport, Output};
+
+/// Conditions imposed on datagrams travelling one way through the daemon, to rehearse a bad link.
+/// Percentages range from 0 to 100. The default is a perfect link
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Conditions {
+  // Added to every datagram, plus or minus up to `jitter`
+  pub delay: Duration,
+  pub jitter: Duration,
+  pub loss: Loss,
+  pub duplicate_pct: f64,
+
+  // Chance a datagram skips the delay, overtaking those sent before it
+  pub reorder_pct: f64,
+
+  // Datagrams queue behind each other to leave no faster than this
+  pub bytes_per_sec: Option<u64>
+}
+
+impl Default for Conditions {
+  fn default() -> Conditions {
+    Conditions {
+      delay: Duration::from_millis(0),
+      jitter: Duration::from_millis(0),
+      loss: Loss::None,
+      duplicate_pct: 0.0,
+      reorder_pct: 0.0,
+      bytes_per_sec: None
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Loss {
+  None,
+
+  // Each datagram is lost independently
+  Random { pct: f64 },
+
+  // The link flips between a good and a bad state before each datagram, losing at that state's rate.
+  // Lingering in the bad state makes losses come in bursts
+  GilbertElliott { good_to_bad_pct: f64, bad_to_good_pct: f64, good_loss_pct: f64, bad_loss_pct: f64 }
+}
+
+// splitmix64. Small, fast and plenty random for simulating a network; the same seed always gives the same link
+pub struct Rng(u64);
+
+impl Rng {
+  pub fn new(seed: u64) -> Rng {
+    Rng(seed)
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+
+  // Uniform in [0, 1)
+  pub fn unit(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  pub fn pct(&mut self, pct: f64) -> bool {
+    pct > 0.0 && self.unit() * 100.0 < pct
+  }
+}
+
+// A datagram held back until its release time. Ties release in the order they were held
+struct Held<K> {
+  at: Instant,
+  seq: u64,
+  key: K,
+  datagram: Vec<u8>
+}
+
+impl<K> PartialEq for Held<K> {
+  fn eq(&self, other: &Self) -> bool {
+    (self.at, self.seq) == (other.at, other.seq)
+  }
+}
+
+impl<K> Eq for Held<K> {}
+
+impl<K> PartialOrd for Held<K> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl<K> Ord for Held<K> {
+  fn cmp(&self, other: &Self) -> Ordering {
+    (self.at, self.seq).cmp(&(other.at, other.seq))
+  }
+}
+
+type Queue<K> = BinaryHeap<Reverse<Held<K>>>;
+
+// One way of the link, and the state its conditions need
+struct Direction {
+  conditions: Conditions,
+  bad: bool,
+  free_at: Option<Instant>,
+  held: usize
+}
+
+impl Direction {
+  fn new(conditions: Conditions) -> Direction {
+    Direction { conditions, bad: false, free_at: None, held: 0 }
+  }
+
+  // When each copy of a datagram arrives at the far end of the link; none if it is lost, two if duplicated
+  fn fates(&mut self, rng: &mut Rng, now: Instant, size: usize) -> [Option<Instant>; 2] {
+    if self.lost(rng) { return [None, None]; }
+    let first = self.release(rng, now, size);
+    let copy = if rng.pct(self.conditions.duplicate_pct) { Some(self.release(rng, now, size)) } else { None };
+    [Some(first), copy]
+  }
+
+  fn lost(&mut self, rng: &mut Rng) -> bool {
+    if self.conditions.bytes_per_sec == Some(0) { return true; }
+    match self.conditions.loss {
+      Loss::None => false,
+      Loss::Random { pct } => rng.pct(pct),
+      Loss::GilbertElliott { good_to_bad_pct, bad_to_good_pct, good_loss_pct, bad_loss_pct } => {
+        self.bad = if self.bad { !rng.pct(bad_to_good_pct) } else { rng.pct(good_to_bad_pct) };
+        rng.pct(if self.bad { bad_loss_pct } else { good_loss_pct })
+      }
+    }
+  }
+
+  fn release(&mut self, rng: &mut Rng, now: Instant, size: usize) -> Instant {
+    // A capped link sends one datagram at a time, so each waits for the one before to finish
+    let sent = match self.conditions.bytes_per_sec {
+      Some(bytes_per_sec) => {
+        let start = cmp::max(self.free_at.unwrap_or(now), now);
+        let free_at = start + Duration::from_secs_f64(size as f64 / bytes_per_sec as f64);
+        self.free_at = Some(free_at);
+        free_at
+      },
+      None => now
+    };
+
+    if rng.pct(self.conditions.reorder_pct) { return sent; }
+    let jitter = self.conditions.jitter.as_secs_f64() * (rng.unit() * 2.0 - 1.0);
+    sent + Duration::from_secs_f64(f64::max(0.0, self.conditions.delay.as_secs_f64() + jitter))
+  }
+}
+
+// Sits between the daemon and its transport, applying each direction's conditions.
+// Held outbound datagrams are sent by the daemon loop once due; held inbound ones are received by the next read of their socket
+pub struct Link {
+  rng: Rng,
+  seq: u64,
+  inbound: Option<Direction>,
+  outbound: Option<Direction>,
+  held_in: HashMap<Token, Queue<SocketAddr>>,
+  held_out: Queue<socket::Id>
+}
+
+impl Link {
+  // None unless the conf asks for any conditions. An unseeded link is seeded randomly
+  pub fn new(conf: &Conf) -> io::Result<Option<Link>> {
+    if conf.inbound_conditions.is_none() && conf.outbound_conditions.is_none() { return Ok(None); }
+
+    let seed = match conf.conditioner_seed {
+      Some(seed) => seed,
+      None => {
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).map_err(error::no_randomness)?;
+        u64::from_be_bytes(bytes)
+      }
+    };
+
+    Ok(Some(Link {
+      rng: Rng::new(seed),
+      seq: 0,
+      inbound: conf.inbound_conditions.map(Direction::new),
+      outbound: conf.outbound_conditions.map(Direction::new),
+      held_in: HashMap::new(),
+      held_out: BinaryHeap::new()
+    }))
+  }
+
+  // Lost and held datagrams count as sent, just like any datagram lost beyond the socket
+  pub fn send_to<O: Output>(&mut self, io: &O, socket_id: socket::Id, datagram: &[u8], now: Instant) -> io::Result<usize> {
+    let direction = match self.outbound.as_mut() {
+      Some(direction) => direction,
+      None => return io.send_to(datagram, socket_id.1)
+    };
+
+    let mut sent = Ok(datagram.len());
+    for (copy, at) in direction.fates(&mut self.rng, now, datagram.len()).iter().enumerate() {
+      match *at {
+        Some(at) if at > now => hold(&mut self.held_out, direction, &mut self.seq, at, socket_id, datagram),
+        Some(_) if copy == 0 => sent = io.send_to(datagram, socket_id.1),
+        // Only the original's error matters; a duplicate is best effort
+        Some(_) => { io.send_to(datagram, socket_id.1).ok(); },
+        None => { }
+      }
+    }
+    sent
+  }
+
+  // Datagrams read from the io pass through the inbound conditions and are held until due, so this may
+  // read several before returning one, or return WouldBlock having read some
+  pub fn recv_from<T: Transport>(&mut self, io: &T, token: Token, buf: &mut [u8], now: Instant) -> io::Result<(usize, SocketAddr)> {
+    let direction = match self.inbound.as_mut() {
+      Some(direction) => direction,
+      None => return io.recv_from(buf)
+    };
+
+    loop {
+      let queue = self.held_in.entry(token).or_default();
+      if queue.peek().map(|Reverse(held)| held.at <= now).unwrap_or(false) {
+        if let Some(Reverse(held)) = queue.pop() {
+          direction.held -= 1;
+          let size = usize::min(held.datagram.len(), buf.len());
+          buf[..size].copy_from_slice(&held.datagram[..size]);
+          return Ok((size, held.key));
+        }
+      }
+
+      let (size, from) = io.recv_from(buf)?;
+      for at in direction.fates(&mut self.rng, now, size).iter().flatten() {
+        hold(queue, direction, &mut self.seq, *at, from, &buf[..size]);
+      }
+    }
+  }
+
+  // The earliest any held datagram is due
+  pub fn next_release(&self) -> Option<Instant> {
+    self.held_in.values()
+      .filter_map(|queue| queue.peek().map(|Reverse(held)| held.at))
+      .chain(self.held_out.peek().map(|Reverse(held)| held.at))
+      .min()
+  }
+
+  // Held outbound datagrams now due, to be sent on their socket
+  pub fn pop_outbound(&mut self, now: Instant) -> Option<(socket::Id, Vec<u8>)> {
+    if !self.held_out.peek().map(|Reverse(held)| held.at <= now).unwrap_or(false) { return None; }
+    let Reverse(held) = self.held_out.pop()?;
+    if let Some(direction) = self.outbound.as_mut() { direction.held -= 1; }
+    Some((held.key, held.datagram))
+  }
+
+  // Sockets with held inbound datagrams now due, which need reading
+  pub fn ready_inbound(&self, now: Instant, tokens: &mut Vec<Token>) {
+    tokens.extend(self.held_in.iter()
+      .filter(|(_, queue)| queue.peek().map(|Reverse(held)| held.at <= now).unwrap_or(false))
+      .map(|(token, _)| *token));
+  }
+
+  // Drops whatever is held for a socket that is gone
+  pub fn forget(&mut self, token: Token) {
+    if let (Some(queue), Some(direction)) = (self.held_in.remove(&token), self.inbound.as_mut()) {
+      direction.held -= queue.len();
+    }
+  }
+}
+
+// Like a router's buffer, a full link drops whatever else comes along
+fn hold<K>(queue: &mut Queue<K>, direction: &mut Direction, seq: &mut u64, at: Instant, key: K, datagram: &[u8]) {
+  if direction.held >= CONDITIONER_QUEUE_MAX { return; }
+  direction.held += 1;
+  *seq += 1;
+  queue.push(Reverse(Held { at, seq: *seq, key, datagram: datagram.to_vec() }));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn losses(loss: Loss, seed: u64, count: usize) -> Vec<bool> {
+    let mut rng = Rng::new(seed);
+    let mut direction = Direction::new(Conditions { loss, ..Conditions::default() });
+    (0..count).map(|_| direction.lost(&mut rng)).collect()
+  }
+
+  #[test]
+  fn same_seed_same_link() {
+    let loss = Loss::Random { pct: 50.0 };
+    assert_eq!(losses(loss, 7, 256), losses(loss, 7, 256));
+    assert_ne!(losses(loss, 7, 256), losses(loss, 8, 256));
+
+    let lost = losses(loss, 7, 10_000).iter().filter(|lost| **lost).count();
+    assert!((4_500..5_500).contains(&lost));
+  }
+
+  #[test]
+  fn gilbert_elliott_loses_in_bursts() {
+    // Rarely turns bad, but then loses everything until it recovers
+    let loss = Loss::GilbertElliott { good_to_bad_pct: 1.0, bad_to_good_pct: 10.0, good_loss_pct: 0.0, bad_loss_pct: 100.0 };
+    let lost = losses(loss, 3, 10_000);
+    let total = lost.iter().filter(|lost| **lost).count();
+    let bursts = lost.windows(2).filter(|pair| pair[1] && !pair[0]).count();
+    assert!(total > 0);
+    assert!(total / bursts >= 5, "{} losses in {} bursts", total, bursts);
+  }
+
+  #[test]
+  fn capped_bandwidth_spaces_datagrams() {
+    let now = Instant::now();
+    let mut rng = Rng::new(0);
+    let conditions = Conditions { delay: Duration::from_millis(50), bytes_per_sec: Some(1_000), ..Conditions::default() };
+    let mut direction = Direction::new(conditions);
+
+    let first = direction.fates(&mut rng, now, 100)[0].unwrap();
+    let second = direction.fates(&mut rng, now, 100)[0].unwrap();
+    assert_eq!(first, now + Duration::from_millis(150));
+    assert_eq!(second, now + Duration::from_millis(250));
+  }
+}
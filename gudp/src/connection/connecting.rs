@@ -0,0 +1,28 @@
+use std::io;
+
+use crossbeam::channel;
+
+use crate::error;
+use crate::service;
+use crate::Connection;
+use crate::types::FromDaemon;
+
+/// A connection still handshaking on a manually stepped daemon. It finishes as the daemon is stepped
+pub struct Connecting {
+  rx: channel::Receiver<FromDaemon>
+}
+
+impl Connecting {
+  pub fn new(rx: channel::Receiver<FromDaemon>) -> Connecting {
+    Connecting { rx }
+  }
+
+  // None until the peer replies. Fails if the peer refused us, or the daemon gave up on the handshake
+  pub fn try_finish(&self) -> Option<io::Result<Connection>> {
+    match self.rx.try_recv() {
+      Ok(received) => Some(service::into_connection(received, self.rx.clone())),
+      Err(channel::TryRecvError::Empty) => None,
+      Err(channel::TryRecvError::Disconnected) => Some(Err(error::cannot_recv_from_daemon(channel::RecvError)))
+    }
+  }
+}
@@ -1,117 +1,113 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::atomic::Ordering::SeqCst as OSeqCst;
+use std::time::{Duration, Instant};
+use std::io;
 
 use crate::types::OnWrite;
 use crate::state;
 use crate::error;
-
-use std::io;
+use crate::connection::ops;
+use crate::connection::refs::{self, Refs};
+use crate::connection::{SendHalf, RecvHalf};
 
 pub type Id = (SocketAddr, SocketAddr);
 
 // A user-facing GUDP Connection interface
-#[derive(Clone)]
+// Clones share the connection; the app only hangs up once every clone (and every split half) is dropped.
 pub struct Connection {
   on_write: Arc<OnWrite>,
   shared: Arc<state::Shared>,
+  refs: Arc<Refs>,
   id: Id // Local addr, Peer Addr
 }
 
+impl Clone for Connection {
+  fn clone(&self) -> Connection {
+    self.refs.acquire(refs::SENDER | refs::RECEIVER);
+    Connection {
+      on_write: Arc::clone(&self.on_write),
+      shared: Arc::clone(&self.shared),
+      refs: Arc::clone(&self.refs),
+      id: self.id
+    }
+  }
+}
+
 impl Drop for Connection {
   fn drop(&mut self) {
-    let (_, _, ref status, _) = *self.shared;
-    status.set_app_hup();
+    let (_, _, ref status, _, _, _) = *self.shared;
+    self.refs.release(refs::SENDER | refs::RECEIVER, status);
   }
 }
 
 impl Connection {
     pub fn new(on_write: Arc<OnWrite>, shared: Arc<state::Shared>, id: Id) -> Connection {
-      Connection { on_write, shared, id }
-    }
-
-    #[inline]
-    pub fn rtt_ms(&self) -> u32 {
-      let (_, _, _, ref netstat_out) = *self.shared;
-      netstat_out.rtt.load(OSeqCst)
-    }
-
-    #[inline]
-    pub fn loss_pct(&self) -> u32 {
-      let (_, _, _, ref netstat_out) = *self.shared;
-      netstat_out.loss.load(OSeqCst)
+      let refs = Arc::new(Refs::new(refs::SENDER | refs::RECEIVER));
+      Connection { on_write, shared, refs, id }
     }
 
-    // TODO: Add TrySend with a condvar + mutex around the write buffer and a buffer size limit
-    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
-      let (ref _buf_read, ref buf_write, ref status, _) = *self.shared;
-      status.check_err()?;
-
-      let mut buf_write = buf_write.lock().map_err(error::poisoned_write_lock)?;
-      let size = buf_write.push_back(buf);
-      drop(buf_write);
-
-      (self.on_write)(size) // Wake on send to flush all writes immediately
+    // Splits into independently owned send and receive halves.
+    // Dropping every SendHalf stops our sends while receiving continues, and vice versa.
+    pub fn split(self) -> (SendHalf, RecvHalf) {
+      // Take out counts for the halves before we release our own, so the app never appears hung up in between
+      self.refs.acquire(refs::SENDER | refs::RECEIVER);
+      let send_half = SendHalf::new(Arc::clone(&self.on_write), Arc::clone(&self.shared), Arc::clone(&self.refs), self.id);
+      let recv_half = RecvHalf::new(Arc::clone(&self.on_write), Arc::clone(&self.shared), Arc::clone(&self.refs), self.id);
+      (send_half, recv_half)
     }
 
-    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
-      let (ref buf_read, ref _buf_write, ref status, _) = *self.shared;
-      let mut buf_read = buf_read.lock().map_err(error::poisoned_read_lock)?;
-
-      let mut health = status.check_err();
-      while buf_read.count() <= 0 && health.is_ok() {
-        buf_read = buf_read.wait().map_err(error::poisoned_read_lock)?;
-        health = status.check_err();
+    // Flushes queued writes, then releases this handle as dropping it would. Other clones and halves keep the connection open;
+    // if this was the last handle, the app hangs up and we wait for the disconnect notice to be queued for the peer.
+    // As with flush, queued means in the socket's outbox, which the daemon sends ahead of anything newer but may not have sent yet.
+    // Waits at most `linger` in total, returning TimedOut if the writes or the disconnect notice weren't queued in time.
+    pub fn close(self, linger: Duration) -> io::Result<()> {
+      let deadline = Instant::now() + linger;
+      let flushed = ops::flush_until(&self.shared, Some(deadline));
+
+      let shared = Arc::clone(&self.shared);
+      let on_write = Arc::clone(&self.on_write);
+      drop(self);
+
+      // Writes that never made it out matter more than anything after
+      flushed?;
+      let (_, ref buf_write, ref status, _, _, _) = *shared;
+      if !status.app_has_hup() { return Ok(()); }
+
+      // Wake the daemon right away, rather than relying on the next heartbeat to notice the hangup.
+      // A connection that has already finished needs no waking, so a daemon that has gone away is no failure then
+      if let Err(e) = on_write(0) {
+        if !status.is_finished() { return Err(e); }
       }
 
-      // We arrive here only if the read buffer has data or the status is closed.
-      // If the read buffer doesn't have data, it means the status is closed.
-      // Nothing left to do but report an error here (and on all future reads).
-      // NOTE: UNLIKE the case where buf_read has data, the daemon calls notify_all() when a conn is closed.
-      // This means every thread will wake up, observe the conn is closed, break its loop and arrive here.
-      // Noticeably, they will NEVER sleep on the condvar and NEVER need to be signalled again. So we don't need to notify_one() here.
-      if buf_read.count() <= 0 {
-        health.and_then(|_| Err(error::unknown()))?;
+      let mut buf_write = buf_write.lock().map_err(error::poisoned_write_lock)?;
+      while !status.is_finished() {
+        buf_write = ops::wait_until(buf_write, Some(deadline), error::poisoned_write_lock)?;
       }
 
-      // We arrive here only if the read buffer has data. We don't care about the connection state until the
-      // read buffer has been drained.
-      let pop_result = buf_read.pop_front(buf);
-
-      // Finished all contentious reading; signal the next reader if needed then drop the lock
-      if buf_read.count() > 0 { buf_read.notify_one(); }
-      drop(buf_read);
-
-      // NOTE: Pop result is only None when there are no reads (not happening here)
-      //       or the buffer to copy to is just too small!
-      //       Thus we signal UnexpectedEOF to indicate there was no space to read.
-      //       The connection is still OK- the data is still waiting to be read if we bring a bigger buffer
-      pop_result.map(Ok).unwrap_or_else(|| Err(error::no_space_to_read()))
+      Ok(())
     }
 
-
-    // Much simpler case since its nonblocking nature means we never worry about the condvar
-    pub fn try_recv(&self, buf: &mut [u8]) -> Option<io::Result<usize>> {
-      let (ref buf_read, ref _buf_write, ref status, _) = *self.shared;
-      buf_read.lock().map_err(error::poisoned_read_lock).and_then(|mut buf_read| {
-        if buf_read.count() > 0 {
-          let pop_result = buf_read.pop_front(buf);
-          drop(buf_read);
-          match pop_result {
-            Some(size) => Ok(Some(size)),
-            None => Err(error::no_space_to_read())
-          }
-        } else {
-          status.check_err().map(|_| None)
-        }
-      }).transpose()
+    // Signals under the key in a ConnectionSet's notify, instead of any registered event loop
+    pub(crate) fn attach(&self, notify: Arc<state::Notify>, key: usize) {
+      let (_, _, _, _, _, ref readiness) = *self.shared;
+      readiness.attach(notify, key);
     }
 
-    pub fn local_addr(&self) -> SocketAddr {
-      self.id.0
+    pub(crate) fn detach(&self) {
+      let (_, _, _, _, _, ref readiness) = *self.shared;
+      readiness.detach();
     }
 
-    pub fn peer_addr(&self) -> SocketAddr {
-      self.id.1
+    // Whether a recv would find something, whether every queued write has been flushed, and whether the connection has closed
+    pub(crate) fn readiness(&self) -> (bool, bool, bool) {
+      let (ref buf_read, ref buf_write, ref status, _, _, _) = *self.shared;
+      let closed = status.close_reason().is_some();
+      let readable = buf_read.lock().map(|buf| buf.count() > 0).unwrap_or(false);
+      let flushed = buf_write.lock().map(|buf| buf.count() == 0).unwrap_or(false);
+      (readable, flushed && !closed, closed)
     }
+
+    impl_common!();
+    impl_send!();
+    impl_recv!();
 }
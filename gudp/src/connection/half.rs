@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use crate::types::OnWrite;
+use crate::state;
+use crate::connection::refs::{self, Refs};
+use crate::connection::connection::Id;
+
+// The sending direction of a split Connection
+// Dropping every SendHalf stops our sends, while any RecvHalf keeps receiving.
+pub struct SendHalf {
+  on_write: Arc<OnWrite>,
+  shared: Arc<state::Shared>,
+  refs: Arc<Refs>,
+  id: Id // Local addr, Peer Addr
+}
+
+// The receiving direction of a split Connection
+// Dropping every RecvHalf stops the daemon buffering reads, while any SendHalf keeps sending.
+pub struct RecvHalf {
+  on_write: Arc<OnWrite>,
+  shared: Arc<state::Shared>,
+  refs: Arc<Refs>,
+  id: Id // Local addr, Peer Addr
+}
+
+// NOTE: The constructors assume the caller has already acquired this half's count
+impl SendHalf {
+  pub(crate) fn new(on_write: Arc<OnWrite>, shared: Arc<state::Shared>, refs: Arc<Refs>, id: Id) -> SendHalf {
+    SendHalf { on_write, shared, refs, id }
+  }
+
+  impl_common!();
+  impl_send!();
+}
+
+impl RecvHalf {
+  pub(crate) fn new(on_write: Arc<OnWrite>, shared: Arc<state::Shared>, refs: Arc<Refs>, id: Id) -> RecvHalf {
+    RecvHalf { on_write, shared, refs, id }
+  }
+
+  impl_common!();
+  impl_recv!();
+}
+
+impl Clone for SendHalf {
+  fn clone(&self) -> SendHalf {
+    self.refs.acquire(refs::SENDER);
+    SendHalf::new(Arc::clone(&self.on_write), Arc::clone(&self.shared), Arc::clone(&self.refs), self.id)
+  }
+}
+
+impl Clone for RecvHalf {
+  fn clone(&self) -> RecvHalf {
+    self.refs.acquire(refs::RECEIVER);
+    RecvHalf::new(Arc::clone(&self.on_write), Arc::clone(&self.shared), Arc::clone(&self.refs), self.id)
+  }
+}
+
+impl Drop for SendHalf {
+  fn drop(&mut self) {
+    let (_, _, ref status, _, _, _) = *self.shared;
+    self.refs.release(refs::SENDER, status);
+  }
+}
+
+impl Drop for RecvHalf {
+  fn drop(&mut self) {
+    let (_, _, ref status, _, _, _) = *self.shared;
+    self.refs.release(refs::RECEIVER, status);
+  }
+}
@@ -1,47 +1,132 @@
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crossbeam::channel;
 use log::warn;
 
 use crate::error;
+use crate::constants::time_ms;
 use crate::Connection;
 use crate::types::{FromDaemon, OnClose};
+use crate::state::{Signal, Notify};
 
 pub struct Listener {
   on_close: Box<OnClose>,
+  signal: Arc<Signal>,
   pub rx: channel::Receiver<FromDaemon>,
+  closed: bool,
+  stepped: bool
 }
 
 impl Drop for Listener {
   fn drop(&mut self) {
-    (self.on_close)().map(|_| {
-      // NOTE: This is a blocking call, waiting for the daemon thread to close its sender.
-      // The reason being, a try_iter loop would be racy-
-      //   it's possible after we iterate but before we drop, a new item is sent.
-      //   that item would be lost, and the io on the daemon side would never be cleaned up.
-      // But it can be very counter-intuitive for a Destructor to block!!
-      // We might want a recv_timeout here just in case something strange occurs.
-      for awaiting_conn in self.rx.iter() {
-        if let FromDaemon::Connection(on_write, shared, id) = awaiting_conn {
-          // Build and drop any awaiting connections until the daemon closes its sender
-          // Dropping a connection guarantees it will eventually be cleaned up
-          Connection::new(on_write, shared, id);
-        }
-      }
-    }).unwrap_or_else(|e| { warn!("Could not close listening socket on drop: {}. Resource may leak!", e); });
+    if self.closed { return; }
+    self.close_until(Instant::now() + time_ms::LISTENER_CLOSE)
+      .unwrap_or_else(|e| { warn!("Could not close listening socket on drop: {}. Resource may leak!", e); });
   }
 }
 
 impl Listener {
-  pub fn new(on_close: Box<OnClose>, rx: channel::Receiver<FromDaemon>) -> Listener {
-    Listener { on_close, rx }
+  pub fn new(on_close: Box<OnClose>, signal: Arc<Signal>, rx: channel::Receiver<FromDaemon>) -> Listener {
+    Listener { on_close, signal, rx, closed: false, stepped: false }
+  }
+
+  // A listener on a manually stepped daemon, which never waits for the daemon to confirm it has closed
+  pub fn stepped(on_close: Box<OnClose>, signal: Arc<Signal>, rx: channel::Receiver<FromDaemon>) -> Listener {
+    Listener { on_close, signal, rx, closed: false, stepped: true }
+  }
+
+  // Wakes the registry with the token when a connection may be waiting to accept, or the listener has stopped.
+  // Edge triggered: drain with try_accept until it returns None before polling again. Unix only
+  pub fn register(&self, registry: &mio::Registry, token: mio::Token) -> io::Result<()> {
+    self.signal.register(registry, token)
+  }
+
+  pub fn deregister(&self, registry: &mio::Registry) -> io::Result<()> {
+    self.signal.deregister(registry)
+  }
+
+  pub(crate) fn attach(&self, notify: Arc<Notify>, key: usize) {
+    self.signal.attach(notify, key);
+  }
+
+  // Stops listening, waiting up to the timeout for the daemon to confirm. Dropping a listener does the same
+  // with a default timeout. Connections not yet accepted are dropped, which hangs up on their peers.
+  pub fn close_timeout(mut self, timeout: Duration) -> io::Result<()> {
+    self.closed = true;
+    self.close_until(Instant::now() + timeout)
+  }
+
+  fn close_until(&mut self, deadline: Instant) -> io::Result<()> {
+    let on_close = (self.on_close)();
+
+    // Nobody would step the daemon while we waited. Connections it hands over in the meantime are dropped along with rx
+    if self.stepped { return on_close; }
+
+    // NOTE: We wait for the daemon thread to close its sender, rather than drain with a try_iter loop.
+    // The reason being, a try_iter loop would be racy-
+    //   it's possible after we iterate but before we drop, a new item is sent.
+    //   that item would be lost, and the io on the daemon side would never be cleaned up.
+    // The deadline only passes if something strange occurs, like a stalled daemon.
+    loop {
+      match self.rx.recv_deadline(deadline) {
+        // Build and drop any awaiting connections until the daemon closes its sender
+        // Dropping a connection guarantees it will eventually be cleaned up
+        Ok(FromDaemon::Connection(on_write, shared, id)) => drop(Connection::new(on_write, shared, id)),
+        Ok(FromDaemon::Listener(..)) | Ok(FromDaemon::Refused(_)) => { },
+
+        // However the daemon came to drop its sender (even by exiting), we are no longer listening
+        Err(channel::RecvTimeoutError::Disconnected) => return Ok(()),
+        Err(channel::RecvTimeoutError::Timeout) => return on_close.and(Err(error::timed_out()))
+      }
+    }
   }
   // Block until connection is established or the daemon dies trying I guess
   pub fn accept(&self) -> io::Result<Connection> {
-    match self.rx.recv() {
-      Ok(FromDaemon::Connection(on_write, shared, id)) => Ok(Connection::new(on_write, shared, id)),
-      Err(e) => Err(error::cannot_recv_from_daemon(e)),
-      _ => Err(error::unexpected_recv_from_daemon())
+    self.rx.recv()
+      .map_err(error::cannot_recv_from_daemon)
+      .and_then(into_connection)
+  }
+
+  // Block until connection is established or the timeout elapses, whichever comes first
+  pub fn accept_timeout(&self, timeout: Duration) -> io::Result<Connection> {
+    self.rx.recv_timeout(timeout)
+      .map_err(error::cannot_recv_from_daemon_timeout)
+      .and_then(into_connection)
+  }
+
+  // Nonblocking; returns None when no connection is waiting to be accepted
+  pub fn try_accept(&self) -> Option<io::Result<Connection>> {
+    match self.rx.try_recv() {
+      Ok(received) => Some(into_connection(received)),
+      Err(channel::TryRecvError::Empty) => None,
+      Err(channel::TryRecvError::Disconnected) => Some(Err(error::cannot_recv_from_daemon(channel::RecvError)))
     }
   }
+
+  // Iterator over connections as they are accepted. Blocks between connections.
+  // Ends once the daemon stops handing out connections for this listener.
+  pub fn incoming(&self) -> Incoming<'_> {
+    Incoming { listener: self }
+  }
+}
+
+pub struct Incoming<'a> {
+  listener: &'a Listener
+}
+
+impl<'a> Iterator for Incoming<'a> {
+  type Item = io::Result<Connection>;
+
+  fn next(&mut self) -> Option<io::Result<Connection>> {
+    self.listener.rx.recv().ok().map(into_connection)
+  }
+}
+
+fn into_connection(received: FromDaemon) -> io::Result<Connection> {
+  match received {
+    FromDaemon::Connection(on_write, shared, id) => Ok(Connection::new(on_write, shared, id)),
+    _ => Err(error::unexpected_recv_from_daemon())
+  }
 }
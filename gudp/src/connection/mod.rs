@@ -1,5 +1,14 @@
+#[macro_use]
+mod ops;
+mod refs;
 mod connection;
+mod half;
 mod listener;
+mod connecting;
+mod set;
 
 pub use connection::Connection;
-pub use listener::Listener;
+pub use half::{SendHalf, RecvHalf};
+pub use listener::{Listener, Incoming};
+pub use connecting::Connecting;
+pub use set::{ConnectionSet, Ready};
@@ -0,0 +1,322 @@
+use std::time::Instant;
+use std::io;
+
+use bring::Bring;
+use cond_mutex::{CondMutexGuard, LockError};
+
+use crate::state;
+use crate::error;
+
+// NOTE: Connection, SendHalf and RecvHalf are all views onto the same shared connection state,
+// differing only in which directions they can use and how they count towards hanging up.
+// Rather than delegate through a common inner type, we macro-ize their shared methods.
+// Each macro expects the implementing struct to have the fields `on_write`, `shared` and `id`.
+
+macro_rules! impl_common {
+  () => {
+    #[inline]
+    pub fn rtt_ms(&self) -> u32 {
+      let (_, _, _, ref netstat_out, _, _) = *self.shared;
+      netstat_out.rtt.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn loss_pct(&self) -> u32 {
+      let (_, _, _, ref netstat_out, _, _) = *self.shared;
+      netstat_out.loss.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+      self.id.0
+    }
+
+    pub fn peer_addr(&self) -> std::net::SocketAddr {
+      self.id.1
+    }
+
+    // Why the connection ended, or None while it's still open
+    pub fn close_reason(&self) -> Option<crate::Error> {
+      let (_, _, ref status, _, _, _) = *self.shared;
+      status.close_reason()
+    }
+
+    // Wakes the registry with the token when this connection may have data to recv, has flushed its writes
+    // or has closed, so one thread's event loop can wait on many connections. Wakeups are edge triggered
+    // and may be spurious: drain with try_recv until it returns None before polling again. Unix only
+    pub fn register(&self, registry: &mio::Registry, token: mio::Token) -> std::io::Result<()> {
+      let (_, _, _, _, _, ref readiness) = *self.shared;
+      readiness.register(registry, token)
+    }
+
+    // Stops waking the registry. Halves share one registration with their connection
+    pub fn deregister(&self, registry: &mio::Registry) -> std::io::Result<()> {
+      let (_, _, _, _, _, ref readiness) = *self.shared;
+      readiness.deregister(registry)
+    }
+  }
+}
+
+macro_rules! impl_send {
+  () => {
+    // TODO: Add TrySend with a condvar + mutex around the write buffer and a buffer size limit
+    // Errors with PayloadTooLarge if buf is more than a single packet can carry
+    pub fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+      let (ref _buf_read, ref buf_write, ref status, _, max_payload, _) = *self.shared;
+      status.check_err()?;
+      if buf.len() > max_payload { return Err(crate::error::payload_too_large(max_payload)); }
+
+      let mut buf_write = buf_write.lock().map_err(crate::error::poisoned_write_lock)?;
+      let size = buf_write.push_back(buf);
+      drop(buf_write);
+
+      self.wake_on_write(size) // Wake on send to flush all writes immediately
+    }
+
+    // Queues each buffer as its own packet under a single lock, waking the daemon at most once.
+    // Returns the total bytes queued.
+    // If any buffer is too large, nothing is queued.
+    pub fn send_batch(&self, bufs: &[&[u8]]) -> std::io::Result<usize> {
+      let (ref _buf_read, ref buf_write, ref status, _, max_payload, _) = *self.shared;
+      status.check_err()?;
+      if bufs.iter().any(|buf| buf.len() > max_payload) { return Err(crate::error::payload_too_large(max_payload)); }
+
+      let mut buf_write = buf_write.lock().map_err(crate::error::poisoned_write_lock)?;
+      let size = bufs.iter().map(|buf| buf_write.push_back(buf)).sum();
+      drop(buf_write);
+
+      self.wake_on_write(size)
+    }
+
+    // Queues the concatenation of the given slices as a single packet
+    pub fn send_vectored(&self, bufs: &[std::io::IoSlice]) -> std::io::Result<usize> {
+      let (ref _buf_read, ref buf_write, ref status, _, max_payload, _) = *self.shared;
+      status.check_err()?;
+      if bufs.iter().map(|buf| buf.len()).sum::<usize>() > max_payload { return Err(crate::error::payload_too_large(max_payload)); }
+
+      let mut buf_write = buf_write.lock().map_err(crate::error::poisoned_write_lock)?;
+      let size = buf_write.push_back_vectored(bufs);
+      drop(buf_write);
+
+      self.wake_on_write(size)
+    }
+
+    // Blocks until the daemon has taken every queued write into its socket's outbox.
+    // Datagrams the socket won't take yet wait there for the next writable event, so they may not have been sent when this returns
+    pub fn flush(&self) -> std::io::Result<()> {
+      crate::connection::ops::flush_until(&self.shared, None)
+    }
+
+    // Wakes the daemon to flush writes, unless a previous wakeup has yet to be handled.
+    // The daemon clears the pending flag before each flush, so queued writes are never stranded.
+    fn wake_on_write(&self, size: usize) -> std::io::Result<usize> {
+      let (_, _, ref status, _, _, _) = *self.shared;
+      if !status.set_write_pending() { return Ok(size); }
+
+      (self.on_write)(size).map_err(|e| {
+        // The daemon never heard about this write; let the next one try again
+        status.clear_write_pending();
+        e
+      })
+    }
+  }
+}
+
+macro_rules! impl_recv {
+  () => {
+    pub fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+      self.recv_until(buf, None)
+    }
+
+    // Like recv, but gives up with a TimedOut error if nothing arrives within the timeout
+    pub fn recv_timeout(&self, buf: &mut [u8], timeout: std::time::Duration) -> std::io::Result<usize> {
+      self.recv_until(buf, Some(std::time::Instant::now() + timeout))
+    }
+
+    // Like recv, but gives up with a TimedOut error if nothing arrives by the deadline
+    pub fn recv_deadline(&self, buf: &mut [u8], deadline: std::time::Instant) -> std::io::Result<usize> {
+      self.recv_until(buf, Some(deadline))
+    }
+
+    fn recv_until(&self, buf: &mut [u8], deadline: Option<std::time::Instant>) -> std::io::Result<usize> {
+      let mut buf_read = crate::connection::ops::lock_readable(&self.shared, deadline)?;
+      let pop_result = buf_read.pop_front(buf)
+        .ok_or_else(|| crate::error::no_space_to_read(buf_read.front_size_bytes().unwrap_or(0)));
+
+      // Finished all contentious reading; signal the next reader if needed then drop the lock
+      if buf_read.count() > 0 { buf_read.notify_one(); }
+      drop(buf_read);
+
+      // NOTE: Pop result is only None when there are no reads (not happening here)
+      //       or the buffer to copy to is just too small!
+      //       Thus we signal UnexpectedEOF to indicate there was no space to read.
+      //       The connection is still OK- the data is still waiting to be read if we bring a bigger buffer
+      //       The error says how big a buffer is needed, as would peek_len.
+      pop_result
+    }
+
+    // Blocks until a packet is available, then runs f over it where it lies in the read buffer and pops it.
+    // Spares recv's copy for large payloads; a packet wrapping around the end of the buffer comes in two halves.
+    // The read lock is held until f returns, and the daemon takes it for every datagram this connection receives,
+    // so a slow f stalls the daemon thread and every connection on it. Keep it short, and don't recv from this connection within it
+    pub fn recv_with<F, R>(&self, f: F) -> std::io::Result<R>
+    where F: FnOnce(slice_pair::SlicePair<u8>) -> R {
+      self.recv_with_until(f, None)
+    }
+
+    // Like recv_with, but gives up with a TimedOut error if nothing arrives within the timeout
+    pub fn recv_with_timeout<F, R>(&self, f: F, timeout: std::time::Duration) -> std::io::Result<R>
+    where F: FnOnce(slice_pair::SlicePair<u8>) -> R {
+      self.recv_with_until(f, Some(std::time::Instant::now() + timeout))
+    }
+
+    // Like recv_with, but gives up with a TimedOut error if nothing arrives by the deadline
+    pub fn recv_with_deadline<F, R>(&self, f: F, deadline: std::time::Instant) -> std::io::Result<R>
+    where F: FnOnce(slice_pair::SlicePair<u8>) -> R {
+      self.recv_with_until(f, Some(deadline))
+    }
+
+    fn recv_with_until<F, R>(&self, f: F, deadline: Option<std::time::Instant>) -> std::io::Result<R>
+    where F: FnOnce(slice_pair::SlicePair<u8>) -> R {
+      let mut buf_read = crate::connection::ops::lock_readable(&self.shared, deadline)?;
+      let res = buf_read.with_front(|data| (f(data), bring::WithOpt::Pop));
+
+      if buf_read.count() > 0 { buf_read.notify_one(); }
+      drop(buf_read);
+
+      // NOTE: lock_readable only returns once there is a packet, so this is never None
+      res.ok_or_else(crate::error::unknown)
+    }
+
+    // Like recv, but leaves the packet in place to be read again
+    pub fn peek(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+      let mut buf_read = crate::connection::ops::lock_readable(&self.shared, None)?;
+      let peek_result = buf_read.front(buf).map(|mut front| {
+        front.with(|size| (size, bring::WithOpt::Peek))
+      }).ok_or_else(|| crate::error::no_space_to_read(buf_read.front_size_bytes().unwrap_or(0)));
+
+      // The packet is still there, so the next reader is always free to go
+      buf_read.notify_one();
+      drop(buf_read);
+
+      peek_result
+    }
+
+    // Blocks until a packet is available, then returns its size without reading it
+    pub fn peek_len(&self) -> std::io::Result<usize> {
+      let buf_read = crate::connection::ops::lock_readable(&self.shared, None)?;
+      let size = buf_read.front_size_bytes();
+      buf_read.notify_one();
+      drop(buf_read);
+
+      size.map(Ok).unwrap_or_else(|| Err(crate::error::unknown()))
+    }
+
+    // Like recv, but allocates a buffer exactly large enough for the packet
+    pub fn recv_vec(&self) -> std::io::Result<Vec<u8>> {
+      let mut buf_read = crate::connection::ops::lock_readable(&self.shared, None)?;
+      let packet = crate::connection::ops::pop_vec(&mut buf_read);
+      if buf_read.count() > 0 { buf_read.notify_one(); }
+      drop(buf_read);
+
+      Ok(packet)
+    }
+
+    // Blocks until at least one packet is available, then drains up to `max` packets into `bufs`
+    // while holding the read lock only once. Returns the number of packets received.
+    pub fn recv_many(&self, bufs: &mut Vec<Vec<u8>>, max: usize) -> std::io::Result<usize> {
+      let mut buf_read = crate::connection::ops::lock_readable(&self.shared, None)?;
+      let mut received = 0;
+      while received < max && buf_read.count() > 0 {
+        bufs.push(crate::connection::ops::pop_vec(&mut buf_read));
+        received += 1;
+      }
+
+      if buf_read.count() > 0 { buf_read.notify_one(); }
+      drop(buf_read);
+
+      Ok(received)
+    }
+
+    // Much simpler case since its nonblocking nature means we never worry about the condvar
+    pub fn try_recv(&self, buf: &mut [u8]) -> Option<std::io::Result<usize>> {
+      let (ref buf_read, ref _buf_write, ref status, _, _, _) = *self.shared;
+      buf_read.lock().map_err(crate::error::poisoned_read_lock).and_then(|mut buf_read| {
+        if buf_read.count() > 0 {
+          let pop_result = buf_read.pop_front(buf)
+            .ok_or_else(|| crate::error::no_space_to_read(buf_read.front_size_bytes().unwrap_or(0)));
+          drop(buf_read);
+          pop_result.map(Some)
+        } else {
+          status.check_err().map(|_| None)
+        }
+      }).transpose()
+    }
+  }
+}
+
+// Waits until the read buffer has data (or the deadline passes) and returns it still locked.
+// Once the connection closes, errors only after the read buffer has been drained.
+pub fn lock_readable(shared: &state::Shared, deadline: Option<Instant>) -> io::Result<CondMutexGuard<'_, Bring>> {
+  let (ref buf_read, ref _buf_write, ref status, _, _, _) = *shared;
+  let mut buf_read = buf_read.lock().map_err(error::poisoned_read_lock)?;
+
+  let mut health = status.check_err();
+  while buf_read.count() <= 0 && health.is_ok() {
+    buf_read = wait_until(buf_read, deadline, error::poisoned_read_lock)?;
+    health = status.check_err();
+  }
+
+  // We arrive here only if the read buffer has data or the status is closed.
+  // If the read buffer doesn't have data, it means the status is closed.
+  // Nothing left to do but report an error here (and on all future reads).
+  // NOTE: UNLIKE the case where buf_read has data, the daemon calls notify_all() when a conn is closed.
+  // This means every thread will wake up, observe the conn is closed, break its loop and arrive here.
+  // Noticeably, they will NEVER sleep on the condvar and NEVER need to be signalled again. So we don't need to notify_one() here.
+  if buf_read.count() <= 0 {
+    health.and_then(|_| Err(error::unknown()))?;
+  }
+
+  // We arrive here only if the read buffer has data. We don't care about the connection state until the
+  // read buffer has been drained.
+  Ok(buf_read)
+}
+
+// Waits until the daemon has drained the write buffer to the socket (or the deadline passes).
+// Errors if the connection closes with writes still queued.
+pub fn flush_until(shared: &state::Shared, deadline: Option<Instant>) -> io::Result<()> {
+  let (_, ref buf_write, ref status, _, _, _) = *shared;
+  let mut buf_write = buf_write.lock().map_err(error::poisoned_write_lock)?;
+
+  let mut health = status.check_err();
+  while buf_write.count() > 0 && health.is_ok() {
+    buf_write = wait_until(buf_write, deadline, error::poisoned_write_lock)?;
+    health = status.check_err();
+  }
+
+  if buf_write.count() > 0 { health } else { Ok(()) }
+}
+
+// Waits on the buffer's condvar, giving up with a TimedOut error once the deadline passes
+// Spurious wakeups and lost races with other waiters simply wait out the remaining time
+pub fn wait_until<'a>(
+  guard: CondMutexGuard<'a, Bring>,
+  deadline: Option<Instant>,
+  poisoned: fn(LockError<'a, Bring>) -> io::Error) -> io::Result<CondMutexGuard<'a, Bring>> {
+  match deadline {
+    None => guard.wait().map_err(poisoned),
+    Some(deadline) => {
+      let remaining = deadline.checked_duration_since(Instant::now()).ok_or_else(error::timed_out)?;
+      let (guard, _) = guard.wait_timeout(remaining).map_err(poisoned)?;
+      Ok(guard)
+    }
+  }
+}
+
+// Pops the front packet into a freshly allocated vec of exactly its size
+// NOTE: The read buffer must be non-empty
+pub fn pop_vec(buf_read: &mut Bring) -> Vec<u8> {
+  let size = buf_read.front_size_bytes().unwrap_or(0);
+  let mut packet = vec![0u8; size];
+  buf_read.pop_front(&mut packet);
+  packet
+}
@@ -0,0 +1,41 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::SeqCst as OSeqCst;
+
+use crate::state::Status;
+
+// Both counts share one atomic, so exactly one dropping handle observes the moment they both reach zero
+pub const SENDER: u64 = 1 << 32;
+pub const RECEIVER: u64 = 1;
+const RECEIVERS_MASK: u64 = SENDER - 1;
+
+/// Counts the app-side handles alive on each direction of a connection.
+/// A Connection holds one of each, while a SendHalf or RecvHalf holds just its own.
+///
+/// When the last receiver drops, the app half-closes its read side and the daemon stops buffering reads.
+/// When the last sender drops, nobody can send anymore but reads continue as normal.
+/// Only when both reach zero has the app hung up.
+#[derive(Debug)]
+pub struct Refs {
+  count: AtomicU64
+}
+
+impl Refs {
+  pub fn new(amount: u64) -> Refs {
+    Refs { count: AtomicU64::new(amount) }
+  }
+
+  pub fn acquire(&self, amount: u64) {
+    self.count.fetch_add(amount, OSeqCst);
+  }
+
+  pub fn release(&self, amount: u64, status: &Status) {
+    let prev = self.count.fetch_sub(amount, OSeqCst);
+    let now = prev - amount;
+
+    if now == 0 {
+      status.set_app_hup();
+    } else if (now & RECEIVERS_MASK) == 0 && (prev & RECEIVERS_MASK) != 0 {
+      status.set_app_recv_hup();
+    }
+  }
+}
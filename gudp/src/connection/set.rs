@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::sync::Arc;
+use std::time::Duration;
+use std::io;
+
+use crate::state::Notify;
+use crate::connection::{Connection, Listener};
+
+// The listener's key never collides with a connection's, which count up from zero
+const LISTENER_KEY: usize = usize::MAX;
+
+// Many connections, and optionally the listener accepting them, waited on from a single thread.
+// Every member signals one shared Notify under its own key, so a set costs no threads or fds per connection.
+pub struct ConnectionSet {
+  notify: Arc<Notify>,
+  listener: Option<Listener>,
+  // The listener failed while other members were ready too; the next wait reports it
+  listener_err: Option<io::Error>,
+  connections: HashMap<usize, Connection>,
+  next_key: usize
+}
+
+// What a wait found for one connection. Wakeups are edge triggered: drain it with try_recv until None
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ready {
+  pub key: usize,
+  // Newly accepted from the set's listener, and now a member under this key
+  pub accepted: bool,
+  pub readable: bool,
+  // Every queued write has been flushed
+  pub writable: bool,
+  pub closed: bool
+}
+
+impl Default for ConnectionSet {
+  fn default() -> ConnectionSet {
+    ConnectionSet::new()
+  }
+}
+
+impl ConnectionSet {
+  pub fn new() -> ConnectionSet {
+    ConnectionSet { notify: Arc::new(Notify::default()), listener: None, listener_err: None, connections: HashMap::new(), next_key: 0 }
+  }
+
+  // Connections the listener hands out are accepted into the set as it waits
+  pub fn with_listener(listener: Listener) -> ConnectionSet {
+    let mut set = ConnectionSet::new();
+    listener.attach(Arc::clone(&set.notify), LISTENER_KEY);
+    set.listener = Some(listener);
+    set
+  }
+
+  // Adds the connection, returning the key waits report it under
+  pub fn insert(&mut self, conn: Connection) -> usize {
+    let key = self.next_key;
+    self.next_key += 1;
+    conn.attach(Arc::clone(&self.notify), key);
+    self.connections.insert(key, conn);
+    key
+  }
+
+  // Hands the connection back. It no longer signals the set
+  pub fn remove(&mut self, key: usize) -> Option<Connection> {
+    let conn = self.connections.remove(&key)?;
+    conn.detach();
+    Some(conn)
+  }
+
+  pub fn get(&self, key: usize) -> Option<&Connection> {
+    self.connections.get(&key)
+  }
+
+  pub fn len(&self) -> usize {
+    self.connections.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.connections.is_empty()
+  }
+
+  pub fn iter(&self) -> hash_map::Iter<'_, usize, Connection> {
+    self.connections.iter()
+  }
+
+  // Waits up to the timeout (or forever) for members to become ready, accepting any new connections.
+  // May return nothing early, say for a connection removed since it signalled.
+  // Errors once the listener stops, say on shutdown. Readiness is never lost to the error: when other members were
+  // ready too, they are returned first and the next wait returns the error right away
+  pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Vec<Ready>> {
+    if let Some(e) = self.listener_err.take() { return Err(e); }
+
+    let mut ready = vec![];
+    for key in self.notify.wait(timeout) {
+      if key == LISTENER_KEY {
+        self.accept(&mut ready);
+      } else if let Some(conn) = self.connections.get(&key) {
+        let (readable, writable, closed) = conn.readiness();
+        ready.push(Ready { key, accepted: false, readable, writable, closed });
+      }
+    }
+
+    match self.listener_err.take() {
+      Some(e) if ready.is_empty() => Err(e),
+      listener_err => { self.listener_err = listener_err; Ok(ready) }
+    }
+  }
+
+  // Keeps the first error for wait to report. The listener stays in the set either way
+  fn accept(&mut self, ready: &mut Vec<Ready>) {
+    let listener = match self.listener.take() {
+      Some(listener) => listener,
+      None => return
+    };
+
+    while let Some(accepted) = listener.try_accept() {
+      match accepted {
+        Ok(conn) => {
+          let key = self.insert(conn);
+          let (readable, writable, closed) = self.connections[&key].readiness();
+          ready.push(Ready { key, accepted: true, readable, writable, closed });
+        },
+        // A stopped listener fails every accept; stop there, but still take any connections queued behind a bad message
+        Err(e) => {
+          self.listener_err.get_or_insert(e);
+          if listener.rx.is_empty() { break; }
+        }
+      }
+    }
+    self.listener = Some(listener);
+  }
+}
+
+impl<'a> IntoIterator for &'a ConnectionSet {
+  type Item = (&'a usize, &'a Connection);
+  type IntoIter = hash_map::Iter<'a, usize, Connection>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.iter()
+  }
+}
@@ -1,8 +1,17 @@
 use mio::Token;
 
 pub const CONFIG_BUF_SIZE_BYTES: usize = 4096;
+pub const MAX_DATAGRAM_SIZE_BYTES: usize = 4096;
 pub const WAKE_TOKEN: Token = Token(0);
 pub const SENT_SEQ_BUF_SIZE: usize = 1024;
+pub const ACCEPT_BACKLOG: usize = 128;
+pub const RATE_LIMIT_IPS_MIN: usize = 1024;
+pub const THROTTLED_IPS_MAX: usize = 1024;
+pub const EVENTS_BACKLOG: usize = 64;
+pub const SIM_QUEUE_MAX: usize = 1024;
+pub const SIM_EPHEMERAL_PORT_MIN: u16 = 49152;
+pub const CONDITIONER_QUEUE_MAX: usize = 4096;
+pub const BATCH_SIZE: usize = 32;
 
 pub mod header {
   use core::ops::Range;
@@ -33,11 +42,57 @@ pub mod header {
     REMOTE_SEQ_TAIL_SIZE_BYTES;
 }
 
+// Unsequenced control messages, distinguished from regular packets by their own magic bytes
+pub mod control {
+  use core::ops::Range;
+  pub const MAGIC_BYTES: [u8; 4] = 0xdeadc0de_u32.to_be_bytes();
+  pub const MAGIC_BYTES_RANGE: Range<usize> =
+    0..MAGIC_BYTES.len();
+
+  pub const KIND_OFFSET: usize = 4;
+  pub const REASON_OFFSET: usize = 5;
+
+  // magic bytes + kind
+  pub const SIZE_BYTES: usize = MAGIC_BYTES.len() + 1;
+
+  // The sender is going away. Followed by a reason byte
+  pub const KIND_DISCONNECT: u8 = 1;
+  pub const DISCONNECT_SIZE_BYTES: usize = SIZE_BYTES + 1;
+
+  // A listener's challenge to a new peer. Followed by a cookie the peer must echo back
+  pub const KIND_CHALLENGE: u8 = 2;
+  pub const COOKIE_OFFSET: usize = SIZE_BYTES;
+  pub const COOKIE_SIZE_BYTES: usize = 16;
+  pub const COOKIE_RANGE: Range<usize> =
+    COOKIE_OFFSET..COOKIE_OFFSET + COOKIE_SIZE_BYTES;
+  pub const CHALLENGE_SIZE_BYTES: usize = SIZE_BYTES + COOKIE_SIZE_BYTES;
+
+  // A new peer's packet, prefixed by the cookie it was challenged with (zeroes before any challenge).
+  // Always larger than a challenge, so a listener answering with one never amplifies a spoofed packet
+  pub const KIND_HELLO: u8 = 3;
+  pub const HELLO_SIZE_BYTES: usize = SIZE_BYTES + COOKIE_SIZE_BYTES;
+
+  pub mod reason {
+    // The app closed or dropped its connection
+    pub const CLOSED: u8 = 0;
+    // The listener's accept backlog had no room for a new peer
+    pub const SERVER_FULL: u8 = 1;
+    // The listener's admission control turned the peer away
+    pub const DENIED: u8 = 2;
+  }
+}
+
 pub mod time_ms {
   use std::time::Duration;
 
   pub const ZERO: Duration = Duration::from_millis(0);
   pub const IOTA: Duration = Duration::from_millis(10);
+  pub const HANDSHAKE_RETRY: Duration = Duration::from_millis(250);
   pub const HEARTBEAT: Duration = Duration::from_millis(1_000);
   pub const TIMEOUT: Duration = Duration::from_millis(15_000);
+  pub const COOKIE_LIFETIME: Duration = Duration::from_millis(10_000);
+  pub const QUARANTINE_RETRY: Duration = Duration::from_millis(1_000);
+  pub const SHUTDOWN_GRACE: Duration = Duration::from_millis(100);
+  pub const LISTENER_CLOSE: Duration = Duration::from_millis(1_000);
+  pub const HANDLER_TICK: Duration = Duration::from_millis(50);
 }
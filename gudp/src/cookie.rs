@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+use std::io;
+
+use hmac_sha256::HMAC;
+
+use crate::constants::{control, time_ms};
+use crate::error;
+
+const SECRET_SIZE_BYTES: usize = 32;
+const ISSUED_SIZE_BYTES: usize = 4;
+
+// Mints and checks stateless handshake cookies.
+// A cookie is the time it was issued plus a truncated HMAC of the peer's address and that time,
+// keyed by a secret that never leaves the daemon. Echoing one back proves the peer can receive at its address.
+pub struct Jar {
+  secret: [u8; SECRET_SIZE_BYTES],
+  epoch: Instant
+}
+
+pub type Cookie = [u8; control::COOKIE_SIZE_BYTES];
+
+impl Jar {
+  pub fn new(epoch: Instant) -> io::Result<Jar> {
+    let mut secret = [0u8; SECRET_SIZE_BYTES];
+    getrandom::getrandom(&mut secret).map_err(error::no_randomness)?;
+    Ok(Jar { secret, epoch })
+  }
+
+  pub fn bake(&self, peer_addr: SocketAddr, now: Instant) -> Cookie {
+    let issued = self.secs(now);
+    let mut cookie = [0u8; control::COOKIE_SIZE_BYTES];
+    cookie[..ISSUED_SIZE_BYTES].copy_from_slice(&issued.to_be_bytes());
+    cookie[ISSUED_SIZE_BYTES..].copy_from_slice(&self.mac(peer_addr, issued)[..control::COOKIE_SIZE_BYTES - ISSUED_SIZE_BYTES]);
+    cookie
+  }
+
+  pub fn check(&self, peer_addr: SocketAddr, now: Instant, cookie: &[u8]) -> bool {
+    if cookie.len() != control::COOKIE_SIZE_BYTES { return false; }
+
+    let mut bytes = [0u8; ISSUED_SIZE_BYTES];
+    bytes.copy_from_slice(&cookie[..ISSUED_SIZE_BYTES]);
+    let issued = u32::from_be_bytes(bytes);
+    let age = self.secs(now).checked_sub(issued);
+    if age.map(|age| age as u64 > time_ms::COOKIE_LIFETIME.as_secs()).unwrap_or(true) { return false; }
+
+    // Compare in constant time, so the mac can't be guessed byte by byte
+    let mac = self.mac(peer_addr, issued);
+    cookie[ISSUED_SIZE_BYTES..].iter()
+      .zip(mac.iter())
+      .fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+  }
+
+  fn secs(&self, now: Instant) -> u32 {
+    now.saturating_duration_since(self.epoch).as_secs() as u32
+  }
+
+  fn mac(&self, peer_addr: SocketAddr, issued: u32) -> [u8; 32] {
+    let mut hmac = HMAC::new(self.secret);
+    match peer_addr {
+      SocketAddr::V4(addr) => hmac.update(addr.ip().octets()),
+      SocketAddr::V6(addr) => hmac.update(addr.ip().octets())
+    };
+    hmac.update(peer_addr.port().to_be_bytes());
+    hmac.update(issued.to_be_bytes());
+    hmac.finalize()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+  use crate::constants::time_ms;
+  use super::Jar;
+
+  #[test]
+  fn cookies_are_bound_to_address_and_time() {
+    let epoch = Instant::now();
+    let jar = Jar::new(epoch).unwrap();
+    let addr = "127.0.0.1:8000".parse().unwrap();
+    let cookie = jar.bake(addr, epoch);
+
+    assert!(jar.check(addr, epoch + Duration::from_secs(1), &cookie));
+    assert!(!jar.check("127.0.0.1:8001".parse().unwrap(), epoch, &cookie));
+    assert!(!jar.check(addr, epoch + time_ms::COOKIE_LIFETIME + Duration::from_secs(1), &cookie));
+    assert!(!jar.check(addr, epoch, &[0u8; 16]));
+
+    // Another daemon's secret makes for another cookie
+    let other = Jar::new(epoch).unwrap();
+    assert!(!other.check(addr, epoch, &cookie));
+  }
+}
@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+use std::mem;
+
+use mio::Token;
+
+use clock::Clock;
+
+use crate::daemon;
+use crate::socket::{Socket, PeerType};
+use crate::transport::Transport;
+
+pub fn handle<C: Clock, T: Transport>(token_map: &mut HashMap<Token, Socket<T>>, s: &mut daemon::State<C, T>) {
+  if s.handlers.is_empty() { return; }
+
+  // Handlers need the daemon state to flush what they send, so they step outside of it
+  let mut handlers = mem::take(&mut s.handlers);
+  handlers.retain(|token, handled| {
+    // A listener's socket goes once it is closed and its last connection is gone
+    let peers = match token_map.get_mut(token).map(|socket| &mut socket.peer_type) {
+      Some(PeerType::Passive { peers, .. }) => Some(peers),
+      _ => None
+    };
+    handled.step(peers, s)
+  });
+  s.handlers = handlers;
+}
@@ -7,10 +7,11 @@ use clock::Clock;
 
 use crate::socket::{Socket, PeerType};
 use crate::daemon::{self, poll};
+use crate::transport::Transport;
 
-type TokenEntry<'a> = OccupiedEntry<'a, Token, Socket>;
+type TokenEntry<'a, T> = OccupiedEntry<'a, Token, Socket<T>>;
 
-pub fn handle<C: Clock>(mut token_entry: TokenEntry, s: &mut daemon::State<C>) {
+pub fn handle<C: Clock, T: Transport>(mut token_entry: TokenEntry<T>, s: &mut daemon::State<C, T>) {
   let socket = token_entry.get_mut();
   match socket.peer_type {
     // Since Direct sockets aren't listeners, this should never occur
@@ -18,12 +19,12 @@ pub fn handle<C: Clock>(mut token_entry: TokenEntry, s: &mut daemon::State<C>) {
 
     // All listeners are Passive sockets
     PeerType::Passive { ref mut listen, ref peers, .. } => {
-      *listen = None; // Closes the channel the Listener awaits new connections on, unblocking the listener's Drop impl.
+      // Closes the channel the Listener awaits new connections on, unblocking the listener's Drop impl.
+      if let Some(conn_opts) = listen.take() { conn_opts.close(); }
 
       // We can free the resource if there are no peers and we aren't listening
       if peers.len() == 0 {
-        poll::deregister_io(&mut socket.io, s);
-        token_entry.remove();
+        poll::finish_socket(token_entry, s);
       }
       // The most common case is that peers exist but time out eventually, and the resource will be cleaned up then.
     }
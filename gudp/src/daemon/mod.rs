@@ -1,20 +1,33 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::io;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use mio::{Poll, Events, Token, Waker};
 use crossbeam::channel;
 
-use clock::Clock;
+use clock::{Clock, sys};
 
 use crate::socket::{self, Socket};
-use crate::constants::{time_ms, WAKE_TOKEN, CONFIG_BUF_SIZE_BYTES};
-use crate::types::ToDaemon as FromService;
+use crate::constants::{time_ms, WAKE_TOKEN};
+use crate::types::{ToDaemon as FromService, FromDaemon as ToService};
 use crate::timer::{self, Timers, TimerKind};
 use crate::service::Conf;
+use crate::metrics::Metrics;
+use crate::health::{self, Event, Health};
+use crate::cookie;
+use crate::ratelimit;
+use crate::conditioner::Link;
+use crate::transport::{self, Transport, Readiness, Udp, Inbox};
+use crate::connection::{Listener, Connecting};
+use crate::service;
+use crate::handler::{Handler, Handled};
+use crate::error;
+use quarantine::Quarantine;
 
 pub use state::State;
 
@@ -25,110 +38,297 @@ mod timer_event;
 mod read_event;
 mod write_event;
 mod listen_close_event;
+mod handler_event;
+mod quarantine;
 
-pub fn spawn<C>(
-  poll: Poll,
-  waker: Arc<Waker>,
-  rx: channel::Receiver<FromService>,
-  conf: Conf,
-  clock: C) -> io::Result<thread::JoinHandle<io::Error>>
-where C: 'static + Clock + Send {
-
+// Runs the daemon on its own thread until it shuts down or fails
+pub fn spawn<C, T>(mut daemon: Daemon<C, T>) -> io::Result<thread::JoinHandle<io::Result<()>>>
+where C: 'static + Clock + Send, T: Transport {
   thread::Builder::new()
     .name("gudp daemon".to_string())
-    .spawn(move || -> io::Error {
-      let mut events = Events::with_capacity(1024); // 1024 connections ought to be enough for anybody
-      let mut token_map: HashMap<Token, Socket> = HashMap::new();
-
-      // tx_on_write forwards callbacks from app connections after they call write
-      let (tx_on_write, rx_write_events) = channel::unbounded();
-
-      // tx_on_close forwards callbacks from app listeners after they close
-      let (tx_on_close, rx_close_listener_events) = channel::unbounded();
-
-      let buf_local = vec![0u8; CONFIG_BUF_SIZE_BYTES];
-      let timers: timer::List<(socket::Id, TimerKind)> = timer::List::new();
-
-      let mut state = State {
-        poll,
-        waker,
-        tx_on_write,
-        tx_on_close,
-        next_conn_id: 1,
-        buf_local,
-        timers,
-        conf,
-        clock
-      };
-
-      // A hacky alloc to iterate with mutation on the keys of the pending_write hashset
-      let mut pending_write_keybuf = Vec::with_capacity(1024);
-      // A hacky alloc to iterate with mutation on expired timers
-      let mut expired_timers = Vec::with_capacity(1024);
-
-      loop {
-        let timeout = state.timers.when_next().map(|t| {
-          let now = state.clock.now();
-          t.checked_duration_since(now)
-            .map(|timeout| Duration::max(timeout, time_ms::IOTA))
-            .unwrap_or(time_ms::ZERO)
-        });
-
-        match state.poll.poll(&mut events, timeout) {
-          Ok(()) => {
-            // Clear out all msgs from service
-            for msg in rx.try_iter() {
-              service_event::handle(
-                msg,
-                &mut token_map,
-                &mut state);
-            }
+    .spawn(move || -> io::Result<()> {
+      while daemon.poll_once(None)? { }
+      Ok(())
+    })
+}
 
-            // Handle reads
-            for event in events.iter() {
-              if event.token() != WAKE_TOKEN && event.is_readable() {
-                if let Entry::Occupied(token_entry) = token_map.entry(event.token()) {
-                  read_event::handle(token_entry, &mut state);
-                }
-              }
-            };
-
-            // Handle listener close
-            for token in rx_close_listener_events.try_iter() {
-              if let Entry::Occupied(token_entry) = token_map.entry(token) {
-                listen_close_event::handle(token_entry, &mut state);
-              }
-            }
+/// The daemon's event loop. It normally runs on its own thread, but one built with Builder::build_manual is
+/// stepped by the app instead, say once per frame of a game loop, or exactly when a test wants it to
+pub struct Daemon<C: Clock = sys::Clock, T: Transport = Udp> {
+  state: State<Stepped<C>, T>,
+  token_map: HashMap<Token, Socket<T>>,
+  events: Events,
+  rx: channel::Receiver<FromService<T>>,
+  rx_ready: channel::Receiver<Token>,
+  rx_write_events: channel::Receiver<socket::Id>,
+  rx_close_listener_events: channel::Receiver<Token>,
+  // A hacky alloc to iterate with mutation on the keys of the pending_write hashset
+  pending_write_keybuf: Vec<SocketAddr>,
+  // A hacky alloc to iterate with mutation on expired timers
+  expired_timers: Vec<(socket::Id, TimerKind)>,
+  // A hacky alloc to iterate with mutation on sockets with conditioned datagrams due
+  link_ready: Vec<Token>,
+  health: Arc<health::Daemon>,
+  finished: bool
+}
+
+// The daemon's time is the later of its clock and the latest time it was stepped to, so it never runs backwards
+pub struct Stepped<C: Clock> {
+  clock: C,
+  floor: Option<Instant>
+}
+
+impl<C: Clock> Clock for Stepped<C> {
+  fn now(&self) -> Instant {
+    let now = self.clock.now();
+    self.floor.map_or(now, |floor| cmp::max(now, floor))
+  }
+}
+
+impl<C: Clock, T: Transport> Daemon<C, T> {
+  #[allow(clippy::too_many_arguments)]
+  pub(crate) fn new(
+    poll: Poll,
+    waker: Arc<Waker>,
+    rx: channel::Receiver<FromService<T>>,
+    conf: Conf,
+    metrics: Arc<Metrics>,
+    tx_events: channel::Sender<Event>,
+    clock: C,
+    health: Arc<health::Daemon>) -> io::Result<Daemon<C, T>> {
+    let cookies = cookie::Jar::new(clock.now())?;
+    let link = Link::new(&conf)?;
+
+    // Transports mio can't poll report readable sockets over this channel instead
+    let (tx_ready, rx_ready) = channel::unbounded();
+    let readiness = Readiness::new(tx_ready, Arc::clone(&waker));
+
+    // tx_on_write forwards callbacks from app connections after they call write
+    let (tx_on_write, rx_write_events) = channel::unbounded();
+
+    // tx_on_close forwards callbacks from app listeners after they close
+    let (tx_on_close, rx_close_listener_events) = channel::unbounded();
+
+    // One byte more than the largest datagram we accept, so recv_from filling it means the datagram was truncated
+    let buf_local = vec![0u8; conf.max_datagram_size + 1];
+    // Batches are received whole, then handed to buf_local a datagram at a time. With offload, each buffer may hold a coalesced burst
+    let offload = conf.udp_offload && link.is_none();
+    let inbox_buf_size = if offload { usize::max(transport::OFFLOAD_BUF_SIZE, buf_local.len()) } else { buf_local.len() };
+    let inbox = Inbox::new(conf.batch_size, inbox_buf_size);
+    let timers: timer::Wheel<(socket::Id, TimerKind)> = timer::Wheel::new(clock.now());
+
+    let state = State {
+      poll,
+      waker,
+      readiness,
+      tx_on_write,
+      tx_on_close,
+      next_conn_id: 1,
+      buf_local,
+      inbox,
+      inbox_token: None,
+      timers,
+      conf,
+      metrics,
+      tx_events,
+      cookies,
+      limiter: ratelimit::Limiter::new(),
+      shutdown: None,
+      quarantine: Quarantine::new(),
+      link,
+      handlers: HashMap::new(),
+      clock: Stepped { clock, floor: None }
+    };
+
+    state.report(Event::Started);
+
+    Ok(Daemon {
+      state,
+      token_map: HashMap::new(),
+      events: Events::with_capacity(1024), // 1024 connections ought to be enough for anybody
+      rx,
+      rx_ready,
+      rx_write_events,
+      rx_close_listener_events,
+      pending_write_keybuf: Vec::with_capacity(1024),
+      expired_timers: Vec::with_capacity(1024),
+      link_ready: Vec::with_capacity(1024),
+      health,
+      finished: false
+    })
+  }
+
+  // When the daemon next has work due, such as a timer, if ever. Until then it only waits on io and the app
+  pub fn next_deadline(&self) -> Option<Instant> {
+    let s = &self.state;
+    let link_next = s.link.as_ref().and_then(Link::next_release);
+    let tick_next = s.handlers.values().filter_map(Handled::next_tick).min();
+    [s.timers.when_next(), s.shutdown, s.quarantine.retry_at(), link_next, tick_next]
+      .iter().flatten().min().copied()
+  }
+
+  // Handles whatever is due as of `now` without blocking
+  pub fn update(&mut self, now: Instant) -> io::Result<bool> {
+    let floor = self.state.clock.floor.map_or(now, |floor| cmp::max(now, floor));
+    self.state.clock.floor = Some(floor);
+    self.poll_once(Some(time_ms::ZERO))
+  }
+
+  // Waits for io, the app or the next deadline, but no longer than the timeout (forever if None), then handles
+  // whatever is due. Returns false once the daemon has shut down, after which there is nothing left to do
+  pub fn poll_once(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+    if self.finished { return Ok(false); }
+
+    match self.step(timeout) {
+      Ok(true) => Ok(true),
+      Ok(false) => {
+        self.finish(Health::Stopped);
+        Ok(false)
+      },
+      Err(e) => {
+        self.finish(Health::Failed(e.kind()));
+        Err(e)
+      }
+    }
+  }
 
-            // Handle timer expiry
-            // NOTE: Occurs after poll read events to allow time to fill the read buffer, last chance to ack heartbeat, etc if necessary
-            let now = state.clock.now();
-            expired_timers.extend(state.timers.expire(now));
-            for ((token, peer_addr), kind) in expired_timers.drain(..) {
-              if let Entry::Occupied(token_entry) = token_map.entry(token) {
-                timer_event::handle(token_entry, peer_addr, kind, &mut state);
-              }
+  fn finish(&mut self, health: Health) {
+    self.finished = true;
+    self.health.exited(health);
+  }
+
+  fn step(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+    let next = self.next_deadline();
+    let Daemon {
+      ref mut state, ref mut token_map, ref mut events, ref rx, ref rx_ready, ref rx_write_events, ref rx_close_listener_events,
+      ref mut pending_write_keybuf, ref mut expired_timers, ref mut link_ready, ..
+    } = *self;
+
+    let next = next.map(|t| {
+      let now = state.clock.now();
+      t.checked_duration_since(now)
+        .map(|timeout| Duration::max(timeout, time_ms::IOTA))
+        .unwrap_or(time_ms::ZERO)
+    });
+    let timeout = [next, timeout].iter().flatten().min().copied();
+
+    match state.poll.poll(events, timeout) {
+      Ok(()) => {
+        // Clear out all msgs from service
+        for msg in rx.try_iter() {
+          service_event::handle(
+            msg,
+            token_map,
+            state);
+        }
+
+        // Handle reads
+        for event in events.iter() {
+          if event.token() != WAKE_TOKEN && event.is_readable() {
+            if let Entry::Occupied(token_entry) = token_map.entry(event.token()) {
+              read_event::handle(token_entry, state);
             }
+          }
+        };
+        for token in rx_ready.try_iter() {
+          if let Entry::Occupied(token_entry) = token_map.entry(token) {
+            read_event::handle(token_entry, state);
+          }
+        }
+        poll::read_conditioned(token_map, link_ready, state);
 
-            // Handle poll writeable
-            for event in events.iter() {
-              if event.token() != WAKE_TOKEN && event.is_writable() {
-                if let Entry::Occupied(token_entry) = token_map.entry(event.token()) {
-                  write_event::handle(token_entry, &mut pending_write_keybuf, &mut state);
-                }
-              }
-            };
-
-            // Handle app writes
-            for (token, peer_addr) in rx_write_events.try_iter() {
-              if let Entry::Occupied(token_entry) = token_map.entry(token) {
-                write_event::handle_app(token_entry, peer_addr, &mut state);
-              }
+        // Handle listener close
+        for token in rx_close_listener_events.try_iter() {
+          if let Entry::Occupied(token_entry) = token_map.entry(token) {
+            listen_close_event::handle(token_entry, state);
+          }
+        }
+
+        // Handle timer expiry
+        // NOTE: Occurs after poll read events to allow time to fill the read buffer, last chance to ack heartbeat, etc if necessary
+        let now = state.clock.now();
+        expired_timers.extend(state.timers.expire(now));
+        for ((token, peer_addr), kind) in expired_timers.drain(..) {
+          if let Entry::Occupied(token_entry) = token_map.entry(token) {
+            timer_event::handle(token_entry, peer_addr, kind, state);
+          }
+        }
+
+        // Handlers run after reads and timers, so what they send goes out with the writes below
+        handler_event::handle(token_map, state);
+
+        // Handle poll writeable
+        for event in events.iter() {
+          if event.token() != WAKE_TOKEN && event.is_writable() {
+            if let Entry::Occupied(token_entry) = token_map.entry(event.token()) {
+              write_event::handle(token_entry, pending_write_keybuf, state);
             }
-          },
+          }
+        };
 
-          Err(e) => return poll::handle_failure(e, &mut token_map)
+        // Handle app writes
+        for (token, peer_addr) in rx_write_events.try_iter() {
+          if let Entry::Occupied(token_entry) = token_map.entry(token) {
+            write_event::handle_app(token_entry, peer_addr, state);
+          }
         }
+
+        poll::send_conditioned(token_map, state);
+        poll::retry_quarantine(state);
+      },
+
+      Err(e) => return Err(poll::handle_failure(e, token_map, state))
+    }
+
+    // Once shutting down, exit as soon as every socket is done, or give up on them at the deadline
+    if let Some(deadline) = state.shutdown {
+      if !token_map.is_empty() && state.clock.now() >= deadline {
+        poll::abandon_all(token_map, state);
       }
-    })
+      if token_map.is_empty() { return Ok(false); }
+    }
+    Ok(true)
+  }
+
+  // Starts listening right away, rather than waiting on the next step like Service::listen would
+  pub fn listen(&mut self, socket: T::Socket) -> io::Result<Listener> {
+    self.listen_with(socket, None)
+  }
+
+  // Like listen, but the handler serves every connection as the daemon is stepped. See Service::listen_with_handler
+  pub fn listen_with_handler<H: 'static + Handler>(&mut self, socket: T::Socket, handler: H) -> io::Result<Listener> {
+    self.listen_with(socket, Some(Box::new(handler)))
+  }
+
+  fn listen_with(&mut self, socket: T::Socket, handler: Option<Box<dyn Handler>>) -> io::Result<Listener> {
+    let (tx, rx) = channel::bounded(usize::max(self.state.conf.accept_backlog, 1));
+    service_event::handle(FromService::Listen(socket, tx, handler), &mut self.token_map, &mut self.state);
+
+    match rx.try_recv() {
+      Ok(ToService::Listener(on_close, signal)) => Ok(Listener::stepped(on_close, signal, rx)),
+      _ => Err(error::cannot_register_with_daemon())
+    }
+  }
+
+  // Starts connecting right away. The connection is ready once the daemon has been stepped through the handshake
+  pub fn connect<A: ToSocketAddrs>(&mut self, socket: T::Socket, to_addr: A) -> io::Result<Connecting> {
+    self.connect_with_token(socket, to_addr, &[])
+  }
+
+  // Like connect, but the first packet carries a payload such as a join token. See Service::connect_with_token
+  pub fn connect_with_token<A: ToSocketAddrs>(&mut self, socket: T::Socket, to_addr: A, token: &[u8]) -> io::Result<Connecting> {
+    service::check_hello(self.state.conf.max_datagram_size, token)?;
+    let peer_addr = service::resolve(to_addr)?;
+
+    let (tx, rx) = channel::bounded(2);
+    service_event::handle(FromService::Connect(socket, tx, peer_addr, token.to_vec()), &mut self.token_map, &mut self.state);
+    Ok(Connecting::new(rx))
+  }
+}
+
+// However the daemon is dropped, it has stopped
+impl<C: Clock, T: Transport> Drop for Daemon<C, T> {
+  fn drop(&mut self) {
+    self.health.exited(Health::Stopped);
+  }
 }
@@ -1,18 +1,23 @@
 use std::io;
-use std::collections::HashMap;
-use std::net::UdpSocket as StdUdpSocket;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{Entry, OccupiedEntry};
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering::SeqCst as OSeqCst;
 
 use log::warn;
-use mio::{Token, Interest};
-use mio::net::UdpSocket as MioUdpSocket;
+use mio::Token;
 
 use clock::Clock;
 
-use crate::daemon;
+use crate::daemon::{self, read_event};
 use crate::socket::{Socket, PeerType};
+use crate::health::Event;
+use crate::state::{self, lock_buf};
+use crate::constants::control;
+use crate::error;
+use crate::transport::Transport;
 
-pub fn handle_failure(e: io::Error, token_map: &mut HashMap<Token, Socket>) -> io::Error {
+pub fn handle_failure<C: Clock, T: Transport>(e: io::Error, token_map: &mut HashMap<Token, Socket<T>>, s: &daemon::State<C, T>) -> io::Error {
   // Call to the system selector failed.
   // We cannot perform any evented IO without it.
   // It's possible this error has non-fatal variants, but it's
@@ -21,50 +26,137 @@ pub fn handle_failure(e: io::Error, token_map: &mut HashMap<Token, Socket>) -> i
   for (_, socket) in token_map.into_iter() {
     match &socket.peer_type {
       PeerType::Direct(_addr, state) => {
-        let (ref buf_read, ref _buf_write, ref status, _) = *state.shared;
-        let lock = buf_read.lock().expect("Could not acquire unpoisoned read lock");
+        let (ref buf_read, ref _buf_write, ref status, _, _, _) = *state.shared;
+        let lock = lock_buf(buf_read, status);
         status.set_io_err(errno);
         lock.notify_all();
         drop(lock);
+        state.notify_write_waiters();
       }
 
       PeerType::Passive { ref peers, .. } => {
         for (_addr, peer_state) in peers.iter() {
-          let (ref buf_read, ref _buf_write, ref status, _) = *peer_state.shared;
-          let lock = buf_read.lock().expect("Could not acquire unpoisoned read lock");
+          let (ref buf_read, ref _buf_write, ref status, _, _, _) = *peer_state.shared;
+          let lock = lock_buf(buf_read, status);
           status.set_io_err(errno);
           lock.notify_all();
           drop(lock);
+          peer_state.notify_write_waiters();
         }
       },
     }
   }
 
+  s.report(Event::PollFailed(error::duplicate(&e)));
   e
 }
 
-pub fn register_io<C: Clock>(io: StdUdpSocket, s: &mut daemon::State<C>) -> Option<(Token, MioUdpSocket, SocketAddr)> {
-  // Create a mio wrapper for the socket.
-  let mut conn = MioUdpSocket::from_std(io);
+// Tells every remaining peer we're going away, without waiting on their queued writes, and frees all io
+pub fn abandon_all<C: Clock, T: Transport>(token_map: &mut HashMap<Token, Socket<T>>, s: &mut daemon::State<C, T>) {
+  for (token, mut socket) in token_map.drain() {
+    // Queued writes go before the disconnect, if the transport takes them now
+    socket.outbox.flush(&socket.io).ok();
+    match socket.peer_type {
+      PeerType::Direct(peer_addr, ref state) => {
+        state::send_disconnect(&socket.io, (token, peer_addr), control::reason::CLOSED, s).ok();
+        state.on_abandoned();
+      },
+
+      PeerType::Passive { ref peers, .. } => {
+        for (peer_addr, peer_state) in peers.iter() {
+          state::send_disconnect(&socket.io, (token, *peer_addr), control::reason::CLOSED, s).ok();
+          peer_state.on_abandoned();
+        }
+      }
+    }
+    close_socket(socket, s);
+  }
+}
+
+pub fn register_io<C: Clock, T: Transport>(io: T::Socket, s: &mut daemon::State<C, T>) -> Option<(Token, T, SocketAddr)> {
+  // Open the app's socket as the daemon's transport
+  let mut conn = T::open(io);
+  // Best effort; without offload every datagram is simply its own
+  if s.offload() { conn.offload(); }
 
   // Associate this io with a token
   let token = Token(s.next_conn_id);
   s.next_conn_id += 1;
 
   // Register this io with its token for polling
-  s.poll.registry()
-    .register(&mut conn, token, Interest::READABLE | Interest::WRITABLE)
+  conn.register(s.poll.registry(), token, &s.readiness)
     .and_then(|_| conn.local_addr())
     .map(|addr| (token, conn, addr))
     .ok()
 }
 
-// If the deregister fails, we'll silently leak the file descriptor
-// For now, simply log if this occurs.
-// TODO: We could bubble up hanging resources to the main loop,
-// where we iterate on trying to deregister them.
-pub fn deregister_io<C: Clock>(io: &mut MioUdpSocket, s: &daemon::State<C>) {
-  s.poll.registry().deregister(io).unwrap_or_else(|e| {
-    warn!("Unable to deregister socket from poll on close. The socket fd may leak! Reason: {}", e);
-  });
+// Cancels the timers of every connection on the socket, then deregisters and closes it
+pub fn close_socket<C: Clock, T: Transport>(mut socket: Socket<T>, s: &mut daemon::State<C, T>) {
+  cancel_timers(&mut socket.peer_type, s);
+  deregister_io(socket.io, s);
+}
+
+// Closes a socket whose connections are all finished, once its outbox is flushed.
+// While the transport won't take the rest, the socket stays registered without connections, for the next writable event to finish
+pub fn finish_socket<C: Clock, T: Transport>(mut token_entry: OccupiedEntry<Token, Socket<T>>, s: &mut daemon::State<C, T>) {
+  let socket = token_entry.get_mut();
+  if socket.outbox.is_empty() { return close_socket(token_entry.remove(), s); }
+
+  let finished = PeerType::Passive { peers: HashMap::new(), pending_writes: HashSet::new(), listen: None };
+  cancel_timers(&mut std::mem::replace(&mut socket.peer_type, finished), s);
+}
+
+fn cancel_timers<C: Clock, T: Transport>(peer_type: &mut PeerType, s: &mut daemon::State<C, T>) {
+  match peer_type {
+    PeerType::Direct(_, ref mut state) => state.cancel_timers(s),
+    PeerType::Passive { ref mut peers, .. } => {
+      for peer_state in peers.values_mut() { peer_state.cancel_timers(s); }
+    }
+  }
+}
+
+// Deregisters and closes the socket.
+// If the deregister fails, the socket is quarantined instead, and deregistering is retried from the main loop
+pub fn deregister_io<C: Clock, T: Transport>(mut io: T, s: &mut daemon::State<C, T>) {
+  if let Err(e) = io.deregister(s.poll.registry()) {
+    warn!("Unable to deregister socket from poll on close. Quarantining it to retry later. Reason: {}", e);
+    if let Ok(local_addr) = io.local_addr() {
+      s.report(Event::DeregisterFailed { local_addr, error: e });
+    }
+
+    let now = s.clock.now();
+    s.quarantine.add(io, now);
+    s.metrics.quarantined_sockets.store(s.quarantine.len() as u64, OSeqCst);
+  }
+}
+
+pub fn retry_quarantine<C: Clock, T: Transport>(s: &mut daemon::State<C, T>) {
+  if s.quarantine.is_empty() { return; }
+  let now = s.clock.now();
+  s.quarantine.retry(s.poll.registry(), now);
+  s.metrics.quarantined_sockets.store(s.quarantine.len() as u64, OSeqCst);
+}
+
+// Reads sockets whose conditioned inbound datagrams have come due
+pub fn read_conditioned<C: Clock, T: Transport>(token_map: &mut HashMap<Token, Socket<T>>, ready: &mut Vec<Token>, s: &mut daemon::State<C, T>) {
+  let now = s.clock.now();
+  match s.link { Some(ref link) => link.ready_inbound(now, ready), None => return };
+  for token in ready.drain(..) {
+    match token_map.entry(token) {
+      Entry::Occupied(token_entry) => read_event::handle(token_entry, s),
+      Entry::Vacant(_) => if let Some(ref mut link) = s.link { link.forget(token) }
+    }
+  }
+}
+
+// Sends conditioned outbound datagrams that have come due. Those for closed sockets are lost.
+// They go straight to the transport rather than through the socket's outbox, as their delay already reorders them
+pub fn send_conditioned<C: Clock, T: Transport>(token_map: &HashMap<Token, Socket<T>>, s: &mut daemon::State<C, T>) {
+  let now = s.clock.now();
+  let link = match s.link { Some(ref mut link) => link, None => return };
+  while let Some(((token, peer_addr), datagram)) = link.pop_outbound(now) {
+    if let Some(socket) = token_map.get(&token) {
+      socket.io.send_to(&datagram, peer_addr).ok();
+    }
+  }
 }
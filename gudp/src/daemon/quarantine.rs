@@ -0,0 +1,84 @@
+use std::io;
+use std::time::Instant;
+
+use mio::Registry;
+use crate::constants::time_ms;
+use crate::transport::Transport;
+
+// Sockets which failed to deregister from poll.
+// Closing them anyway could leave a stale registration behind, so they are held and retried until deregistering succeeds
+pub struct Quarantine<T: Transport> {
+  sockets: Vec<T>,
+  retry_at: Option<Instant>
+}
+
+impl<T: Transport> Quarantine<T> {
+  pub fn new() -> Quarantine<T> {
+    Quarantine { sockets: vec![], retry_at: None }
+  }
+
+  pub fn len(&self) -> usize {
+    self.sockets.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.sockets.is_empty()
+  }
+
+  pub fn retry_at(&self) -> Option<Instant> {
+    self.retry_at
+  }
+
+  pub fn add(&mut self, io: T, now: Instant) {
+    self.sockets.push(io);
+    self.retry_at.get_or_insert(now + time_ms::QUARANTINE_RETRY);
+  }
+
+  // Tries to deregister every socket again, once it's time to. Sockets which succeed are closed
+  pub fn retry(&mut self, registry: &Registry, now: Instant) {
+    if self.retry_at.map(|retry_at| now < retry_at).unwrap_or(true) { return; }
+
+    self.sockets.retain_mut(|io| match io.deregister(registry) {
+      Ok(()) => false,
+      // Not registered (anymore) is as good as deregistered
+      Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+      Err(_) => true
+    });
+    self.retry_at = if self.sockets.is_empty() { None } else { Some(now + time_ms::QUARANTINE_RETRY) };
+  }
+}
+
+impl<T: Transport> Default for Quarantine<T> {
+  fn default() -> Quarantine<T> {
+    Quarantine::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Instant;
+  use mio::Poll;
+  use crate::constants::time_ms;
+  use crate::transport::{Transport, Udp};
+  use super::Quarantine;
+
+  #[test]
+  fn retries_on_schedule() {
+    let poll = Poll::new().unwrap();
+    let io = Udp::open(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+    let now = Instant::now();
+    let mut quarantine = Quarantine::new();
+
+    quarantine.add(io, now);
+    assert_eq!(quarantine.retry_at(), Some(now + time_ms::QUARANTINE_RETRY));
+
+    // Too soon to retry
+    quarantine.retry(poll.registry(), now);
+    assert_eq!(quarantine.len(), 1);
+
+    // The socket was never registered, so it's free to go
+    quarantine.retry(poll.registry(), now + time_ms::QUARANTINE_RETRY);
+    assert!(quarantine.is_empty());
+    assert_eq!(quarantine.retry_at(), None);
+  }
+}
@@ -6,21 +6,25 @@ use mio::Token;
 use clock::Clock;
 
 use crate::socket::{Socket, PeerType};
-use crate::state::State;
+use crate::state::{self, Admitted};
 use crate::daemon::{self, poll};
+use crate::state::Deps;
 use crate::constants::header;
+use crate::metrics::Metrics;
+use crate::health::Event;
+use crate::transport::Transport;
 
-type TokenEntry<'a> = OccupiedEntry<'a, Token, Socket>;
-pub fn handle<C: Clock>(mut token_entry: TokenEntry, s: &mut daemon::State<C>) {
+type TokenEntry<'a, T> = OccupiedEntry<'a, Token, Socket<T>>;
+pub fn handle<C: Clock, T: Transport>(mut token_entry: TokenEntry<T>, s: &mut daemon::State<C, T>) {
   let token = *token_entry.key();
   let socket = token_entry.get_mut();
   let local_addr = socket.local_addr;
 
   // socket read loop
-  // early return on wouldblock
+  // early return on wouldblock, or on a fatal io error after closing the io
   // break on cases where we're finished with the io, to perform io cleanup at the end
   loop {
-    match socket.io.recv_from(&mut s.buf_local) {
+    match s.recv_from(&socket.io, token) {
       Err(e) => {
         // WouldBlock is fine for mio, we just try again later
         if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -40,14 +44,33 @@ pub fn handle<C: Clock>(mut token_entry: TokenEntry, s: &mut daemon::State<C>) {
           };
 
           trace!("OnReadable: IO encountered error, dropping all peers.");
-          break; // Breaking the loop without wouldblock indicates a failure state
+          s.report(Event::SocketError { local_addr, error: e });
+          return poll::close_socket(token_entry.remove(), s);
         }
       },
 
-      Ok((size, peer_addr)) => {
+      Ok((mut size, peer_addr)) => {
+        if size >= s.buf_local.len() {
+          trace!("OnReadable: Dropping datagram truncated to {} bytes from {}", size, peer_addr);
+          Metrics::incr(&s.metrics.truncated_packets);
+          continue;
+        }
+
         // Filter out non-conforming protocol bits as socket noise
-        if size < header::SIZE_BYTES { continue; }
-        if s.buf_local[..4] != header::MAGIC_BYTES { continue; }
+        let is_control = state::is_control(&s.buf_local[..size]);
+        if !is_control {
+          if size < header::SIZE_BYTES { continue; }
+          if s.buf_local[..4] != header::MAGIC_BYTES { continue; }
+        }
+
+        // Rate limits drop datagrams before they can cost any more than this
+        let now = s.clock.now();
+        if let Some(limit) = s.conf.ip_rate_limit {
+          if !s.limiter.take_packet(limit, peer_addr.ip(), now, size) {
+            s.metrics.throttle(peer_addr.ip(), size);
+            continue;
+          }
+        }
 
         match socket.peer_type {
           PeerType::Passive { ref mut peers, ref listen, .. } => {
@@ -57,45 +80,86 @@ pub fn handle<C: Clock>(mut token_entry: TokenEntry, s: &mut daemon::State<C>) {
 
               /* Existing peer */
               (Some(state), _) => {
+                if !state.take_inbound(now, size) {
+                  s.metrics.throttle(peer_addr.ip(), size);
+                  continue;
+                }
+
                 // Returns FALSE if the socket can be cleaned up (read from app end is closed and write to peer buffer is empty)
                 // Returns TRUE otherwise
                 if !state.read(socket.local_addr, peer_addr, size, s) {
                   trace!("OnReadable: Peer is finished, dropping {}", peer_addr);
-                  peers.remove(&peer_addr);
+                  if let Some(mut peer_state) = peers.remove(&peer_addr) { peer_state.cancel_timers(s); }
                   // If no peers left and not actively listening, close and free the resource
                   if peers.len() == 0 && listen.is_none() {
                     trace!("OnReadable: All peers are finished, dropping IO");
                     break;
                   }
-                };
+                } else {
+                  serve(token, state, s);
+                }
               },
 
+              /* unverified new peer */
+              // New peers must prove they own their address before anything else
+              (None, Some(_)) if !state::verify_hello(&socket.io, (token, peer_addr), &mut size, s) => { },
+
               /* create+handle new peer */
               (None, Some(conn_opts)) => {
-                let socket_id = (token, peer_addr);
-                trace!("Creating new peer: {}", peer_addr);
-                let mut peer_state = State::init(local_addr, socket_id, conn_opts.clone(), s);
+                if let Some(per_sec) = s.conf.ip_connect_rate {
+                  if !s.limiter.take_connect(per_sec, peer_addr.ip(), now) {
+                    s.metrics.throttle(peer_addr.ip(), size);
+                    continue;
+                  }
+                }
 
-                // If state update fails, we simply don't insert the new peer
-                if peer_state.read(socket.local_addr, peer_addr, size, s) {
-                  trace!("> Inserting new peer: {}", peer_addr);
-                  peers.insert(peer_addr, peer_state);
-                } else {
-                  trace!("> Ignoring new peer: {}", peer_addr);
-                };
+                trace!("Creating new peer: {}", peer_addr);
+                match state::admit(&socket.io, local_addr, (token, peer_addr), conn_opts, peers.len(), size, s) {
+                  Admitted::Connection(mut peer_state) => {
+                    serve(token, &mut peer_state, s);
+                    trace!("> Inserting new peer: {}", peer_addr);
+                    peers.insert(peer_addr, peer_state);
+                  },
+                  Admitted::Full => {
+                    trace!("> Refusing new peer, accept backlog is full: {}", peer_addr);
+                    Metrics::incr(&s.metrics.refused_peers);
+                  },
+                  Admitted::Refused(refusal) => {
+                    trace!("> Refusing new peer, {:?}: {}", refusal, peer_addr);
+                    Metrics::incr(&s.metrics.denied_peers);
+                  },
+                  // If state update fails, we simply don't insert the new peer
+                  Admitted::Finished => trace!("> Ignoring new peer: {}", peer_addr)
+                }
               },
             }
           }
 
-          PeerType::Direct(peer_addr, ref mut state) => {
-            if !state.read(socket.local_addr, peer_addr, size, s) { break };
+          PeerType::Direct(direct_addr, ref mut state) => {
+            // Only our one peer may tell us to hang up
+            if is_control && direct_addr != peer_addr { continue; }
+            if !state.take_inbound(now, size) {
+              s.metrics.throttle(peer_addr.ip(), size);
+              continue;
+            }
+            if !state.read(socket.local_addr, direct_addr, size, s) { break };
           }
         }
       }
     }
   }
 
-  // Reach here when the state machine is terminal or a fatal io error occurs
-  poll::deregister_io(&mut socket.io, s);
-  token_entry.remove();
+  // Reach here when the state machine is terminal
+  poll::finish_socket(token_entry, s);
+}
+
+// A listener's handler runs over what the connection just read while it is still in the buffer
+fn serve<C: Clock, T: Transport>(token: Token, state: &mut state::State, s: &mut daemon::State<C, T>) {
+  if state.handling.is_none() { return; }
+  let now = s.clock.now();
+  let wrote = match s.handlers.get_mut(&token) {
+    Some(handled) => handled.deliver(state, &s.buf_local, now),
+    None => false
+  };
+  if wrote { s.notify_write(state.socket_id); }
 }
@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
 use std::sync::Arc;
 use std::io;
 
@@ -9,19 +10,24 @@ use clock::Clock;
 use crate::socket::{Socket, PeerType, ConnOpts};
 use crate::types::FromDaemon as ToService;
 use crate::types::ToDaemon as FromService;
-use crate::state::State;
+use crate::state::{State, Deps, Signal};
 use crate::daemon::{self, poll};
 use crate::error;
+use crate::transport::Transport;
+use crate::handler::Handled;
 
-pub fn handle<C: Clock>(msg: FromService, token_map: &mut HashMap<Token, Socket>, s: &mut daemon::State<C>) {
+pub fn handle<C: Clock, T: Transport>(msg: FromService<T>, token_map: &mut HashMap<Token, Socket<T>>, s: &mut daemon::State<C, T>) {
   match msg {
-    FromService::Connect(io, respond_tx, peer_addr) => {
+    // Once shutting down, new sockets are refused by dropping them along with their response channel
+    FromService::Connect(..) | FromService::Listen(..) if s.shutdown.is_some() => { },
+
+    FromService::Connect(io, respond_tx, peer_addr, hello) => {
       match poll::register_io(io, s) {
         Some((token, conn, local_addr)) => {
-          let conn_opts = ConnOpts::new(token, respond_tx, s.tx_on_write.clone(), Arc::clone(&s.waker));
+          let conn_opts = ConnOpts::new(token, respond_tx, s.tx_on_write.clone(), Some(Arc::clone(&s.waker)));
           // TODO: Better name than socket_id? Maybe io_conn_id?
           let socket_id = (token, peer_addr);
-          let state = State::init(local_addr, socket_id, conn_opts, s);
+          let state = State::init(local_addr, socket_id, conn_opts, hello, s);
           let socket = Socket::new(conn, local_addr, PeerType::Direct(peer_addr, state));
           token_map.insert(token, socket);
         }
@@ -29,9 +35,40 @@ pub fn handle<C: Clock>(msg: FromService, token_map: &mut HashMap<Token, Socket>
       }
     }
 
-    FromService::Listen(io, respond_tx) => {
+    FromService::Shutdown(linger) => {
+      s.shutdown = Some(s.clock.now() + linger);
+
+      // Stop listening, and hang up every connection once its queued writes are flushed
+      let mut idle = vec![];
+      for (token, socket) in token_map.iter_mut() {
+        match socket.peer_type {
+          PeerType::Direct(_, ref state) => {
+            state.on_shutdown();
+            s.notify_write(state.socket_id);
+          },
+
+          PeerType::Passive { ref mut listen, ref peers, .. } => {
+            if let Some(conn_opts) = listen.take() { conn_opts.close(); } // Closes the channel listeners await new connections on
+            for (_, peer_state) in peers.iter() {
+              peer_state.on_shutdown();
+              s.notify_write(peer_state.socket_id);
+            }
+            if peers.is_empty() { idle.push(*token); }
+          }
+        }
+      }
+
+      // Listeners without peers are already done
+      for token in idle {
+        if let Entry::Occupied(token_entry) = token_map.entry(token) {
+          poll::finish_socket(token_entry, s);
+        }
+      }
+    }
+
+    FromService::Listen(io, respond_tx, handler) => {
       match poll::register_io(io, s) {
-        Some((token, mut conn, local_addr)) => {
+        Some((token, conn, local_addr)) => {
           let on_close = {
             let tx_on_close = s.tx_on_close.clone();
             let waker = Arc::clone(&s.waker);
@@ -42,19 +79,33 @@ pub fn handle<C: Clock>(msg: FromService, token_map: &mut HashMap<Token, Socket>
             }
           };
 
-          respond_tx.send(ToService::Listener(Box::new(on_close)))
-            .map_err(|_| poll::deregister_io(&mut conn, s)).ok()
-            .map(|_| {
+          let signal = Arc::new(Signal::new());
+          match respond_tx.send(ToService::Listener(Box::new(on_close), Arc::clone(&signal))) {
+            // The service is no longer waiting on this listener
+            Err(_) => poll::deregister_io(conn, s),
+            Ok(_) => {
+              // A handled listener's connections stay on this thread. The app's listener only ever closes it
+              let handled = handler.is_some();
+              let tx_to_service = match handler {
+                Some(handler) => {
+                  let (mut handled, tx) = Handled::new(handler, s.conf.accept_backlog);
+                  handled.start(s);
+                  s.handlers.insert(token, handled);
+                  tx
+                },
+                None => respond_tx
+              };
               let tx_on_write = s.tx_on_write.clone();
               let waker = Arc::clone(&s.waker);
               let peers = HashMap::new();
-              let listen = Some(ConnOpts::new(token, respond_tx, tx_on_write, waker));
+              let listen = Some(ConnOpts { signal, handled, ..ConnOpts::new(token, tx_to_service, tx_on_write, Some(waker)) });
               let pending_writes = HashSet::new();
               token_map.insert(
                 token,
                 Socket::new(conn, local_addr, PeerType::Passive { peers, listen, pending_writes })
               );
-            });
+            }
+          }
         },
         None => drop(respond_tx)
       }
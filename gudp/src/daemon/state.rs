@@ -1,32 +1,86 @@
+use std::collections::HashMap;
 use std::slice::SliceIndex;
 use std::net::SocketAddr;
+use std::io;
 use std::sync::Arc;
+use std::time::Instant;
 
 use crossbeam::channel;
 use mio::{Poll, Token, Waker};
 use clock::Clock;
 
 use crate::service::Conf;
+use crate::metrics::Metrics;
+use crate::health::Event;
+use crate::cookie;
+use crate::admission;
+use crate::constants::header;
+use crate::ratelimit;
+use crate::conditioner::Link;
+use crate::daemon::quarantine::Quarantine;
 use crate::socket;
 use crate::timer::{self, TimerKind};
 use crate::state::Deps;
+use crate::transport::{Transport, Readiness, Output, Inbox};
 use crate::warn;
+use crate::handler::Handled;
 
 // Contains all the state used by the single threaded event loop handlers and state changes
-pub struct State<C: Clock> {
+pub struct State<C: Clock, T: Transport> {
   pub poll: Poll,
   pub waker: Arc<Waker>,
+  pub readiness: Readiness,
   pub tx_on_write: channel::Sender<socket::Id>,
   pub tx_on_close: channel::Sender<Token>,
   pub next_conn_id: usize,
   pub buf_local: Vec<u8>,
-  pub timers: timer::List<(socket::Id, TimerKind)>,
+  // Datagrams received in the last batch and not yet read, and the socket they came in on
+  pub inbox: Inbox,
+  pub inbox_token: Option<Token>,
+  pub timers: timer::Wheel<(socket::Id, TimerKind)>,
   pub conf: Conf,
+  pub metrics: Arc<Metrics>,
+  pub tx_events: channel::Sender<Event>,
+  pub cookies: cookie::Jar,
+  pub limiter: ratelimit::Limiter,
+  pub shutdown: Option<Instant>,
+  pub quarantine: Quarantine<T>,
+  pub link: Option<Link>,
+  // Listeners whose connections a Handler serves on this thread
+  pub handlers: HashMap<Token, Handled>,
   pub clock: C
 }
 
-impl<C: Clock> Deps for State<C> {
-  fn timers(&mut self) -> &mut timer::List<(socket::Id, TimerKind)> {
+impl<C: Clock, T: Transport> State<C, T> {
+  // Best effort; if nobody is draining the events channel, new events are dropped rather than stall the daemon
+  pub fn report(&self, event: Event) {
+    self.tx_events.try_send(event).ok();
+  }
+
+  // Receives into the local buffer, through the link conditioner if any.
+  // Otherwise datagrams come off the socket a batch at a time, and out of the inbox one at a time
+  pub fn recv_from(&mut self, io: &T, token: Token) -> io::Result<(usize, SocketAddr)> {
+    if let Some(ref mut link) = self.link {
+      return link.recv_from(io, token, &mut self.buf_local, self.clock.now());
+    }
+
+    // Whatever is left is from a socket dropped mid-batch
+    if self.inbox_token != Some(token) {
+      self.inbox.clear();
+      self.inbox_token = Some(token);
+    }
+    if self.inbox.is_empty() { io.recv_batch(&mut self.inbox)?; }
+    self.inbox.pop(&mut self.buf_local).ok_or_else(|| io::ErrorKind::WouldBlock.into())
+  }
+
+  // Whether sockets have the kernel segment and coalesce datagrams. The conditioner needs to see each one
+  pub fn offload(&self) -> bool {
+    self.conf.udp_offload && self.link.is_none()
+  }
+}
+
+impl<C: Clock, T: Transport> Deps for State<C, T> {
+  fn timers(&mut self) -> &mut timer::Wheel<(socket::Id, TimerKind)> {
     &mut self.timers
   }
 
@@ -46,6 +100,14 @@ impl<C: Clock> Deps for State<C> {
     self.tx_on_write.send(socket_id).unwrap_or_else(warn::tx_to_write_send_failed);
   }
 
+  fn send_to<O: Output>(&mut self, io: &O, socket_id: socket::Id, size: usize) -> io::Result<usize> {
+    let datagram = &self.buf_local[..size];
+    match self.link {
+      Some(ref mut link) => link.send_to(io, socket_id, datagram, self.clock.now()),
+      None => io.send_to(datagram, socket_id.1)
+    }
+  }
+
   fn on_packet_sent<I>(&mut self, addr_pair: (SocketAddr, SocketAddr), index: I, sequence_no: u32)
   where I: SliceIndex<[u8], Output = [u8]> {
     let buf = &self.buf_local[index];
@@ -59,4 +121,12 @@ impl<C: Clock> Deps for State<C> {
   fn conf(&self) -> &Conf {
     &self.conf
   }
+
+  fn cookies(&self) -> &cookie::Jar {
+    &self.cookies
+  }
+
+  fn admit(&mut self, peer_addr: SocketAddr, n_peers: usize, size: usize) -> Result<(), admission::Refusal> {
+    admission::check(&mut self.conf, peer_addr, n_peers, &self.buf_local[header::SIZE_BYTES..size])
+  }
 }
@@ -9,16 +9,16 @@ use clock::Clock;
 use crate::socket::{Socket, PeerType};
 use crate::daemon::{self, poll};
 use crate::timer::TimerKind;
+use crate::transport::Transport;
 
-type TokenEntry<'a> = OccupiedEntry<'a, Token, Socket>;
+type TokenEntry<'a, T> = OccupiedEntry<'a, Token, Socket<T>>;
 
-pub fn handle<C: Clock>(mut token_entry: TokenEntry, peer_addr: SocketAddr, kind: TimerKind, s: &mut daemon::State<C>) {
+pub fn handle<C: Clock, T: Transport>(mut token_entry: TokenEntry<T>, peer_addr: SocketAddr, kind: TimerKind, s: &mut daemon::State<C, T>) {
   let socket = token_entry.get_mut();
   match socket.peer_type {
     PeerType::Direct(_, ref mut state) => {
       if !state.timer(kind, s) {
-        poll::deregister_io(&mut socket.io, s);
-        token_entry.remove();
+        poll::finish_socket(token_entry, s);
       }
     },
 
@@ -27,11 +27,10 @@ pub fn handle<C: Clock>(mut token_entry: TokenEntry, peer_addr: SocketAddr, kind
         if !state.timer(kind, s) {
           trace!("OnTimeout: Peer is finished, dropping {}", peer_addr);
 
-          peers.remove(&peer_addr);
+          if let Some(mut peer_state) = peers.remove(&peer_addr) { peer_state.cancel_timers(s); }
           if peers.len() == 0 && listen.is_none() {
             trace!("OnTimeout: All peers are finished, dropping IO");
-            poll::deregister_io(&mut socket.io, s);
-            token_entry.remove();
+            poll::finish_socket(token_entry, s);
           }
         }
       }
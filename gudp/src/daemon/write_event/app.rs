@@ -1,54 +1,66 @@
-use std::collections::hash_map::OccupiedEntry;
 use std::net::SocketAddr;
 
 use log::trace;
-use mio::Token;
 
 use clock::Clock;
 
-use crate::socket::{Socket, PeerType};
+use crate::socket::PeerType;
 use crate::daemon::{self, poll};
+use crate::transport::{Transport, Batched};
+use super::{TokenEntry, flushed, fail};
 
 // Handling app writes are subtly different than socket writeable events
 // In the case of a direct connection, the two are identical
 // In the case of a passive listener connection...
 //  App writes are only for a given peer, and add to the pending writers list on block.
 //  Writeable events walk the list and try to write for all pending writers of an io until the io would block again.
-type TokenEntry<'a> = OccupiedEntry<'a, Token, Socket>;
-pub fn handle<C: Clock>(mut token_entry: TokenEntry, peer_addr: SocketAddr, s: &mut daemon::State<C>) {
+pub fn handle<C: Clock, T: Transport>(mut token_entry: TokenEntry<T>, peer_addr: SocketAddr, s: &mut daemon::State<C, T>) {
   let socket = token_entry.get_mut();
-  match &mut socket.peer_type {
+
+  // Datagrams a blocked batch left behind go before anything newer
+  match socket.outbox.flush(&socket.io) {
+    Ok(()) => { },
+    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+      if let PeerType::Passive { ref mut pending_writes, .. } = socket.peer_type {
+        pending_writes.insert(peer_addr);
+      }
+      return;
+    },
+    Err(e) => {
+      trace!("App Write: IO encountered error, dropping all peers.");
+      return fail(token_entry, e, s);
+    }
+  }
+  // A finished socket was only waiting on its outbox
+  if socket.is_finished() { return poll::close_socket(token_entry.remove(), s); }
+
+  let io = Batched::new(&socket.io, &mut socket.outbox, s.conf.batch_size);
+  let outcome = match &mut socket.peer_type {
     PeerType::Passive { peers, ref listen, pending_writes } => {
       match (peers.get_mut(&peer_addr), listen) {
-        (None, _) => { /* discard socket noise */ },
+        (None, _) => { Ok(true) /* discard socket noise */ },
         (Some(peer_state), _) => {
-          match peer_state.write(&mut socket.io, peer_addr, s) {
+          match peer_state.write(&io, peer_addr, s) {
             // Success, pending write fulfilled if present
-            Ok(true) => { pending_writes.remove(&peer_addr); },
+            Ok(true) => { pending_writes.remove(&peer_addr); Ok(true) },
             // Peer hung up and no reads left, can clean up the resource
             Ok(false) => {
               trace!("App Write: Peer is finished, dropping {}", peer_addr);
-              peers.remove(&peer_addr);
+              if let Some(mut peer_state) = peers.remove(&peer_addr) { peer_state.cancel_timers(s); }
 
-              if peers.len() == 0 && listen.is_none() {
-                trace!("App Write: All peers are finished, dropping IO");
-                poll::deregister_io(&mut socket.io, s);
-                token_entry.remove();
-              }
+              let finished = peers.len() == 0 && listen.is_none();
+              if finished { trace!("App Write: All peers are finished, dropping IO"); }
+              Ok(!finished)
             },
             Err(e) => {
               // WouldBlock is fine for mio, we just try again later
               if e.kind() == std::io::ErrorKind::WouldBlock {
                 // mark pending write if absent
                 pending_writes.insert(peer_addr);
+                Ok(true)
               } else {
-                let errno = e.raw_os_error();
-                for (_addr, peer_state) in peers.iter() {
-                  peer_state.on_io_error(errno);
-                }
-                  trace!("App Write: IO encountered error, dropping all peers. Caused by {}", peer_addr);
-                poll::deregister_io(&mut socket.io, s);
-                token_entry.remove();
+                trace!("App Write: IO encountered error, dropping all peers. Caused by {}", peer_addr);
+                Err(e)
               }
             }
           }
@@ -57,21 +69,19 @@ pub fn handle<C: Clock>(mut token_entry: TokenEntry, peer_addr: SocketAddr, s: &
     },
 
     PeerType::Direct(addr, state) => {
-      match state.write(&mut socket.io, *addr, s) {
-        Ok(true) => { /* Success */ },
-        Ok(false) => { // Resource ready to be cleaned up
-          poll::deregister_io(&mut socket.io, s);
-          token_entry.remove();
-        },
-        Err(e) => {
-          if e.kind() != std::io::ErrorKind::WouldBlock {
-            let errno = e.raw_os_error();
-            state.on_io_error(errno);
-            poll::deregister_io(&mut socket.io, s);
-            token_entry.remove();
-          }
-        }
+      match state.write(&io, *addr, s) {
+        // Resource ready to be cleaned up once false
+        Ok(open) => Ok(open),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(true),
+        Err(e) => Err(e)
       }
     }
+  };
+
+  // Everything the write sent goes out in as few batches as it takes
+  match flushed(outcome, io.flush()) {
+    Ok(true) => { },
+    Ok(false) => poll::finish_socket(token_entry, s),
+    Err(e) => fail(token_entry, e, s)
   }
 }
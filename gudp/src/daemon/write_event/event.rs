@@ -1,36 +1,51 @@
-use std::collections::hash_map::OccupiedEntry;
 use std::net::SocketAddr;
 
 use log::trace;
-use mio::Token;
 
 use clock::Clock;
 
-use crate::socket::{Socket, PeerType};
+use crate::socket::PeerType;
 use crate::daemon::{self, poll};
+use crate::transport::{Transport, Batched};
+use super::{TokenEntry, flushed, fail};
 
-type TokenEntry<'a> = OccupiedEntry<'a, Token, Socket>;
-pub fn handle<C: Clock>(mut token_entry: TokenEntry, pending_write_keybuf: &mut Vec<SocketAddr>, s: &mut daemon::State<C>) {
+pub fn handle<C: Clock, T: Transport>(mut token_entry: TokenEntry<T>, pending_write_keybuf: &mut Vec<SocketAddr>, s: &mut daemon::State<C, T>) {
   let socket = token_entry.get_mut();
-  match &mut socket.peer_type {
+
+  // Datagrams a blocked batch left behind go before anything newer
+  match socket.outbox.flush(&socket.io) {
+    Ok(()) => { },
+    // WouldBlock is fine for mio, we just try again later
+    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+    Err(e) => {
+      trace!("OnWriteable: IO encountered error, dropping all peers.");
+      return fail(token_entry, e, s);
+    }
+  }
+  // A finished socket was only waiting on its outbox
+  if socket.is_finished() { return poll::close_socket(token_entry.remove(), s); }
+
+  let io = Batched::new(&socket.io, &mut socket.outbox, s.conf.batch_size);
+  let outcome = match &mut socket.peer_type {
     PeerType::Passive { ref mut peers, ref listen, ref mut pending_writes } => {
+      let mut outcome = Ok(true);
+      pending_write_keybuf.clear();
       pending_write_keybuf.extend(pending_writes.iter().copied());
       for peer_addr in pending_write_keybuf.iter() {
         match (peers.get_mut(peer_addr), listen) {
           (None, _) => { /* discard socket noise */ },
           (Some(peer_state), _) => {
-            match peer_state.write(&mut socket.io, *peer_addr, s) {
+            match peer_state.write(&io, *peer_addr, s) {
               // Success; pending write fulfilled
               Ok(true) => { pending_writes.remove(peer_addr); },
               // Peer hung up and no reads left, can clean up the resource
               Ok(false) => {
                 trace!("OnWriteable: Peer is finished, dropping {}", peer_addr);
-                peers.remove(&peer_addr);
+                if let Some(mut peer_state) = peers.remove(&peer_addr) { peer_state.cancel_timers(s); }
 
                 if peers.len() == 0 && listen.is_none() {
                   trace!("OnWriteable: All peers are finished, dropping IO");
-                  poll::deregister_io(&mut socket.io, s);
-                  token_entry.remove();
+                  outcome = Ok(false);
                   break; // Stop iterating peers, they're all gone
                 }
               },
@@ -40,36 +55,29 @@ pub fn handle<C: Clock>(mut token_entry: TokenEntry, pending_write_keybuf: &mut
                   break; // Stop iterating peers, the io would block
                 }
 
-                // SOMEDAY: Convey more error info to app side. Maybe set remote drop flags based on errorkind?
-                let errno = e.raw_os_error();
-                for (_addr, peer_state) in peers.iter() {
-                  peer_state.on_io_error(errno);
-                }
-
                 trace!("OnWriteable: IO encountered error, dropping all peers. Caused by {}", peer_addr);
-                poll::deregister_io(&mut socket.io, s);
-                token_entry.remove();
+                outcome = Err(e);
                 break; // Stop iterating peers, they're all dead
               }
             }
           },
         }
       }
+      outcome
     },
 
     PeerType::Direct(addr, state) => {
-      match state.write(&mut socket.io, *addr, s) {
-        Ok(_) => { /* Success */ },
-        Err(e) => {
-          if e.kind() != std::io::ErrorKind::WouldBlock {
-            // SOMEDAY: Convey more error info to app side. Maybe set remote drop flags based on errorkind?
-            let errno = e.raw_os_error();
-            state.on_io_error(errno);
-            poll::deregister_io(&mut socket.io, s);
-            token_entry.remove();
-          }
-        }
+      match state.write(&io, *addr, s) {
+        Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => Err(e),
+        _ => Ok(true)
       }
     }
+  };
+
+  // Everything the writes sent goes out in as few batches as it takes
+  match flushed(outcome, io.flush()) {
+    Ok(true) => { },
+    Ok(false) => poll::finish_socket(token_entry, s),
+    Err(e) => fail(token_entry, e, s)
   }
 }
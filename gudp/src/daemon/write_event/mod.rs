@@ -3,3 +3,45 @@ mod event;
 
 pub use event::handle;
 pub use app::handle as handle_app;
+
+use std::collections::hash_map::OccupiedEntry;
+use std::io;
+
+use mio::Token;
+
+use clock::Clock;
+
+use crate::socket::{Socket, PeerType};
+use crate::daemon::{self, poll};
+use crate::health::Event;
+use crate::transport::Transport;
+
+type TokenEntry<'a, T> = OccupiedEntry<'a, Token, Socket<T>>;
+
+// What writing to a socket came to, once the batch the writes collected has been flushed.
+// A flush that would block is no failure, as what's left waits in the socket's outbox for the next writable event
+fn flushed(outcome: io::Result<bool>, flush: io::Result<()>) -> io::Result<bool> {
+  let open = outcome?;
+  match flush {
+    Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+    _ => Ok(open)
+  }
+}
+
+// An io error on write is fatal to every peer of the socket, which is dropped
+fn fail<C: Clock, T: Transport>(token_entry: TokenEntry<T>, e: io::Error, s: &mut daemon::State<C, T>) {
+  let socket = token_entry.remove();
+
+  // SOMEDAY: Convey more error info to app side. Maybe set remote drop flags based on errorkind?
+  let errno = e.raw_os_error();
+  match socket.peer_type {
+    PeerType::Direct(_, ref state) => state.on_io_error(errno),
+    PeerType::Passive { ref peers, .. } => {
+      for (_addr, peer_state) in peers.iter() {
+        peer_state.on_io_error(errno);
+      }
+    }
+  }
+  s.report(Event::SocketError { local_addr: socket.local_addr, error: e });
+  poll::close_socket(socket, s);
+}
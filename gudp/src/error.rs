@@ -1,37 +1,198 @@
 use std::sync::PoisonError;
 use std::io;
-use crossbeam::channel::{RecvError, SendError};
+use std::fmt;
+use crossbeam::channel::{RecvError, RecvTimeoutError, SendError};
+
+use crate::constants::control;
+
+// Why a peer said it was going away
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+  Closed,
+  ServerFull,
+  Denied,
+  Unknown(u8)
+}
+
+impl From<u8> for Reason {
+  fn from(reason: u8) -> Reason {
+    match reason {
+      control::reason::CLOSED => Reason::Closed,
+      control::reason::SERVER_FULL => Reason::ServerFull,
+      control::reason::DENIED => Reason::Denied,
+      other => Reason::Unknown(other)
+    }
+  }
+}
+
+// Every gudp failure, and why a connection ended.
+// Converts into an io::Error with a matching kind, and can be recovered from one with Error::from_io
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+  // The peer went quiet for longer than the connection timeout
+  PeerTimedOut,
+  // The peer told us it hung up
+  PeerDisconnected(Reason),
+  // Our app closed the connection, or dropped every handle able to use it
+  LocalClosed,
+  // The socket failed with this os error
+  Io(i32),
+  // The next packet needs a buffer of at least this many bytes. It's still there to be read.
+  BufferTooSmall { needed: usize },
+  // The daemon shut down or is no longer running
+  DaemonGone,
+  // The listener turned us away because it has no room
+  ServerFull,
+  // The listener's admission control turned us away
+  Denied,
+  // An app thread panicked while using the connection
+  Poisoned,
+  // A single send can carry at most this many bytes, set by the max datagram size
+  PayloadTooLarge { max: usize },
+  // The operation didn't complete before its deadline
+  TimedOut,
+  Unknown
+}
+
+impl Error {
+  // Recovers the typed error from an io::Error returned by gudp
+  pub fn from_io(e: &io::Error) -> Option<Error> {
+    e.get_ref()
+      .and_then(|inner| inner.downcast_ref::<Error>())
+      .copied()
+      .or_else(|| e.raw_os_error().map(Error::Io))
+  }
+
+  pub fn kind(&self) -> io::ErrorKind {
+    match self {
+      Error::PeerTimedOut | Error::PeerDisconnected(_) | Error::LocalClosed => io::ErrorKind::ConnectionReset,
+      Error::Io(errno) => io::Error::from_raw_os_error(*errno).kind(),
+      Error::BufferTooSmall { .. } => io::ErrorKind::UnexpectedEof,
+      Error::PayloadTooLarge { .. } => io::ErrorKind::InvalidInput,
+      Error::DaemonGone => io::ErrorKind::BrokenPipe,
+      Error::ServerFull | Error::Denied => io::ErrorKind::ConnectionRefused,
+      Error::TimedOut => io::ErrorKind::TimedOut,
+      Error::Poisoned | Error::Unknown => io::ErrorKind::Other
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::PeerTimedOut => write!(f, "The peer timed out"),
+      Error::PeerDisconnected(reason) => write!(f, "The peer disconnected ({:?})", reason),
+      Error::LocalClosed => write!(f, "Attempted to use after the connection was closed"),
+      Error::Io(errno) => write!(f, "{}", io::Error::from_raw_os_error(*errno)),
+      Error::BufferTooSmall { needed } => write!(f, "Not enough space to read entire packet, {} bytes needed", needed),
+      Error::PayloadTooLarge { max } => write!(f, "Payload is larger than the {} bytes a packet can carry", max),
+      Error::DaemonGone => write!(f, "The daemon is no longer running"),
+      Error::ServerFull => write!(f, "The server is full"),
+      Error::Denied => write!(f, "The server denied the connection"),
+      Error::Poisoned => write!(f, "An app thread panicked while using this connection. It was closed."),
+      Error::TimedOut => write!(f, "Operation timed out before it could complete"),
+      Error::Unknown => write!(f, "An unknown IO error occured")
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+  fn from(e: Error) -> io::Error {
+    match e {
+      // Keep os errors raw, so they look exactly like any other socket error
+      Error::Io(errno) => io::Error::from_raw_os_error(errno),
+      e => io::Error::new(e.kind(), e)
+    }
+  }
+}
 
 pub fn poisoned_write_lock<_T>(_: PoisonError<_T>) -> io::Error {
-  io::Error::new(io::ErrorKind::Other, "Write buffer lock was poisoned. Can not continue.")
+  Error::Poisoned.into()
 }
 
 pub fn poisoned_read_lock<_T>(_: PoisonError<_T>) -> io::Error {
-  io::Error::new(io::ErrorKind::Other, "Read buffer lock was poisoned. Can not continue.")
+  Error::Poisoned.into()
 }
 
 pub fn wake_failed(reason: io::Error) -> io::Error {
   io::Error::new(io::ErrorKind::Other, reason)
 }
 
-pub fn no_space_to_read() -> io::Error {
-  io::Error::new(io::ErrorKind::UnexpectedEof, "Not enough space to read entire packet")
+pub fn no_space_to_read(needed: usize) -> io::Error {
+  Error::BufferTooSmall { needed }.into()
+}
+
+pub fn payload_too_large(max: usize) -> io::Error {
+  Error::PayloadTooLarge { max }.into()
+}
+
+pub fn invalid_max_datagram_size(min: usize) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, format!("Max datagram size must be at least {} bytes", min))
+}
+
+pub fn invalid_batch_size(max: usize) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, format!("Batch size must be from 1 to {}", max))
+}
+
+pub fn invalid_handshake_retry() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, "Handshake retry backoff must be at least 1, and the first retry no later than the longest")
+}
+
+pub fn daemon_is_stepped() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, "The daemon is stepped by the app, which would never see the reply; use Daemon::listen or Daemon::connect")
+}
+
+pub fn refused(reason: Reason) -> io::Error {
+  match reason {
+    Reason::ServerFull => Error::ServerFull,
+    Reason::Denied => Error::Denied,
+    reason => Error::PeerDisconnected(reason)
+  }.into()
+}
+
+pub fn timed_out() -> io::Error {
+  Error::TimedOut.into()
+}
+
+pub fn no_randomness(reason: getrandom::Error) -> io::Error {
+  io::Error::new(io::ErrorKind::Other, reason)
+}
+
+// io::Error can't be cloned, but its kind and errno are all we need to report it twice
+pub fn duplicate(e: &io::Error) -> io::Error {
+  e.raw_os_error()
+    .map(io::Error::from_raw_os_error)
+    .unwrap_or_else(|| io::Error::new(e.kind(), e.to_string()))
 }
 
-pub fn use_after_hup() -> io::Error {
-  io::Error::new(io::ErrorKind::ConnectionReset, "Attempted to use after receiver (or sender) hung up.")
+pub fn daemon_failed(kind: io::ErrorKind) -> io::Error {
+  io::Error::new(kind, "The daemon exited after a fatal io error")
+}
+
+pub fn daemon_panicked() -> io::Error {
+  io::Error::new(io::ErrorKind::Other, "The daemon thread panicked")
 }
 
 pub fn unknown() -> io::Error {
-  io::Error::new(io::ErrorKind::Other, "An unknown IO error occured")
+  Error::Unknown.into()
+}
+
+// The daemon dropped its end of the channel, so there's nobody left to talk to
+pub fn cannot_send_to_daemon<T>(_: SendError<T>) -> io::Error {
+  Error::DaemonGone.into()
 }
 
-pub fn cannot_send_to_daemon<T: 'static + Sync + Send>(reason: SendError<T>) -> io::Error {
-  io::Error::new(io::ErrorKind::BrokenPipe, reason)
+pub fn cannot_recv_from_daemon(_: RecvError) -> io::Error {
+  Error::DaemonGone.into()
 }
 
-pub fn cannot_recv_from_daemon(reason: RecvError) -> io::Error {
-  io::Error::new(io::ErrorKind::BrokenPipe, reason)
+pub fn cannot_recv_from_daemon_timeout(reason: RecvTimeoutError) -> io::Error {
+  match reason {
+    RecvTimeoutError::Timeout => timed_out(),
+    RecvTimeoutError::Disconnected => Error::DaemonGone.into()
+  }
 }
 
 pub fn unexpected_recv_from_daemon() -> io::Error {
@@ -39,7 +200,7 @@ pub fn unexpected_recv_from_daemon() -> io::Error {
 }
 
 pub fn cannot_register_with_daemon() -> io::Error {
-  io::Error::new(io::ErrorKind::BrokenPipe, "Daemon failed to acknowledge io registration")
+  Error::DaemonGone.into()
 }
 
 pub fn socket_addr_failed_to_resolve() -> io::Error {
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bring::Bring;
+use crossbeam::channel;
+
+use clock::Clock;
+
+use crate::constants::{time_ms, header};
+use crate::daemon;
+use crate::error::{self, Error};
+use crate::state::{State, Shared, Deps};
+use crate::transport::Transport;
+use crate::types::FromDaemon;
+
+/// Handles a listener's connections on the daemon thread itself, registered with Service::listen_with_handler.
+/// Each message is handed over straight out of the daemon's receive buffer as it is read, with no app thread, lock
+/// or condvar wakeup in between, and what it sends is queued on the daemon thread and flushed in that pass too.
+/// For echo servers, relays and the like.
+///
+/// Every callback blocks the daemon, and with it every other connection, so keep them short.
+pub trait Handler: Send {
+  fn on_connect(&mut self, _ctx: &mut ConnectionCtx) { }
+
+  fn on_message(&mut self, ctx: &mut ConnectionCtx, payload: &[u8]);
+
+  // The connection is gone, for this reason. Sends are refused from here on
+  fn on_disconnect(&mut self, _ctx: &mut ConnectionCtx, _reason: Error) { }
+
+  // Called for every connection once per Builder::handler_tick
+  fn on_tick(&mut self, _ctx: &mut ConnectionCtx) { }
+}
+
+// So a handler can ride along in the daemon's messages
+impl fmt::Debug for dyn Handler {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Handler")
+  }
+}
+
+/// One of a Handler's connections, for the length of a callback
+pub struct ConnectionCtx<'a> {
+  key: usize,
+  local_addr: SocketAddr,
+  peer_addr: SocketAddr,
+  shared: &'a Shared,
+  // Where sends are queued, until the connection is gone
+  sends: Option<&'a mut Bring>,
+  wrote: bool,
+  close: bool
+}
+
+impl<'a> ConnectionCtx<'a> {
+  // Identifies the connection across callbacks, until it disconnects
+  pub fn key(&self) -> usize {
+    self.key
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  pub fn peer_addr(&self) -> SocketAddr {
+    self.peer_addr
+  }
+
+  pub fn rtt_ms(&self) -> u32 {
+    let (_, _, _, ref netstat_out, _, _) = *self.shared;
+    netstat_out.rtt.load(std::sync::atomic::Ordering::SeqCst)
+  }
+
+  pub fn loss_pct(&self) -> u32 {
+    let (_, _, _, ref netstat_out, _, _) = *self.shared;
+    netstat_out.loss.load(std::sync::atomic::Ordering::SeqCst)
+  }
+
+  // Queues the payload as one packet, like Connection::send. Only the daemon thread ever touches the queue,
+  // so there is no lock to take, and the daemon flushes it before it next waits
+  pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let (_, _, ref status, _, max_payload, _) = *self.shared;
+    status.check_err()?;
+    if buf.len() > max_payload { return Err(error::payload_too_large(max_payload)); }
+
+    let sends = match self.sends {
+      Some(ref mut sends) if !self.close && !status.app_has_hup() => sends,
+      _ => return Err(Error::LocalClosed.into())
+    };
+    self.wrote = true;
+    Ok(sends.push_back(buf))
+  }
+
+  // Hangs up once the callback returns, after flushing what was sent. on_disconnect follows with LocalClosed
+  pub fn close(&mut self) {
+    self.close = true;
+  }
+}
+
+// A connection the handler has heard about
+struct Served {
+  key: usize,
+  local_addr: SocketAddr,
+  // Outlives the daemon's state for the connection, so the handler can still be told why it went
+  shared: Arc<Shared>
+}
+
+// A listener's handler and the connections it serves, kept by the daemon
+pub struct Handled {
+  handler: Box<dyn Handler>,
+  rx: channel::Receiver<FromDaemon>,
+  conns: HashMap<SocketAddr, Served>,
+  next_key: usize,
+  listening: bool,
+  tick: Duration,
+  next_tick: Option<Instant>
+}
+
+impl fmt::Debug for Handled {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Handled").field("connections", &self.conns.len()).field("listening", &self.listening).finish()
+  }
+}
+
+impl Handled {
+  // Also returns the channel the listener holds in place of the app's accept channel. Nothing is ever sent on it;
+  // once the listener and its handshaking peers drop every clone, the listener has closed
+  pub fn new(handler: Box<dyn Handler>, backlog: usize) -> (Handled, channel::Sender<FromDaemon>) {
+    let (tx, rx) = channel::bounded(usize::max(backlog, 1));
+    let handled = Handled {
+      handler, rx,
+      conns: HashMap::new(),
+      next_key: 0,
+      listening: true,
+      tick: time_ms::ZERO,
+      next_tick: None
+    };
+    (handled, tx)
+  }
+
+  pub fn start<C: Clock, T: Transport>(&mut self, s: &daemon::State<C, T>) {
+    self.tick = s.conf.handler_tick;
+    self.next_tick = Some(s.clock.now() + self.tick);
+  }
+
+  // When the handler next ticks, if it has connections to tick
+  pub fn next_tick(&self) -> Option<Instant> {
+    if self.conns.is_empty() { None } else { self.next_tick }
+  }
+
+  // Runs the handler over what the connection just read, its payload still in the daemon's buffer.
+  // Returns whether the handler sent or closed, so the daemon has writes to flush
+  pub fn deliver(&mut self, state: &mut State, buf: &[u8], now: Instant) -> bool {
+    let (connecting, received) = match state.handling {
+      Some(ref mut handling) => (mem::take(&mut handling.connecting), handling.received.take()),
+      None => return false
+    };
+
+    let mut wrote = false;
+    if connecting {
+      // Nothing ticked while there were no connections, so the first waits out a whole tick
+      if self.conns.is_empty() { self.next_tick = Some(now + self.tick); }
+      let peer_addr = state.socket_id.1;
+      let served = Served { key: self.next_key, local_addr: state.local_addr, shared: Arc::clone(&state.shared) };
+      self.next_key += 1;
+      // The peer reconnected before the handler heard its last connection was gone
+      if let Some(gone) = self.conns.insert(peer_addr, served) {
+        let reason = gone.shared.2.close_reason().unwrap_or(Error::Unknown);
+        self.disconnected(peer_addr, gone, reason);
+      }
+      wrote |= self.dispatch(state, |handler, ctx| handler.on_connect(ctx));
+    }
+    if let Some(size) = received {
+      let payload = &buf[header::SIZE_BYTES..size];
+      wrote |= self.dispatch(state, |handler, ctx| handler.on_message(ctx, payload));
+    }
+    wrote
+  }
+
+  // Tells the handler about connections that went since it last ran, and ticks the rest when due.
+  // Returns false once the listener has closed and every connection it served is gone
+  pub fn step<C: Clock, T: Transport>(&mut self, mut peers: Option<&mut HashMap<SocketAddr, State>>, s: &mut daemon::State<C, T>) -> bool {
+    loop {
+      match self.rx.try_recv() {
+        Ok(_) => { },
+        Err(channel::TryRecvError::Empty) => break,
+        Err(channel::TryRecvError::Disconnected) => { self.listening = false; break; }
+      }
+    }
+
+    let gone: Vec<SocketAddr> = self.conns.iter()
+      .filter(|(addr, served)| {
+        let kept = peers.as_ref().and_then(|peers| peers.get(addr)).is_some_and(|state| Arc::ptr_eq(&state.shared, &served.shared));
+        !kept || served.shared.2.close_reason().is_some()
+      })
+      .map(|(addr, _)| *addr)
+      .collect();
+    for addr in gone {
+      if let Some(served) = self.conns.remove(&addr) {
+        let reason = served.shared.2.close_reason().unwrap_or(Error::Unknown);
+        self.disconnected(addr, served, reason);
+      }
+    }
+
+    let now = s.clock.now();
+    if self.next_tick.is_some_and(|next| now >= next) {
+      self.next_tick = Some(now + self.tick);
+      let addrs: Vec<SocketAddr> = self.conns.keys().copied().collect();
+      for addr in addrs {
+        if let Some(state) = peers.as_mut().and_then(|peers| peers.get_mut(&addr)) {
+          if self.dispatch(state, |handler, ctx| handler.on_tick(ctx)) { s.notify_write(state.socket_id); }
+        }
+      }
+    }
+
+    self.listening || !self.conns.is_empty()
+  }
+
+  // Runs one callback for the connection, then hangs up if the handler closed it.
+  // Returns whether the handler sent or closed
+  fn dispatch<F>(&mut self, state: &mut State, f: F) -> bool
+  where F: FnOnce(&mut dyn Handler, &mut ConnectionCtx) {
+    let peer_addr = state.socket_id.1;
+    let key = match self.conns.get(&peer_addr) { Some(served) => served.key, None => return false };
+    let sends = state.handling.as_mut().map(|handling| &mut handling.sends);
+    let mut ctx = ConnectionCtx { key, local_addr: state.local_addr, peer_addr, shared: &state.shared, sends, wrote: false, close: false };
+    f(&mut *self.handler, &mut ctx);
+    let (wrote, close) = (ctx.wrote, ctx.close);
+
+    // As if the app dropped its connection: the daemon stops reading, flushes and tells the peer
+    if close {
+      state.shared.2.set_app_hup();
+      if let Some(served) = self.conns.remove(&peer_addr) { self.disconnected(peer_addr, served, Error::LocalClosed); }
+    }
+    wrote || close
+  }
+
+  fn disconnected(&mut self, peer_addr: SocketAddr, served: Served, reason: Error) {
+    let mut ctx = ConnectionCtx {
+      key: served.key, local_addr: served.local_addr, peer_addr, shared: &served.shared, sends: None, wrote: false, close: false
+    };
+    self.handler.on_disconnect(&mut ctx, reason);
+  }
+}
@@ -0,0 +1,105 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::constants::time_ms;
+
+/// Notable daemon occurrences, reported over the channel from Service::events
+#[derive(Debug)]
+pub enum Event {
+  // The daemon thread is up and polling
+  Started,
+
+  // The system selector failed. The daemon can no longer perform any io and exits
+  PollFailed(io::Error),
+
+  // A socket could not be removed from the selector; its file descriptor may leak
+  DeregisterFailed { local_addr: SocketAddr, error: io::Error },
+
+  // A socket failed with a fatal io error, closing every connection on it
+  SocketError { local_addr: SocketAddr, error: io::Error }
+}
+
+/// Whether the daemon thread is alive
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+  Running,
+  // The daemon exited after Service::shutdown
+  Stopped,
+  // The daemon exited after a fatal io error of this kind
+  Failed(io::ErrorKind),
+  Panicked
+}
+
+// Holds on to the daemon thread, so its exit can be observed from any service clone.
+// A manually stepped daemon has no thread, and reports its own exit instead
+pub struct Daemon {
+  state: Mutex<DaemonState>
+}
+
+enum DaemonState {
+  Stepped,
+  Running(JoinHandle<io::Result<()>>),
+  Exited(Health)
+}
+
+impl Daemon {
+  pub fn stepped() -> Daemon {
+    Daemon { state: Mutex::new(DaemonState::Stepped) }
+  }
+
+  // Hands the daemon over to a thread, whose exit then decides the health
+  pub fn attach(&self, handle: JoinHandle<io::Result<()>>) {
+    *self.lock() = DaemonState::Running(handle);
+  }
+
+  pub fn exited(&self, health: Health) {
+    let mut state = self.lock();
+    if let DaemonState::Stepped = *state { *state = DaemonState::Exited(health); }
+  }
+
+  pub fn is_stepped(&self) -> bool {
+    matches!(*self.lock(), DaemonState::Stepped)
+  }
+
+  // Nothing panics while holding this lock, but even so its state is always valid
+  fn lock(&self) -> MutexGuard<'_, DaemonState> {
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  pub fn health(&self) -> Health {
+    let mut state = self.lock();
+
+    match *state {
+      DaemonState::Stepped => Health::Running,
+      DaemonState::Running(ref handle) if !handle.is_finished() => Health::Running,
+      DaemonState::Exited(health) => health,
+      DaemonState::Running(_) => {
+        let health = match std::mem::replace(&mut *state, DaemonState::Exited(Health::Panicked)) {
+          DaemonState::Running(handle) => match handle.join() {
+            Ok(Ok(())) => Health::Stopped,
+            Ok(Err(e)) => Health::Failed(e.kind()),
+            Err(_) => Health::Panicked
+          },
+          DaemonState::Exited(health) => health,
+          DaemonState::Stepped => Health::Running
+        };
+        *state = DaemonState::Exited(health);
+        health
+      }
+    }
+  }
+
+  // Waits up to the timeout for the daemon thread to exit, returning its health either way.
+  // A stepped daemon only exits as the app steps it, so there is no use waiting on one
+  pub fn join_timeout(&self, timeout: Duration) -> Health {
+    let deadline = Instant::now() + timeout;
+    loop {
+      let health = self.health();
+      if health != Health::Running || Instant::now() >= deadline || self.is_stepped() { return health; }
+      thread::sleep(time_ms::IOTA);
+    }
+  }
+}
@@ -1,14 +1,33 @@
+mod admission;
+mod conditioner;
 mod connection;
 mod constants;
+mod cookie;
 mod daemon;
+pub mod proto;
 mod error;
+mod handler;
+mod health;
+mod metrics;
+mod ratelimit;
 mod warn;
 mod service;
 mod socket;
 mod state;
 mod types;
 mod timer;
+mod transport;
 
-pub use connection::{Connection, Listener};
+pub use connection::{Connection, SendHalf, RecvHalf, Listener, Incoming, Connecting, ConnectionSet, Ready};
 pub use service::{Builder, Service};
+pub use daemon::Daemon;
+pub use metrics::{Metrics, Throttled};
+pub use health::{Event, Health};
+pub use ratelimit::RateLimit;
+pub use conditioner::{Conditions, Loss};
+pub use handler::{Handler, ConnectionCtx};
+pub use admission::{Admission, IpRange};
+pub use error::{Error, Reason};
+pub use transport::{Transport, Readiness, Udp, Inbox, Outbox, sim};
 pub use constants::header::MAGIC_BYTES as PROTOCOL_ID;
+pub use slice_pair::SlicePair;
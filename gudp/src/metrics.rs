@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::SeqCst as OSeqCst;
+
+use crate::constants::THROTTLED_IPS_MAX;
+
+/// Service-wide counters, updated by the daemon and readable from any thread
+#[derive(Debug, Default)]
+pub struct Metrics {
+  // New peers turned away because their listener's accept backlog was full
+  pub(crate) refused_peers: AtomicU64,
+
+  // New peers turned away by connection limits or admission control
+  pub(crate) denied_peers: AtomicU64,
+
+  // Closed sockets held until they can be deregistered from poll
+  pub(crate) quarantined_sockets: AtomicU64,
+
+  // Inbound datagrams dropped for being larger than the max datagram size
+  pub(crate) truncated_packets: AtomicU64,
+
+  // Inbound datagrams dropped by rate limits
+  pub(crate) throttled_packets: AtomicU64,
+  pub(crate) throttled_bytes: AtomicU64,
+
+  // The same, broken down by source ip. Only the first THROTTLED_IPS_MAX ips are tracked
+  throttled_by_ip: Mutex<HashMap<IpAddr, Throttled>>
+}
+
+/// Inbound traffic dropped by rate limits
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Throttled {
+  pub packets: u64,
+  pub bytes: u64
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    Metrics::default()
+  }
+
+  pub fn refused_peers(&self) -> u64 {
+    self.refused_peers.load(OSeqCst)
+  }
+
+  pub fn denied_peers(&self) -> u64 {
+    self.denied_peers.load(OSeqCst)
+  }
+
+  pub fn quarantined_sockets(&self) -> u64 {
+    self.quarantined_sockets.load(OSeqCst)
+  }
+
+  pub fn truncated_packets(&self) -> u64 {
+    self.truncated_packets.load(OSeqCst)
+  }
+
+  pub fn throttled_packets(&self) -> u64 {
+    self.throttled_packets.load(OSeqCst)
+  }
+
+  pub fn throttled_bytes(&self) -> u64 {
+    self.throttled_bytes.load(OSeqCst)
+  }
+
+  // A snapshot of who is being throttled
+  pub fn throttled_by_ip(&self) -> HashMap<IpAddr, Throttled> {
+    self.throttled_by_ip.lock()
+      .map(|by_ip| by_ip.clone())
+      .unwrap_or_default()
+  }
+
+  pub(crate) fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, OSeqCst);
+  }
+
+  pub(crate) fn throttle(&self, ip: IpAddr, size: usize) {
+    Metrics::incr(&self.throttled_packets);
+    self.throttled_bytes.fetch_add(size as u64, OSeqCst);
+
+    if let Ok(mut by_ip) = self.throttled_by_ip.lock() {
+      if by_ip.len() >= THROTTLED_IPS_MAX && !by_ip.contains_key(&ip) { return; }
+      let throttled = by_ip.entry(ip).or_default();
+      throttled.packets += 1;
+      throttled.bytes += size as u64;
+    }
+  }
+}
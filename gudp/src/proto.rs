@@ -0,0 +1,294 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::slice::SliceIndex;
+use std::time::Instant;
+
+use crossbeam::channel;
+use mio::Token;
+
+use crate::admission;
+use crate::constants::header;
+use crate::cookie;
+use crate::error::Reason;
+use crate::service::{self, Conf};
+use crate::socket::{self, ConnOpts};
+use crate::state::{self, State, Deps};
+use crate::timer::{self, Timers, TimerKind};
+use crate::transport::Output;
+use crate::types::FromDaemon;
+use crate::Connection;
+
+// An endpoint is a single socket, so all its connections share one token
+const TOKEN: Token = Token(1);
+
+/// The gudp protocol over one local socket, with no io, threads or clock of its own (sans-IO).
+/// The app does the io: it feeds the endpoint each datagram the socket receives along with the current time,
+/// sends every datagram poll_transmit hands back, and calls handle_timeout once poll_timeout comes due.
+///
+/// Connections handed out by poll_event are ordinary gudp Connections, but nothing happens on them between
+/// calls to the endpoint, so only their nonblocking calls make sense. Each connection's own rate limit
+/// (conn_rate_limit) applies as usual, but the per-ip limits and the link conditioner are daemon features, and don't apply here.
+pub struct Endpoint {
+  local_addr: SocketAddr,
+  core: Core,
+  peers: HashMap<SocketAddr, State>,
+  listen: Option<ConnOpts>,
+  rx_events: channel::Receiver<FromDaemon>,
+  rx_write: channel::Receiver<socket::Id>,
+  outbox: Outbox,
+  expired: Vec<(socket::Id, TimerKind)>
+}
+
+/// A datagram for the app to send from the endpoint's socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transmit {
+  pub to: SocketAddr,
+  pub datagram: Vec<u8>
+}
+
+pub enum Event {
+  // The connection is established, or a new peer was accepted
+  Connected(Connection),
+  // The peer turned us away before the connection was established
+  Refused(Reason)
+}
+
+// Everything the connection state machines need, minus the io
+struct Core {
+  conf: Conf,
+  now: Instant,
+  buf: Vec<u8>,
+  timers: timer::Wheel<(socket::Id, TimerKind)>,
+  cookies: cookie::Jar,
+  tx_write: channel::Sender<socket::Id>
+}
+
+// Collects sent datagrams until the app polls for them
+#[derive(Default)]
+struct Outbox(RefCell<VecDeque<Transmit>>);
+
+impl Output for Outbox {
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    self.0.borrow_mut().push_back(Transmit { to: addr, datagram: buf.to_vec() });
+    Ok(buf.len())
+  }
+}
+
+impl Endpoint {
+  fn new(conf: Conf, local_addr: SocketAddr, rx_events: channel::Receiver<FromDaemon>, now: Instant) -> io::Result<Endpoint> {
+    service::check_conf(&conf)?;
+    let (tx_write, rx_write) = channel::unbounded();
+    let buf = vec![0u8; conf.max_datagram_size];
+    let core = Core { conf, now, buf, timers: timer::Wheel::new(now), cookies: cookie::Jar::new(now)?, tx_write };
+
+    Ok(Endpoint {
+      local_addr,
+      core,
+      peers: HashMap::new(),
+      listen: None,
+      rx_events,
+      rx_write,
+      outbox: Outbox::default(),
+      expired: vec![]
+    })
+  }
+
+  pub(crate) fn connect(conf: Conf, local_addr: SocketAddr, peer_addr: SocketAddr, hello: &[u8], now: Instant) -> io::Result<Endpoint> {
+    service::check_hello(conf.max_datagram_size, hello)?;
+    let (tx, rx_events) = channel::bounded(2);
+    let mut endpoint = Endpoint::new(conf, local_addr, rx_events, now)?;
+
+    let conn_opts = ConnOpts::new(TOKEN, tx, endpoint.core.tx_write.clone(), None);
+    let state = State::init(local_addr, (TOKEN, peer_addr), conn_opts, hello.to_vec(), &mut endpoint.core);
+    endpoint.peers.insert(peer_addr, state);
+    Ok(endpoint)
+  }
+
+  pub(crate) fn listen(conf: Conf, local_addr: SocketAddr, now: Instant) -> io::Result<Endpoint> {
+    // New peers wait here until the app polls for them. Once full, further peers are refused
+    let (tx, rx_events) = channel::bounded(usize::max(conf.accept_backlog, 1));
+    let mut endpoint = Endpoint::new(conf, local_addr, rx_events, now)?;
+    endpoint.listen = Some(ConnOpts::new(TOKEN, tx, endpoint.core.tx_write.clone(), None));
+    Ok(endpoint)
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  // Whether every connection is finished. A listening endpoint carries on accepting regardless
+  pub fn is_idle(&self) -> bool {
+    self.peers.is_empty()
+  }
+
+  // Handles a datagram the socket received
+  pub fn handle_datagram(&mut self, from: SocketAddr, datagram: &[u8], now: Instant) {
+    self.core.now = now;
+    let mut size = datagram.len();
+    if size > self.core.buf.len() { return; }
+    self.core.buf[..size].copy_from_slice(datagram);
+
+    // Filter out non-conforming protocol bits as socket noise
+    let is_control = state::is_control(datagram);
+    if !is_control && (size < header::SIZE_BYTES || datagram[header::MAGIC_BYTES_RANGE] != header::MAGIC_BYTES) { return; }
+
+    if let Some(state) = self.peers.get_mut(&from) {
+      if state.take_inbound(now, size) && !state.read(self.local_addr, from, size, &mut self.core) {
+        state.cancel_timers(&mut self.core);
+        self.peers.remove(&from);
+      }
+      return;
+    }
+
+    // New peers must prove they own their address before anything else
+    let conn_opts = match self.listen { Some(ref conn_opts) => conn_opts, None => return };
+    let socket_id = (TOKEN, from);
+    if !state::verify_hello(&self.outbox, socket_id, &mut size, &mut self.core) { return; }
+
+    if let state::Admitted::Connection(state) = state::admit(&self.outbox, self.local_addr, socket_id, conn_opts, self.peers.len(), size, &mut self.core) {
+      self.peers.insert(from, state);
+    }
+  }
+
+  // When handle_timeout should next be called, if ever
+  pub fn poll_timeout(&self) -> Option<Instant> {
+    self.core.timers.when_next()
+  }
+
+  // Handles every timer due by now, such as heartbeats and peers timing out
+  pub fn handle_timeout(&mut self, now: Instant) {
+    self.core.now = now;
+    self.expired.extend(self.core.timers.expire(now));
+    for ((_, peer_addr), kind) in self.expired.drain(..) {
+      if let Some(state) = self.peers.get_mut(&peer_addr) {
+        if !state.timer(kind, &mut self.core) { state.cancel_timers(&mut self.core); self.peers.remove(&peer_addr); }
+      }
+    }
+  }
+
+  // The next datagram to send, if any. Flushes whatever the app has written since the last call
+  pub fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+    self.core.now = now;
+    for (_, peer_addr) in self.rx_write.try_iter() {
+      if let Some(state) = self.peers.get_mut(&peer_addr) {
+        // The outbox never fails, so an error is as terminal as a finished connection
+        if !state.write(&self.outbox, peer_addr, &mut self.core).unwrap_or(false) { state.cancel_timers(&mut self.core); self.peers.remove(&peer_addr); }
+      }
+    }
+    self.outbox.0.borrow_mut().pop_front()
+  }
+
+  // The next connection established or refused, if any
+  pub fn poll_event(&mut self) -> Option<Event> {
+    loop {
+      match self.rx_events.try_recv().ok()? {
+        FromDaemon::Connection(on_write, shared, id) => return Some(Event::Connected(Connection::new(on_write, shared, id))),
+        FromDaemon::Refused(reason) => return Some(Event::Refused(reason)),
+        FromDaemon::Listener(..) => { }
+      }
+    }
+  }
+}
+
+impl Deps for Core {
+  fn timers(&mut self) -> &mut timer::Wheel<(socket::Id, TimerKind)> {
+    &mut self.timers
+  }
+
+  fn now(&mut self) -> Instant {
+    self.now
+  }
+
+  fn buffer<I: SliceIndex<[u8], Output = [u8]>>(&self, index: I) -> &[u8] {
+    &self.buf[index]
+  }
+
+  fn buffer_mut<I: SliceIndex<[u8], Output = [u8]>>(&mut self, index: I) -> &mut [u8] {
+    &mut self.buf[index]
+  }
+
+  fn send_to<O: Output>(&mut self, io: &O, socket_id: socket::Id, size: usize) -> io::Result<usize> {
+    io.send_to(&self.buf[..size], socket_id.1)
+  }
+
+  fn on_packet_sent<I>(&mut self, addr_pair: (SocketAddr, SocketAddr), index: I, sequence_no: u32)
+  where I: SliceIndex<[u8], Output = [u8]> {
+    let buf = &self.buf[index];
+    if let Some(f) = self.conf.on_packet_sent.as_mut() { f(addr_pair, buf, sequence_no); }
+  }
+
+  fn on_packet_acked(&mut self, addr_pair: (SocketAddr, SocketAddr), sequence_no: u32) {
+    if let Some(f) = self.conf.on_packet_acked.as_mut() { f(addr_pair, sequence_no); }
+  }
+
+  // The receiver lives as long as the endpoint, and the channel is unbounded
+  fn notify_write(&self, socket_id: socket::Id) {
+    self.tx_write.send(socket_id).ok();
+  }
+
+  fn conf(&self) -> &Conf {
+    &self.conf
+  }
+
+  fn cookies(&self) -> &cookie::Jar {
+    &self.cookies
+  }
+
+  fn admit(&mut self, peer_addr: SocketAddr, n_peers: usize, size: usize) -> Result<(), admission::Refusal> {
+    admission::check(&mut self.conf, peer_addr, n_peers, &self.buf[header::SIZE_BYTES..size])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  // Delivers everything each endpoint has to send to the other, until neither has anything left
+  fn exchange(a: &mut Endpoint, b: &mut Endpoint, now: Instant) {
+    loop {
+      let mut quiet = true;
+      while let Some(transmit) = a.poll_transmit(now) {
+        if transmit.to == b.local_addr() { b.handle_datagram(a.local_addr(), &transmit.datagram, now); }
+        quiet = false;
+      }
+      while let Some(transmit) = b.poll_transmit(now) {
+        if transmit.to == a.local_addr() { a.handle_datagram(b.local_addr(), &transmit.datagram, now); }
+        quiet = false;
+      }
+      if quiet { return; }
+    }
+  }
+
+  #[test]
+  fn connects_and_exchanges_without_io() {
+    let now = Instant::now();
+    let client_addr: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+    let server_addr: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+    let mut server = Endpoint::listen(Conf::default(), server_addr, now).unwrap();
+    let mut client = Endpoint::connect(Conf::default(), client_addr, server_addr, b"join", now).unwrap();
+
+    exchange(&mut client, &mut server, now);
+    let accepted = match server.poll_event() { Some(Event::Connected(conn)) => conn, _ => panic!("Nothing accepted") };
+    let connected = match client.poll_event() { Some(Event::Connected(conn)) => conn, _ => panic!("Never connected") };
+    assert_eq!(accepted.peer_addr(), client_addr);
+
+    let mut buf = [0u8; 64];
+    let size = accepted.try_recv(&mut buf).expect("No join token").unwrap();
+    assert_eq!(&buf[..size], b"join");
+
+    connected.send(b"hello").unwrap();
+    exchange(&mut client, &mut server, now);
+    let size = accepted.try_recv(&mut buf).expect("Nothing received").unwrap();
+    assert_eq!(&buf[..size], b"hello");
+
+    // Time only passes when the app says so; the client hears nothing more and times out
+    let later = now + Duration::from_secs(20);
+    assert!(client.poll_timeout().map(|when| when <= later).unwrap_or(false));
+    client.handle_timeout(later);
+    assert!(client.is_idle());
+    assert_eq!(connected.close_reason(), Some(crate::Error::PeerTimedOut));
+  }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::constants::RATE_LIMIT_IPS_MIN;
+
+/// A sustained rate of inbound traffic, in packets and bytes per second.
+/// Bursts of up to one second's worth of either are let through
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+  pub packets_per_sec: u32,
+  pub bytes_per_sec: u32
+}
+
+impl RateLimit {
+  pub fn new(packets_per_sec: u32, bytes_per_sec: u32) -> RateLimit {
+    RateLimit { packets_per_sec, bytes_per_sec }
+  }
+}
+
+// A token bucket, refilled continuously at `rate` tokens per second up to `rate` tokens
+pub struct Bucket {
+  rate: f64,
+  tokens: f64,
+  last: Instant
+}
+
+impl Bucket {
+  pub fn new(rate: u32, now: Instant) -> Bucket {
+    Bucket { rate: rate as f64, tokens: rate as f64, last: now }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.tokens = f64::min(self.rate, self.tokens + elapsed * self.rate);
+    self.last = now;
+  }
+
+  fn has(&self, amount: f64) -> bool {
+    self.tokens >= amount
+  }
+
+  pub fn take(&mut self, now: Instant, amount: f64) -> bool {
+    self.refill(now);
+    if !self.has(amount) { return false; }
+    self.tokens -= amount;
+    true
+  }
+
+  // A full bucket behaves exactly like a fresh one, so it can be forgotten
+  fn is_full(&mut self, now: Instant) -> bool {
+    self.refill(now);
+    self.tokens >= self.rate
+  }
+}
+
+// Limits both the packets and bytes of a single source
+pub struct Buckets {
+  packets: Bucket,
+  bytes: Bucket
+}
+
+impl Buckets {
+  pub fn new(limit: RateLimit, now: Instant) -> Buckets {
+    Buckets {
+      packets: Bucket::new(limit.packets_per_sec, now),
+      bytes: Bucket::new(limit.bytes_per_sec, now)
+    }
+  }
+
+  // Returns false if the datagram is over the limit, in which case nothing is taken
+  pub fn take(&mut self, now: Instant, size: usize) -> bool {
+    self.packets.refill(now);
+    self.bytes.refill(now);
+    if !self.packets.has(1.0) || !self.bytes.has(size as f64) { return false; }
+    self.packets.take(now, 1.0) && self.bytes.take(now, size as f64)
+  }
+
+  fn is_full(&mut self, now: Instant) -> bool {
+    self.packets.is_full(now) && self.bytes.is_full(now)
+  }
+}
+
+// Per source ip limits on inbound datagrams and new connections.
+// Idle sources are forgotten whenever the tables double in size, so spoofed sources can't grow them forever
+pub struct Limiter {
+  packets: HashMap<IpAddr, Buckets>,
+  connects: HashMap<IpAddr, Bucket>,
+  prune_at: usize
+}
+
+impl Limiter {
+  pub fn new() -> Limiter {
+    Limiter { packets: HashMap::new(), connects: HashMap::new(), prune_at: RATE_LIMIT_IPS_MIN }
+  }
+
+  pub fn take_packet(&mut self, limit: RateLimit, ip: IpAddr, now: Instant, size: usize) -> bool {
+    self.prune(now);
+    self.packets.entry(ip)
+      .or_insert_with(|| Buckets::new(limit, now))
+      .take(now, size)
+  }
+
+  pub fn take_connect(&mut self, per_sec: u32, ip: IpAddr, now: Instant) -> bool {
+    self.prune(now);
+    self.connects.entry(ip)
+      .or_insert_with(|| Bucket::new(per_sec, now))
+      .take(now, 1.0)
+  }
+
+  fn prune(&mut self, now: Instant) {
+    if self.packets.len() + self.connects.len() < self.prune_at { return; }
+    self.packets.retain(|_, buckets| !buckets.is_full(now));
+    self.connects.retain(|_, bucket| !bucket.is_full(now));
+    self.prune_at = usize::max(RATE_LIMIT_IPS_MIN, 2 * (self.packets.len() + self.connects.len()));
+  }
+}
+
+impl Default for Limiter {
+  fn default() -> Limiter {
+    Limiter::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+  use super::{Buckets, RateLimit};
+
+  #[test]
+  fn packets_over_limit() {
+    let now = Instant::now();
+    let mut buckets = Buckets::new(RateLimit::new(2, 1000), now);
+    assert!(buckets.take(now, 10));
+    assert!(buckets.take(now, 10));
+    assert!(!buckets.take(now, 10));
+
+    // Half a second refills one packet
+    let later = now + Duration::from_millis(500);
+    assert!(buckets.take(later, 10));
+    assert!(!buckets.take(later, 10));
+  }
+
+  #[test]
+  fn bytes_over_limit() {
+    let now = Instant::now();
+    let mut buckets = Buckets::new(RateLimit::new(10, 100), now);
+    assert!(buckets.take(now, 100));
+    assert!(!buckets.take(now, 1));
+
+    // Half a second refills 50 bytes. Refused datagrams take nothing
+    let later = now + Duration::from_millis(500);
+    assert!(!buckets.take(later, 60));
+    assert!(buckets.take(later, 50));
+  }
+}
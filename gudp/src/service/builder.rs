@@ -1,7 +1,16 @@
 use std::io;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use clock::Clock;
+use clock::{Clock, sys};
+
+use crate::admission::{Admission, IpRange};
+use crate::ratelimit::RateLimit;
+use crate::conditioner::Conditions;
+
+use crate::transport::Transport;
+use crate::daemon::Daemon;
+use crate::proto::Endpoint;
 
 use super::{Conf, Service};
 
@@ -19,6 +28,101 @@ macro_rules! impl_builder {
       self
     }
 
+    // Refuse new peers once this many connections are waiting on accept(), rather than stall the daemon
+    pub fn accept_backlog(mut self, backlog: usize) -> $builder {
+      self.conf.accept_backlog = backlog;
+      self
+    }
+
+    pub fn max_datagram_size(mut self, size: usize) -> $builder {
+      self.conf.max_datagram_size = size;
+      self
+    }
+
+    // Receive and send up to this many datagrams per syscall, where the platform allows. One turns batching off
+    pub fn batch_size(mut self, size: usize) -> $builder {
+      self.conf.batch_size = size;
+      self
+    }
+
+    // Let the kernel segment and coalesce bursts of datagrams, on Linux. Pays off when max_datagram_size fits the path MTU;
+    // sends fall back to plain batches if the device can't segment them
+    pub fn udp_offload(mut self, offload: bool) -> $builder {
+      self.conf.udp_offload = offload;
+      self
+    }
+
+    pub fn max_peers(mut self, max_peers: usize) -> $builder {
+      self.conf.max_peers = Some(max_peers);
+      self
+    }
+
+    pub fn admit(mut self, f: Box<dyn FnMut(SocketAddr, &[u8]) -> Admission + Send>) -> $builder {
+      self.conf.admit = Some(f);
+      self
+    }
+
+    pub fn allow(mut self, range: IpRange) -> $builder {
+      self.conf.allow.push(range);
+      self
+    }
+
+    pub fn deny(mut self, range: IpRange) -> $builder {
+      self.conf.deny.push(range);
+      self
+    }
+
+    // Drop inbound datagrams beyond this rate from any one source ip
+    pub fn ip_rate_limit(mut self, packets_per_sec: u32, bytes_per_sec: u32) -> $builder {
+      self.conf.ip_rate_limit = Some(RateLimit::new(packets_per_sec, bytes_per_sec));
+      self
+    }
+
+    // Drop inbound datagrams beyond this rate on any one connection
+    pub fn conn_rate_limit(mut self, packets_per_sec: u32, bytes_per_sec: u32) -> $builder {
+      self.conf.conn_rate_limit = Some(RateLimit::new(packets_per_sec, bytes_per_sec));
+      self
+    }
+
+    // Limit how many new connections per second any one source ip may open, however many ports it uses
+    pub fn ip_connect_rate(mut self, connects_per_sec: u32) -> $builder {
+      self.conf.ip_connect_rate = Some(connects_per_sec);
+      self
+    }
+
+    // Resend the initial packet after `retry` if the peer hasn't replied yet,
+    // multiplying the delay by `backoff` each time up to at most `retry_max`.
+    // Building fails with InvalidInput unless backoff is at least 1 and retry is no more than retry_max
+    pub fn handshake_retry(mut self, retry: Duration, backoff: u32, retry_max: Duration) -> $builder {
+      self.conf.handshake_retry = retry;
+      self.conf.handshake_backoff = backoff;
+      self.conf.handshake_retry_max = retry_max;
+      self
+    }
+
+    // How often a Handler registered with Service::listen_with_handler ticks each of its connections
+    pub fn handler_tick(mut self, tick: Duration) -> $builder {
+      self.conf.handler_tick = tick;
+      self
+    }
+
+    // Impose delay, loss and so on upon received datagrams, as if they crossed a bad link
+    pub fn inbound_conditions(mut self, conditions: Conditions) -> $builder {
+      self.conf.inbound_conditions = Some(conditions);
+      self
+    }
+
+    // Impose delay, loss and so on upon sent datagrams, as if they crossed a bad link
+    pub fn outbound_conditions(mut self, conditions: Conditions) -> $builder {
+      self.conf.outbound_conditions = Some(conditions);
+      self
+    }
+
+    pub fn conditioner_seed(mut self, seed: u64) -> $builder {
+      self.conf.conditioner_seed = Some(seed);
+      self
+    }
+
     pub fn on_packet_sent(mut self, f: Box<dyn FnMut((SocketAddr, SocketAddr), &[u8], u32) + Send>) -> $builder {
       self.conf.on_packet_sent = Some(f);
       self
@@ -54,6 +158,30 @@ impl Builder {
   pub fn build(self) -> io::Result<Service> {
     Service::initialize(self.conf)
   }
+
+  pub fn build_with_transport<T: Transport>(self) -> io::Result<Service<T>> {
+    Service::initialize_with_transport(self.conf, sys::Clock())
+  }
+
+  // Builds a service whose daemon the app steps itself, rather than running on its own thread
+  pub fn build_manual(self) -> io::Result<(Service, Daemon)> {
+    Service::initialize_manual(self.conf, sys::Clock())
+  }
+
+  pub fn build_manual_with_transport<T: Transport>(self) -> io::Result<(Service<T>, Daemon<sys::Clock, T>)> {
+    Service::initialize_manual(self.conf, sys::Clock())
+  }
+
+  // A sans-IO endpoint connecting from local_addr to peer_addr, whose first packet carries the token (which may be empty).
+  // The app does the io and keeps the time; see proto::Endpoint
+  pub fn connect_endpoint(self, local_addr: SocketAddr, peer_addr: SocketAddr, token: &[u8], now: Instant) -> io::Result<Endpoint> {
+    Endpoint::connect(self.conf, local_addr, peer_addr, token, now)
+  }
+
+  // A sans-IO endpoint accepting peers on local_addr
+  pub fn listen_endpoint(self, local_addr: SocketAddr, now: Instant) -> io::Result<Endpoint> {
+    Endpoint::listen(self.conf, local_addr, now)
+  }
 }
 
 // Custom clock case
@@ -70,5 +198,19 @@ impl <C: 'static + Clock + Send> ClockedBuilder<C> {
   pub fn build(self) -> io::Result<Service> {
     Service::initialize_with_clock(self.conf, self.clock)
   }
+
+  // Builds a service over another transport, such as sim::Socket
+  pub fn build_with_transport<T: Transport>(self) -> io::Result<Service<T>> {
+    Service::initialize_with_transport(self.conf, self.clock)
+  }
+
+  // Builds a service whose daemon the app steps itself, such as a test stepping it in lockstep with a mock clock
+  pub fn build_manual(self) -> io::Result<(Service, Daemon<C>)> {
+    Service::initialize_manual(self.conf, self.clock)
+  }
+
+  pub fn build_manual_with_transport<T: Transport>(self) -> io::Result<(Service<T>, Daemon<C, T>)> {
+    Service::initialize_manual(self.conf, self.clock)
+  }
 }
 
@@ -1,10 +1,71 @@
 use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::constants::{time_ms, ACCEPT_BACKLOG, BATCH_SIZE, MAX_DATAGRAM_SIZE_BYTES};
+use crate::admission::{Admission, IpRange};
+use crate::ratelimit::RateLimit;
+use crate::conditioner::Conditions;
 
-#[derive(Default)]
 pub struct Conf {
   pub example: usize,
 
-  // Called when the packet is sent over the wire, with its sequence number
+  // How many new connections a listener may hold awaiting accept() before refusing further peers
+  pub accept_backlog: usize,
+
+  // Largest datagram sent or received, headers included. Larger sends are refused and larger receives are dropped.
+  // Both ends should agree on this
+  pub max_datagram_size: usize,
+
+  // Most datagrams the daemon receives or sends in one syscall, where the transport batches them (recvmmsg and sendmmsg on Linux)
+  pub batch_size: usize,
+
+  // Have the kernel split large sends to one peer into datagrams (UDP_SEGMENT), and coalesce bursts it receives (UDP_GRO).
+  // Linux only, and ignored under the link conditioner
+  pub udp_offload: bool,
+
+  // How many peers a listener may have at once. Further peers are refused as if the server were full
+  pub max_peers: Option<usize>,
+
+  // Decides whether to admit a new peer, given its address and the payload of its first packet (such as a join token)
+  pub admit: Option<Box<dyn FnMut(SocketAddr, &[u8]) -> Admission + Send>>,
+
+  // If non-empty, only peers within these ranges may connect to a listener
+  pub allow: Vec<IpRange>,
+
+  // Peers within these ranges are silently ignored by listeners. Takes precedence over allow
+  pub deny: Vec<IpRange>,
+
+  // Inbound datagrams beyond this rate from any one ip are dropped
+  pub ip_rate_limit: Option<RateLimit>,
+
+  // Inbound datagrams beyond this rate on any one connection are dropped
+  pub conn_rate_limit: Option<RateLimit>,
+
+  // How many new connections per second any one ip may open to a listener
+  pub ip_connect_rate: Option<u32>,
+
+  // Delay before resending the initial packet while awaiting the peer's first reply.
+  // A zero delay disables resending; the initial packet then only repeats with heartbeats.
+  pub handshake_retry: Duration,
+
+  // Factor the handshake retry delay is multiplied by after each resend
+  pub handshake_backoff: u32,
+
+  // Upper bound on the handshake retry delay
+  pub handshake_retry_max: Duration,
+
+  // Simulated network conditions for datagrams the daemon receives and sends. For testing only
+  pub inbound_conditions: Option<Conditions>,
+  pub outbound_conditions: Option<Conditions>,
+
+  // Seeds the link conditioner's randomness, so a run can be repeated exactly. Random if unset
+  pub conditioner_seed: Option<u64>,
+
+  // How often a listener's Handler has on_tick called for each of its connections
+  pub handler_tick: Duration,
+
+  // Called when the packet is sent, with its sequence number. The daemon calls it once the packet is queued in its
+  // socket's outbox, which may be before the transport takes it, or never if the socket fails first
   pub on_packet_sent: Option<Box<dyn FnMut((SocketAddr, SocketAddr), &[u8], u32) + Send>>,
 
   // Called when the given sequence number is acked
@@ -13,3 +74,32 @@ pub struct Conf {
   // Called when the given sequence number is lost (never acked and too old)
   pub on_packet_lost: Option<Box<dyn FnMut((SocketAddr, SocketAddr), u32) + Send>>
 }
+
+impl Default for Conf {
+  fn default() -> Conf {
+    Conf {
+      example: 0,
+      accept_backlog: ACCEPT_BACKLOG,
+      max_datagram_size: MAX_DATAGRAM_SIZE_BYTES,
+      batch_size: BATCH_SIZE,
+      udp_offload: false,
+      max_peers: None,
+      admit: None,
+      allow: vec![],
+      deny: vec![],
+      ip_rate_limit: None,
+      conn_rate_limit: None,
+      ip_connect_rate: None,
+      handshake_retry: time_ms::HANDSHAKE_RETRY,
+      handshake_backoff: 2,
+      handshake_retry_max: time_ms::HEARTBEAT,
+      inbound_conditions: None,
+      outbound_conditions: None,
+      conditioner_seed: None,
+      handler_tick: time_ms::HANDLER_TICK,
+      on_packet_sent: None,
+      on_packet_acked: None,
+      on_packet_lost: None
+    }
+  }
+}
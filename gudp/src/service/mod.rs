@@ -1,6 +1,7 @@
-use std::net::{UdpSocket, ToSocketAddrs};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::io;
+use std::time::Duration;
 
 use mio::{Poll, Waker};
 use crossbeam::channel;
@@ -9,11 +10,15 @@ use clock::{Clock, sys};
 
 use log::warn;
 use crate::types::{FromDaemon, ToDaemon};
-use crate::constants::WAKE_TOKEN;
+use crate::constants::{time_ms, header, control, WAKE_TOKEN, EVENTS_BACKLOG};
 use crate::daemon;
 use crate::Connection;
 use crate::Listener;
+use crate::handler::Handler;
 use crate::error;
+use crate::metrics::Metrics;
+use crate::health::{self, Event, Health};
+use crate::transport::{self, Transport, Udp};
 
 mod builder;
 mod conf;
@@ -21,66 +26,127 @@ mod conf;
 pub use builder::Builder;
 pub use conf::Conf;
 
-#[derive(Clone)]
-pub struct Service {
+// Talks over real UDP sockets unless built with another transport, such as the sim network
+pub struct Service<T: Transport = Udp> {
   waker: Arc<Waker>,
-  to_daemon_tx: channel::Sender<ToDaemon>
+  to_daemon_tx: channel::Sender<ToDaemon<T>>,
+  metrics: Arc<Metrics>,
+  rx_events: channel::Receiver<Event>,
+  daemon: Arc<health::Daemon>,
+  accept_backlog: usize,
+  max_datagram_size: usize
+}
+
+impl<T: Transport> Clone for Service<T> {
+  fn clone(&self) -> Service<T> {
+    Service {
+      waker: Arc::clone(&self.waker),
+      to_daemon_tx: self.to_daemon_tx.clone(),
+      metrics: Arc::clone(&self.metrics),
+      rx_events: self.rx_events.clone(),
+      daemon: Arc::clone(&self.daemon),
+      accept_backlog: self.accept_backlog,
+      max_datagram_size: self.max_datagram_size
+    }
+  }
 }
 
 impl Service {
   // Starts the service, spawning the daemon thread and providing access to connections
   #[inline]
   pub fn initialize_with_clock<C: 'static + Clock + Send>(conf: Conf, clock: C) -> io::Result<Service> {
+    Service::initialize_with_transport(conf, clock)
+  }
+
+  pub fn initialize(conf: Conf) -> io::Result<Service> {
+    Self::initialize_with_clock(conf, sys::Clock())
+  }
+}
+
+impl<T: Transport> Service<T> {
+  // Like initialize_with_clock, but over the given transport
+  pub fn initialize_with_transport<C: 'static + Clock + Send>(conf: Conf, clock: C) -> io::Result<Service<T>> {
+    let (service, daemon) = Self::initialize_manual(conf, clock)?;
+    service.daemon.attach(daemon::spawn(daemon)?);
+    Ok(service)
+  }
+
+  // Starts the service without a daemon thread. The app steps the returned daemon itself, and nothing happens in between
+  pub fn initialize_manual<C: 'static + Clock + Send>(conf: Conf, clock: C) -> io::Result<(Service<T>, daemon::Daemon<C, T>)> {
+    check_conf(&conf)?;
     let (tx, other_rx) = channel::unbounded(); // Service -> Daemon
     let poll = Poll::new()?;
     let waker = Waker::new(poll.registry(), WAKE_TOKEN)?;
     let waker = Arc::new(waker);
-    daemon::spawn(poll, Arc::clone(&waker), other_rx, conf, clock)?;
+    let metrics = Arc::new(Metrics::new());
+    let accept_backlog = conf.accept_backlog;
+    let max_datagram_size = conf.max_datagram_size;
+    let (tx_events, rx_events) = channel::bounded(EVENTS_BACKLOG);
+    let daemon = Arc::new(health::Daemon::stepped());
+    let stepped = daemon::Daemon::new(poll, Arc::clone(&waker), other_rx, conf, Arc::clone(&metrics), tx_events, clock, Arc::clone(&daemon))?;
 
-    Ok(Service { waker, to_daemon_tx: tx })
+    Ok((Service { waker, to_daemon_tx: tx, metrics, rx_events, daemon, accept_backlog, max_datagram_size }, stepped))
   }
 
-  pub fn initialize(conf: Conf) -> io::Result<Service> {
-    Self::initialize_with_clock(conf, sys::Clock())
+  pub fn connect<A: ToSocketAddrs>(&self, socket: T::Socket, to_addr: A) -> io::Result<Connection> {
+    self.connect_until(socket, to_addr, &[], None)
+  }
+
+  // Like connect_timeout, but the first packet to the peer carries a payload such as a join token.
+  // A listener's admission callback sees this payload, and once admitted the peer receives it as its first message
+  pub fn connect_with_token<A: ToSocketAddrs>(&self, socket: T::Socket, to_addr: A, token: &[u8], timeout: Duration) -> io::Result<Connection> {
+    self.connect_until(socket, to_addr, token, Some(timeout))
+  }
+
+  // Like connect, but gives up with a TimedOut error if the peer hasn't replied within the timeout.
+  // How often the initial packet is resent while waiting is configured with Builder::handshake_retry
+  pub fn connect_timeout<A: ToSocketAddrs>(&self, socket: T::Socket, to_addr: A, timeout: Duration) -> io::Result<Connection> {
+    self.connect_until(socket, to_addr, &[], Some(timeout))
   }
 
-  pub fn connect<A: ToSocketAddrs>(&self, socket: UdpSocket, to_addr: A) -> io::Result<Connection> {
-    let peer_addr = to_addr.to_socket_addrs().and_then(|mut addr| {
-      addr.next()
-        .map(Ok)
-        .unwrap_or_else(|| Err(error::socket_addr_failed_to_resolve()))
-    })?;
+  fn connect_until<A: ToSocketAddrs>(&self, socket: T::Socket, to_addr: A, hello: &[u8], timeout: Option<Duration>) -> io::Result<Connection> {
+    // Waiting on a stepped daemon here would hang, since nothing steps it in the meantime
+    if self.daemon.is_stepped() { return Err(error::daemon_is_stepped()); }
+    check_hello(self.max_datagram_size, hello)?;
+    let peer_addr = resolve(to_addr)?;
 
     let (tx, rx) = channel::bounded(2);
     let (tx_to_daemon, waker) = self.clone_parts();
-    tx_to_daemon.send(ToDaemon::Connect(socket, tx, peer_addr))
+    tx_to_daemon.send(ToDaemon::Connect(socket, tx, peer_addr, hello.to_vec()))
       .map_err(error::cannot_send_to_daemon)?;
 
     // Force daemon to handle this new connection immediately
     waker.wake().map_err(error::wake_failed)?;
 
-    // Close any spurious listeners
-    rx.recv()
-      .map_err(error::cannot_recv_from_daemon)
-      .and_then(|received| match received {
-        FromDaemon::Connection(on_write, shared, id) => Ok(Connection::new(on_write, shared, id)),
-
-        // This is unexpected. We only wanted a Connection message.
-        // Close the given listener and signal the issue;
-        FromDaemon::Listener(on_close) => {
-          warn!("When trying to register directly connected socket, received Listener instead");
-          let listener = Listener::new(on_close, rx);
-          drop(listener);
-          Err(error::unexpected_recv_from_daemon())
-        }
-      })
+    // NOTE: On timeout we simply drop rx. The daemon fails to hand over the connection
+    // once the peer finally replies, or times out the peer if it never does, and cleans up either way.
+    let received = match timeout {
+      None => rx.recv().map_err(error::cannot_recv_from_daemon),
+      Some(timeout) => rx.recv_timeout(timeout).map_err(error::cannot_recv_from_daemon_timeout)
+    };
+
+    received.and_then(|received| into_connection(received, rx))
+  }
+
+  pub fn listen(&self, socket: T::Socket) -> io::Result<Listener> {
+    self.listen_with(socket, None)
+  }
+
+  // Serves every connection with the handler on the daemon thread, instead of handing them to the app.
+  // The listener returned never accepts; it only stops listening once closed or dropped
+  pub fn listen_with_handler<H: 'static + Handler>(&self, socket: T::Socket, handler: H) -> io::Result<Listener> {
+    self.listen_with(socket, Some(Box::new(handler)))
   }
 
-  pub fn listen(&self, socket: UdpSocket) -> io::Result<Listener> {
-    let (tx, rx_from_daemon) = channel::bounded(2);
+  fn listen_with(&self, socket: T::Socket, handler: Option<Box<dyn Handler>>) -> io::Result<Listener> {
+    if self.daemon.is_stepped() { return Err(error::daemon_is_stepped()); }
+
+    // The daemon never blocks on this channel once listening. When it is full, new peers are refused.
+    // NOTE: The backlog needs at least one slot to carry the initial Listener reply
+    let (tx, rx_from_daemon) = channel::bounded(usize::max(self.accept_backlog, 1));
     let (tx_to_daemon, waker) = self.clone_parts();
 
-    tx_to_daemon.send(ToDaemon::Listen(socket, tx))
+    tx_to_daemon.send(ToDaemon::Listen(socket, tx, handler))
       .map_err(error::cannot_send_to_daemon)?;
 
       waker.wake()?; // Force daemon to handle this new connection immediately
@@ -88,8 +154,8 @@ impl Service {
       match rx_from_daemon.recv() {
         // The expected case. Once the io has been confirmed, we can return a listener
         // which can accept() incoming connections.
-        Ok(FromDaemon::Listener(on_close)) => {
-          Ok(Listener::new(on_close, rx_from_daemon))
+        Ok(FromDaemon::Listener(on_close, signal)) => {
+          Ok(Listener::new(on_close, signal, rx_from_daemon))
         },
 
         // This is unexpected. We only wanted a listener.
@@ -101,19 +167,98 @@ impl Service {
           Err(error::unexpected_recv_from_daemon())
         },
 
+        Ok(FromDaemon::Refused(_)) => Err(error::unexpected_recv_from_daemon()),
+
         // A closed rx means the daemon cannot register our io for some reason
         Err(_) => Err(error::cannot_register_with_daemon())
       }
   }
 
+  pub fn metrics(&self) -> &Metrics {
+    &self.metrics
+  }
+
+  // Whether the daemon thread is still running, and if not, why it stopped
+  pub fn health(&self) -> Health {
+    self.daemon.health()
+  }
+
+  // Stops the daemon. Listeners stop accepting and every connection is hung up on once its queued writes are flushed.
+  // Connections still flushing after the timeout are abandoned. Returns once the daemon thread has exited.
+  // Affects every clone of the service; afterwards, no new connections or listeners can be made
+  pub fn shutdown(&self, timeout: Duration) -> io::Result<()> {
+    if self.daemon.health() == Health::Running {
+      self.to_daemon_tx.send(ToDaemon::Shutdown(timeout))
+        .map_err(error::cannot_send_to_daemon)?;
+      self.waker.wake().map_err(error::wake_failed)?;
+    }
+
+    // A stepped daemon finishes shutting down as the app steps it
+    if self.daemon.is_stepped() { return Ok(()); }
+
+    match self.daemon.join_timeout(timeout + time_ms::SHUTDOWN_GRACE) {
+      Health::Stopped => Ok(()),
+      Health::Running => Err(error::timed_out()),
+      Health::Failed(kind) => Err(error::daemon_failed(kind)),
+      Health::Panicked => Err(error::daemon_panicked())
+    }
+  }
+
+  // Daemon events, such as io failures. Shared by every clone of the service, so each event is received once.
+  // The channel holds a limited backlog; events beyond it are dropped until it is drained
+  pub fn events(&self) -> channel::Receiver<Event> {
+    self.rx_events.clone()
+  }
+
   pub fn wake(&self) -> io::Result<()> {
     self.waker.wake()
   }
 
-  fn clone_parts(&self) -> (channel::Sender<ToDaemon>, Arc<Waker>) {
+  fn clone_parts(&self) -> (channel::Sender<ToDaemon<T>>, Arc<Waker>) {
     (self.to_daemon_tx.clone(), Arc::clone(&self.waker))
   }
+}
+
+pub fn check_conf(conf: &Conf) -> io::Result<()> {
+  // Even a hello with no payload must fit
+  let min_datagram_size = control::HELLO_SIZE_BYTES + header::SIZE_BYTES;
+  if conf.max_datagram_size < min_datagram_size { return Err(error::invalid_max_datagram_size(min_datagram_size)); }
+  if conf.batch_size == 0 || conf.batch_size > transport::MAX_BATCH { return Err(error::invalid_batch_size(transport::MAX_BATCH)); }
+  // A zero backoff would resend the hello on every pass of the event loop
+  if conf.handshake_backoff == 0 || conf.handshake_retry > conf.handshake_retry_max { return Err(error::invalid_handshake_retry()); }
+  Ok(())
+}
+
+pub fn resolve<A: ToSocketAddrs>(to_addr: A) -> io::Result<SocketAddr> {
+  to_addr.to_socket_addrs().and_then(|mut addr| {
+    addr.next()
+      .map(Ok)
+      .unwrap_or_else(|| Err(error::socket_addr_failed_to_resolve()))
+  })
+}
 
-  // TODO: Should we expose join at all? We should always drop and detach the daemon...
-  // Maybe add an error reporting chan out of the daemon for observing errors?
+pub fn check_hello(max_datagram_size: usize, hello: &[u8]) -> io::Result<()> {
+  // The hello is wrapped in both the handshake prefix and the packet header
+  let max_hello = max_datagram_size - control::HELLO_SIZE_BYTES - header::SIZE_BYTES;
+  if hello.len() > max_hello { return Err(error::payload_too_large(max_hello)); }
+  Ok(())
+}
+
+// Turns the daemon's reply to a connect into a connection
+pub fn into_connection(received: FromDaemon, rx: channel::Receiver<FromDaemon>) -> io::Result<Connection> {
+  match received {
+    FromDaemon::Connection(on_write, shared, id) => Ok(Connection::new(on_write, shared, id)),
+
+    // The peer turned us away, say because it was full
+    FromDaemon::Refused(reason) => Err(error::refused(reason)),
+
+    // This is unexpected. We only wanted a Connection message.
+    // Close the given listener and signal the issue;
+    FromDaemon::Listener(on_close, signal) => {
+      warn!("When trying to register directly connected socket, received Listener instead");
+      let listener = Listener::new(on_close, signal, rx);
+      drop(listener);
+      Err(error::unexpected_recv_from_daemon())
+    }
+  }
 }
@@ -4,18 +4,20 @@ use std::collections::{HashMap, HashSet};
 
 use crossbeam::channel;
 
-use mio::net::UdpSocket as MioUdpSocket;
 use mio::{Waker, Token};
 
 use crate::types::FromDaemon as ToService;
-use crate::state::State;
+use crate::state::{State, Signal};
+use crate::transport::{Transport, Outbox};
 
 pub type Id = (Token, SocketAddr);
 
-pub struct Socket {
-  pub io: MioUdpSocket,
+pub struct Socket<T: Transport> {
+  pub io: T,
   pub local_addr: SocketAddr,
-  pub peer_type: PeerType
+  pub peer_type: PeerType,
+  // Datagrams written but not yet taken by the transport, sent before any others
+  pub outbox: Outbox
 }
 
 #[derive(Clone, Debug)]
@@ -23,7 +25,12 @@ pub struct ConnOpts {
   pub token: Token,
   pub tx_to_service: channel::Sender<ToService>,
   pub tx_on_write: channel::Sender<Id>,
-  pub waker: Arc<Waker>
+  // None when nobody needs waking, as with a sans-IO endpoint the app polls anyway
+  pub waker: Option<Arc<Waker>>,
+  // Tells a listener's app event loop about connections to accept
+  pub signal: Arc<Signal>,
+  // Connections are served by the listener's Handler on the daemon thread, rather than handed over
+  pub handled: bool
 }
 
 impl ConnOpts {
@@ -31,14 +38,30 @@ impl ConnOpts {
     token: Token,
     tx_to_service: channel::Sender<ToService>,
     tx_on_write: channel::Sender<Id>,
-    waker: Arc<Waker>) -> ConnOpts {
-      ConnOpts { token, tx_to_service, tx_on_write, waker }
+    waker: Option<Arc<Waker>>) -> ConnOpts {
+      ConnOpts { token, tx_to_service, tx_on_write, waker, signal: Arc::new(Signal::new()), handled: false }
+  }
+
+  // Stops handing out connections. A registered listener is woken to find the channel closed,
+  // once peers still handshaking drop their clones too
+  pub fn close(self) {
+    let signal = Arc::clone(&self.signal);
+    drop(self);
+    signal.signal();
   }
 }
 
-impl Socket {
-  pub fn new(io: MioUdpSocket, local_addr: SocketAddr, peer_type: PeerType) -> Socket {
-    Socket { io, local_addr, peer_type }
+impl<T: Transport> Socket<T> {
+  pub fn new(io: T, local_addr: SocketAddr, peer_type: PeerType) -> Socket<T> {
+    Socket { io, local_addr, peer_type, outbox: Outbox::default() }
+  }
+
+  // Whether the socket has no connections left and takes no new ones, so it only waits to flush its outbox
+  pub fn is_finished(&self) -> bool {
+    match self.peer_type {
+      PeerType::Direct(..) => false,
+      PeerType::Passive { ref peers, ref listen, .. } => peers.is_empty() && listen.is_none()
+    }
   }
 }
 
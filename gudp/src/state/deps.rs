@@ -3,14 +3,18 @@
 
 use std::slice::SliceIndex;
 use std::net::SocketAddr;
+use std::io;
 use std::time::Instant;
 // TODO: Simply export Timer instead of TimerList and change the underlying impl in the timer crate, not generically
 use crate::timer::{self, TimerKind};
 use crate::socket;
 use crate::service;
+use crate::cookie;
+use crate::admission;
+use crate::transport::Output;
 
 pub trait Deps {
-  fn timers(&mut self) -> &mut timer::List<(socket::Id, TimerKind)>;
+  fn timers(&mut self) -> &mut timer::Wheel<(socket::Id, TimerKind)>;
   fn now(&mut self) -> Instant;
 
   fn buffer<I>(&self, index: I) -> &[u8]
@@ -19,6 +23,9 @@ pub trait Deps {
   fn buffer_mut<I>(&mut self, index: I) -> &mut [u8]
   where I: SliceIndex<[u8], Output = [u8]>;
 
+  // Sends the front of the buffer to the socket's peer
+  fn send_to<O: Output>(&mut self, io: &O, socket_id: socket::Id, size: usize) -> io::Result<usize>;
+
   fn on_packet_sent<I>(&mut self, addr_pair: (SocketAddr, SocketAddr), index: I, sequence_no: u32)
   where I: SliceIndex<[u8], Output = [u8]>;
 
@@ -26,4 +33,10 @@ pub trait Deps {
 
   fn notify_write(&self, socket_id: socket::Id);
   fn conf(&self) -> &service::Conf;
+
+  // Bakes and checks the cookies new peers must echo before they may connect
+  fn cookies(&self) -> &cookie::Jar;
+
+  // Runs admission control on a new peer, whose first packet is at the front of the buffer
+  fn admit(&mut self, peer_addr: SocketAddr, n_peers: usize, size: usize) -> Result<(), admission::Refusal>;
 }
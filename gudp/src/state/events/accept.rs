@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+
+use crate::state::{State, Deps, is_hello, send_challenge, send_disconnect};
+use crate::constants::{header, control};
+use crate::admission::Refusal;
+use crate::transport::Output;
+use crate::socket::{self, ConnOpts};
+
+// What became of a new peer's verified hello.
+// Only ever returned, and the connection moves straight on into the peer map, so it isn't boxed
+#[allow(clippy::large_enum_variant)]
+pub enum Admitted {
+  // The peer's new connection, to keep
+  Connection(State),
+  // The accept backlog had no room, and the peer was told the server is full
+  Full,
+  // Admission control turned the peer away, telling it why if the refusal calls for it
+  Refused(Refusal),
+  // The first packet ended the connection, so there is nothing to keep
+  Finished
+}
+
+// Checks a datagram from a new peer is a hello with a valid cookie, challenging the peer if it has none.
+// A verified hello's packet is moved to the front of the buffer and its size updated, as if it was received bare
+pub fn verify_hello<D: Deps, O: Output>(io: &O, socket_id: socket::Id, size: &mut usize, deps: &mut D) -> bool {
+  let (_, peer_addr) = socket_id;
+  if !is_hello(deps.buffer(..*size)) || *size < control::HELLO_SIZE_BYTES + header::SIZE_BYTES { return false; }
+
+  let now = deps.now();
+  if !deps.cookies().check(peer_addr, now, deps.buffer(control::COOKIE_RANGE)) {
+    // Stateless until verified. The challenge is never larger than the hello it answers
+    let cookie = deps.cookies().bake(peer_addr, now);
+    send_challenge(io, socket_id, &cookie, deps).ok();
+    return false;
+  }
+
+  if deps.buffer(control::HELLO_SIZE_BYTES..)[header::MAGIC_BYTES_RANGE] != header::MAGIC_BYTES { return false; }
+  deps.buffer_mut(..*size).copy_within(control::HELLO_SIZE_BYTES.., 0);
+  *size -= control::HELLO_SIZE_BYTES;
+  true
+}
+
+// Creates a connection for a new peer whose hello verified, unless the accept backlog is full or admission control refuses it.
+// Admission control runs before any state is allocated, so turned away peers cost nothing
+pub fn admit<D: Deps, O: Output>(
+  io: &O,
+  local_addr: SocketAddr,
+  socket_id: socket::Id,
+  conn_opts: &ConnOpts,
+  n_peers: usize,
+  size: usize,
+  deps: &mut D) -> Admitted {
+  let (_, peer_addr) = socket_id;
+
+  // Never block on a slow app; turn away peers the accept backlog has no room for
+  if conn_opts.tx_to_service.is_full() {
+    send_disconnect(io, socket_id, control::reason::SERVER_FULL, deps).ok();
+    return Admitted::Full;
+  }
+
+  if let Err(refusal) = deps.admit(peer_addr, n_peers, size) {
+    if let Some(reason) = refusal.reason() {
+      send_disconnect(io, socket_id, reason, deps).ok();
+    }
+    return Admitted::Refused(refusal);
+  }
+
+  let mut state = State::init(local_addr, socket_id, conn_opts.clone(), vec![], deps);
+  if state.read(local_addr, peer_addr, size, deps) {
+    Admitted::Connection(state)
+  } else {
+    state.cancel_timers(deps);
+    Admitted::Finished
+  }
+}
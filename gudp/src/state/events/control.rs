@@ -0,0 +1,96 @@
+use std::io;
+
+use crate::state::{State, FSM, Deps, lock_buf};
+use crate::types::FromDaemon as ToService;
+use crate::error::Reason;
+use crate::constants::{header, control};
+use crate::cookie::Cookie;
+use crate::transport::Output;
+use crate::socket;
+
+// Control messages carry their own magic bytes and skip the sequenced packet header entirely
+pub fn is_control(buf: &[u8]) -> bool {
+  buf.len() >= control::SIZE_BYTES && buf[control::MAGIC_BYTES_RANGE] == control::MAGIC_BYTES
+}
+
+pub fn is_hello(buf: &[u8]) -> bool {
+  is_control(buf) && buf[control::KIND_OFFSET] == control::KIND_HELLO
+}
+
+// Challenges a new peer to echo the cookie before it may connect.
+// NOTE: Callers must only answer packets at least this large, so spoofed packets are never amplified
+pub fn send_challenge<D: Deps, O: Output>(io: &O, socket_id: socket::Id, cookie: &Cookie, deps: &mut D) -> io::Result<usize> {
+  deps.buffer_mut(control::MAGIC_BYTES_RANGE).copy_from_slice(&control::MAGIC_BYTES);
+  deps.buffer_mut(..control::CHALLENGE_SIZE_BYTES)[control::KIND_OFFSET] = control::KIND_CHALLENGE;
+  deps.buffer_mut(control::COOKIE_RANGE).copy_from_slice(cookie);
+  deps.send_to(io, socket_id, control::CHALLENGE_SIZE_BYTES)
+}
+
+// Tells the peer we're going away, for the given reason. Uses the deps buffer as scratch space.
+// Best effort; if the notice is lost the peer simply times out instead
+pub fn send_disconnect<D: Deps, O: Output>(io: &O, socket_id: socket::Id, reason: u8, deps: &mut D) -> io::Result<usize> {
+  deps.buffer_mut(control::MAGIC_BYTES_RANGE).copy_from_slice(&control::MAGIC_BYTES);
+  deps.buffer_mut(..control::DISCONNECT_SIZE_BYTES)[control::KIND_OFFSET] = control::KIND_DISCONNECT;
+  deps.buffer_mut(..control::DISCONNECT_SIZE_BYTES)[control::REASON_OFFSET] = reason;
+  deps.send_to(io, socket_id, control::DISCONNECT_SIZE_BYTES)
+}
+
+impl State {
+  // Returns false when the connection is terminal and can be cleaned up
+  // Returns true otherwise
+  pub fn control<D: Deps>(&mut self, size: usize, deps: &mut D) -> bool {
+    match deps.buffer(..size)[control::KIND_OFFSET] {
+      control::KIND_DISCONNECT => {
+        // The peer hung up. The app may still drain its reads before observing the closed status.
+        let reason = if size < control::DISCONNECT_SIZE_BYTES { control::reason::CLOSED } else { deps.buffer(..size)[control::REASON_OFFSET] };
+
+        // Nobody has the connection yet; whoever is still waiting to connect learns why it never will
+        if let FSM::Handshaking { ref conn_opts, .. } = self.fsm {
+          conn_opts.tx_to_service.try_send(ToService::Refused(Reason::from(reason))).ok();
+          return false;
+        }
+
+        let (ref buf_read, _, ref status, _, _, _) = *self.shared;
+        let lock = lock_buf(buf_read, status);
+        status.set_peer_disconnect(reason);
+        lock.notify_all();
+        drop(lock);
+        self.notify_write_waiters();
+        false
+      },
+
+      control::KIND_CHALLENGE => {
+        // Echo the cookie with our hello right away, rather than waiting on the next retry
+        if let FSM::Handshaking { ref mut cookie, ref hello, .. } = self.fsm {
+          if size < control::CHALLENGE_SIZE_BYTES { return true; }
+          cookie.copy_from_slice(deps.buffer(control::COOKIE_RANGE));
+
+          let (_, ref buf_write, ref status, _, _, _) = *self.shared;
+          let mut buf_write = lock_buf(buf_write, status);
+          if buf_write.count() == 0 { buf_write.push_back(hello); }
+          drop(buf_write);
+          deps.notify_write(self.socket_id);
+        }
+        true
+      },
+
+      control::KIND_HELLO => {
+        // Hellos from a peer we're connected with are retransmits; we've had their payload
+        if let FSM::Connected = self.fsm { return true; }
+
+        // Two direct sockets connecting to each other both send hellos, and the first to arrive completes our handshake.
+        // Unwrap the packet within and read it as if it was received bare
+        if size < control::HELLO_SIZE_BYTES + header::SIZE_BYTES { return true; }
+        let packet = deps.buffer_mut(..size);
+        if packet[control::HELLO_SIZE_BYTES..][header::MAGIC_BYTES_RANGE] != header::MAGIC_BYTES { return true; }
+        packet.copy_within(control::HELLO_SIZE_BYTES.., 0);
+
+        let (local_addr, (_, peer_addr)) = (self.local_addr, self.socket_id);
+        self.read(local_addr, peer_addr, size - control::HELLO_SIZE_BYTES, deps)
+      },
+
+      // Unknown control messages are socket noise
+      _ => true
+    }
+  }
+}
@@ -2,17 +2,19 @@ use std::net::SocketAddr;
 use std::sync::atomic::Ordering::SeqCst as OSeqCst;
 
 use crate::socket::{self, ConnOpts};
-use crate::state::{State, FSM, Deps, Sequence, NetStat, shared};
-use crate::timer::{Timers, TimerKind};
-use crate::constants::time_ms;
+use crate::state::{State, FSM, Handling, Deps, Sequence, NetStat, shared};
+use crate::timer::{Armed, TimerKind};
+use crate::constants::{time_ms, header, control};
+use crate::ratelimit::Buckets;
 
 impl State {
-  pub fn init<D: Deps>(local_addr: SocketAddr, socket_id: socket::Id, conn_opts: ConnOpts, deps: &mut D) -> State {
+  pub fn init<D: Deps>(local_addr: SocketAddr, socket_id: socket::Id, conn_opts: ConnOpts, hello: Vec<u8>, deps: &mut D) -> State {
     let when = deps.now();
-    let timers = deps.timers();
-    timers.add((socket_id, TimerKind::Timeout), when + time_ms::TIMEOUT);
-    timers.add((socket_id, TimerKind::Heartbeat), when + time_ms::HEARTBEAT);
-    let shared = shared::new();
+    let retry = deps.conf().handshake_retry;
+    let rate_limit = deps.conf().conn_rate_limit.map(|limit| Buckets::new(limit, when));
+    // The first packet to the peer carries the hello payload, if any
+    let max_payload = deps.conf().max_datagram_size - header::SIZE_BYTES;
+    let shared = shared::new(&hello, max_payload);
 
     let rtt_ms = shared.3.rtt.load(OSeqCst);
     let netstat = NetStat::new(rtt_ms);
@@ -20,15 +22,27 @@ impl State {
     // Notify that we have pending initial writes to send
     deps.notify_write(socket_id);
 
-    State {
-      shared: shared::new(),
+    let handling = if conn_opts.handled { Some(Handling::new(&hello)) } else { None };
+
+    let mut state = State {
+      shared,
       local_addr,
       socket_id,
       sequence: Sequence::new(), // TODO: Use deps.rand() to randomize seq no
       last_recv: when,
       last_send: when,
       netstat,
-      fsm: FSM::Handshaking { conn_opts },
+      rate_limit,
+      armed: Armed::default(),
+      handling,
+      fsm: FSM::Handshaking { conn_opts, retry, hello, cookie: [0u8; control::COOKIE_SIZE_BYTES], hello_sent: false },
+    };
+
+    state.armed.arm(deps.timers(), socket_id, TimerKind::Timeout, when + time_ms::TIMEOUT);
+    state.armed.arm(deps.timers(), socket_id, TimerKind::Heartbeat, when + time_ms::HEARTBEAT);
+    if retry > time_ms::ZERO {
+      state.armed.arm(deps.timers(), socket_id, TimerKind::Handshake, when + retry);
     }
+    state
   }
 }
@@ -2,3 +2,5 @@ pub mod init;
 pub mod read;
 pub mod write;
 pub mod timer;
+pub mod control;
+pub mod accept;
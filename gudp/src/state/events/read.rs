@@ -3,40 +3,62 @@ use std::sync::Arc;
 use std::sync::atomic::Ordering::SeqCst as OSeqCst;
 use std::io;
 
+use bring::Bring;
+use cond_mutex::CondMutexGuard;
+
 use crate::types::FromDaemon as ToService;
 use crate::error;
-use crate::state::{sequence, State, FSM, Sequence, Deps};
+use crate::state::{sequence, is_control, State, FSM, Handling, Sequence, Deps, Signal, lock_buf};
 use crate::constants::header;
 
 impl State {
   // Returns false when the connection is terminal and can be cleaned up
   // Returns true otherwise
   pub fn read<D: Deps>(&mut self, local_addr: SocketAddr, peer_addr: SocketAddr, size: usize, deps: &mut D) -> bool {
-    let addr_pair = (local_addr, peer_addr);
-    let (ref buf_read, ref buf_write, ref status, ref netstat_out) = *self.shared;
+    if is_control(deps.buffer(..size)) { return self.control(size, deps); }
 
-    // TODO: Should we handle a poisoned lock state here? IE if a thread with a connection panics,
-    // what should the daemon do about it? Just close the connection?
-    // Likely the client should panic on poison, and the daemon should recover the lock and close the conn on poison
-    // For now just panic
-    let mut buf = buf_read.lock().expect("Could not acquire unpoisoned read lock");
+    let addr_pair = (local_addr, peer_addr);
+    let (ref buf_read, _, ref status, ref netstat_out, _, ref readiness) = *self.shared;
+
+    // If an app thread panicked while holding the lock, only this connection is closed.
+    // A handler's connection has no app thread to panic or wait on it, so it is left unlocked
+    let buf = match self.handling { Some(_) => None, None => Some(lock_buf(buf_read, status)) };
+    if status.is_poisoned() {
+      if let Some(buf) = buf { buf.notify_all(); }
+      self.notify_write_waiters();
+      return false;
+    }
 
     match &mut self.fsm {
       /* Initial read from peer */
-      FSM::Handshaking { conn_opts } => {
-        let on_write = {
-          let token = conn_opts.token;
-          let tx_on_write = conn_opts.tx_on_write.clone();
-          let waker = Arc::clone(&conn_opts.waker);
-
-          move |size| -> io::Result<usize> {
-            tx_on_write.send((token, peer_addr)).map_err(error::cannot_send_to_daemon)?;
-            waker.wake().map_err(error::wake_failed)?;
-            Ok(size)
+      FSM::Handshaking { conn_opts, hello_sent, .. } => {
+        let handed_over = match self.handling {
+          // The handler serves the connection right here, so there is no app to hand it to
+          Some(ref mut handling) => {
+            handling.connecting = true;
+            true
+          },
+          None => {
+            let on_write = {
+              let token = conn_opts.token;
+              let tx_on_write = conn_opts.tx_on_write.clone();
+              let waker = conn_opts.waker.clone();
+
+              move |size| -> io::Result<usize> {
+                tx_on_write.send((token, peer_addr)).map_err(error::cannot_send_to_daemon)?;
+                if let Some(ref waker) = waker { waker.wake().map_err(error::wake_failed)?; }
+                Ok(size)
+              }
+            };
+            // NOTE: Never block the event loop on the app. A full channel fails like a closed one.
+            let sent = conn_opts.tx_to_service.try_send(ToService::Connection(Arc::new(on_write), Arc::clone(&self.shared), (local_addr, peer_addr)));
+            if sent.is_ok() { conn_opts.signal.signal(); }
+            sent.is_ok()
           }
         };
-        match conn_opts.tx_to_service.send(ToService::Connection(Arc::new(on_write), Arc::clone(&self.shared), (local_addr, peer_addr))) {
-          Ok(_) => {
+
+        match handed_over {
+          true => {
             /* Initial response handling */
             // This was relevant socket activity, so bump the timeout
             let when = deps.now();
@@ -51,9 +73,7 @@ impl State {
             self.sequence.remote_seq_no = seq_no;
 
             if size > header::SIZE_BYTES {
-              buf.push_back(&mut deps.buffer(header::SIZE_BYTES..size));
-              buf.notify_one();
-              drop(buf);
+              deliver(&mut self.handling, buf, readiness, size, deps);
             }
 
             for ack in handle_acks(&mut bytes, &mut self.sequence, deps) {
@@ -61,10 +81,12 @@ impl State {
               deps.on_packet_acked(addr_pair, ack.seq_no);
             }
 
+            // Any hellos we sent shared one sequence number; packets from here on move past it
+            if *hello_sent { self.sequence.local_seq_no = self.sequence.local_seq_no.wrapping_add(1); }
             self.fsm = FSM::Connected;
             true
           },
-          Err(_) => {
+          false => {
             // NOTE: Setting status and notifying is not necessary- if the send failed there is no app-side connection to observe this or block on it
             false
           }
@@ -83,18 +105,20 @@ impl State {
           sequence::Distance::Redundant => None, // Keep jitter within 33 seconds, but don't redundantly ack it
           sequence::Distance::New(n) => Some(n) // Keep and ack
         };
+        // A repeat of the newest packet, such as a retried hello, has already been delivered
+        let repeat = seq_no == self.sequence.remote_seq_no;
 
         let when = deps.now();
         self.last_recv = when;
 
         // The connection only sets app_has_hup on drop, which can only occur
-        // when all clones have been dropped (they are simply behind an arc).
+        // when all clones have been dropped, or on close, which no longer reads.
         // Thus, we can guarantee there are no condvar-listeners to notify
         if status.app_has_hup() {
           // We check the special case of a dropped connection.
-          // We can actually clean up the resource if dropped and there are no writes to flush
-          let buf_write = buf_write.lock().expect("Could not acquire unpoisoned write lock");
-          return buf_write.count() > 0;
+          // Nothing left to read; let the write side flush any writes, tell the peer and clean up the resource
+          deps.notify_write(self.socket_id);
+          return true;
         }
 
         // Only update the sequence gap if the sequence is newer
@@ -108,9 +132,9 @@ impl State {
           self.sequence.update_remote(seq_no, gap);
         }
 
-        if size > header::SIZE_BYTES {
-          buf.push_back(&mut deps.buffer(header::SIZE_BYTES..size));
-          buf.notify_one();
+        // Once the app can no longer receive, payloads are only worth their acks
+        if size > header::SIZE_BYTES && !repeat && !status.app_recv_has_hup() {
+          deliver(&mut self.handling, buf, readiness, size, deps);
         }
 
         for ack in handle_acks(&mut bytes, &mut self.sequence, deps) {
@@ -124,6 +148,21 @@ impl State {
   }
 }
 
+// Hands the payload to whoever reads the connection: the app through the read buffer,
+// or the handler, which reads it straight out of the daemon's buffer once the read is done
+fn deliver<D: Deps>(handling: &mut Option<Handling>, buf: Option<CondMutexGuard<Bring>>, readiness: &Signal, size: usize, deps: &mut D) {
+  match (handling, buf) {
+    (Some(handling), _) => handling.received = Some(size),
+    (None, Some(mut buf)) => {
+      buf.push_back(deps.buffer(header::SIZE_BYTES..size));
+      buf.notify_one();
+      drop(buf);
+      readiness.signal();
+    },
+    (None, None) => { }
+  }
+}
+
 fn handle_acks<'a, D: Deps>(bytes: &mut [u8; 4], sequence: &'a mut Sequence, deps: &mut D) -> sequence::AckIter<'a> {
   bytes.copy_from_slice(deps.buffer(header::REMOTE_SEQ_NO_RANGE));
   let ack_no = u32::from_be_bytes(*bytes);
@@ -1,31 +1,53 @@
-use crate::state::{State, Deps};
+use std::time::Duration;
+
+use crate::state::{State, FSM, Deps, lock_buf};
 use crate::constants::time_ms;
-use crate::timer::{Timers, TimerKind};
+use crate::timer::TimerKind;
 
 impl State {
   // Returns true when the connection is updated
   // Returns false when the connection has timed out
   pub fn timer<D: Deps>(&mut self, kind: TimerKind, deps: &mut D) -> bool {
-    let (ref buf_read, _, ref status, _) = *self.shared;
+    self.armed.fired(kind);
+    let (ref buf_read, ref buf_write, ref status, _, _, _) = *self.shared;
     match kind {
       TimerKind::Timeout => {
         let when = deps.now();
         if (when - self.last_recv) >= time_ms::TIMEOUT {
-          let lock = buf_read.lock().expect("Could not acquire unpoisoned read lock");
+          let lock = lock_buf(buf_read, status);
           status.set_peer_hup();
           lock.notify_all();
+          drop(lock);
+          self.notify_write_waiters();
           false
         } else {
-          deps.timers().add((self.socket_id, TimerKind::Timeout), when + time_ms::TIMEOUT);
+          self.armed.arm(deps.timers(), self.socket_id, TimerKind::Timeout, when + time_ms::TIMEOUT);
           true
         }
       },
 
       TimerKind::Heartbeat => {
         let when = deps.now();
-        deps.timers().add((self.socket_id, TimerKind::Heartbeat), when + time_ms::HEARTBEAT);
+        self.armed.arm(deps.timers(), self.socket_id, TimerKind::Heartbeat, when + time_ms::HEARTBEAT);
         deps.notify_write(self.socket_id);
 
+        true
+      },
+
+      TimerKind::Handshake => {
+        // Only resend while the peer has yet to reply; once connected, heartbeats take over
+        if let FSM::Handshaking { ref mut retry, ref hello, .. } = self.fsm {
+          let when = deps.now();
+          let conf = deps.conf();
+          *retry = Duration::min(retry.saturating_mul(conf.handshake_backoff), conf.handshake_retry_max);
+          self.armed.arm(deps.timers(), self.socket_id, TimerKind::Handshake, when + *retry);
+
+          let mut buf_write = lock_buf(buf_write, status);
+          if buf_write.count() == 0 { buf_write.push_back(hello); }
+          drop(buf_write);
+          deps.notify_write(self.socket_id);
+        }
+
         true
       }
     }
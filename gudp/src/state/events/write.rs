@@ -1,19 +1,23 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::mem;
 use std::sync::atomic::Ordering::SeqCst as OSeqCst;
 use std::io;
-use mio::net::UdpSocket as MioUdpSocket;
 
 use bring::WithOpt;
 use bring::Bring;
 use cond_mutex::CondMutex;
 
-use crate::state::{State, Deps, SentSeqNo};
+use crate::state::{State, FSM, Deps, SentSeqNo, Status, Signal, send_disconnect, lock_buf};
 use crate::types::READ_BUFFER_TAG;
-use crate::constants::{header, time_ms, SENT_SEQ_BUF_SIZE};
+use crate::transport::Output;
+use crate::constants::{header, control, time_ms, SENT_SEQ_BUF_SIZE};
 
-fn terminal(buf_read: &CondMutex<Bring, READ_BUFFER_TAG>) -> io::Result<bool> {
-  let lock = buf_read.lock().expect("Could not acquire unpoisoned read lock");
+fn terminal(buf_read: &CondMutex<Bring, READ_BUFFER_TAG>, status: &Status, readiness: &Signal) -> io::Result<bool> {
+  let lock = lock_buf(buf_read, status);
   lock.notify_all();
+  drop(lock);
+  readiness.signal();
   Ok(false)
 }
 
@@ -23,75 +27,64 @@ impl State {
   //    Ok(False) when the state has become terminal and the socket can be cleaned up
   //    Err(e) when an io error occurs on write. NOTE: It may be WouldBlock, which is non-fatal
 
-  pub fn write<D: Deps>(&mut self, io: &mut MioUdpSocket, peer_addr: SocketAddr, deps: &mut D) -> io::Result<bool> {
-    let (ref buf_read, ref buf_write, ref status, ref netstat_out) = *self.shared;
+  pub fn write<D: Deps, O: Output>(&mut self, io: &O, peer_addr: SocketAddr, deps: &mut D) -> io::Result<bool> {
+    if self.handling.is_some() { return self.write_handled(io, peer_addr, deps); }
+
+    // Held apart from self, which each send updates
+    let shared = Arc::clone(&self.shared);
+    let (ref buf_read, ref buf_write, ref status, _, _, ref readiness) = *shared;
     // NOTE: Currently ONLY a timeout can cause a peer_hup, and socket cleanup happens immediately.
     // We will never end up here in the single-threaded event loop writing to a peer which has hung up.
     // So we don't check for peer_hup here. If we add a protocol-level fin message, this may change.
 
+    // Any app writes from here on must wake us again, since this flush may miss them
+    status.clear_write_pending();
+
     // loop until we hit WOULDBLOCK, some other err or run out of things to write
-    let mut buf_write = buf_write.lock().expect("Could not acquire unpoisoned write lock");
+    let mut buf_write = lock_buf(buf_write, status);
+
+    // An app thread panicked mid-update, so the buffer can't be trusted. Tell the peer and give up.
+    if status.is_poisoned() {
+      send_disconnect(io, self.socket_id, control::reason::CLOSED, deps).ok();
+      status.set_fin_sent();
+      buf_write.notify_all();
+      drop(buf_write);
+      return terminal(buf_read, status, readiness);
+    }
+
     loop {
       if buf_write.count() <= 0 {
         if (deps.now() - self.last_send) >= time_ms::HEARTBEAT {
-          buf_write.push_back(&[]);
+          // Until the peer replies, every packet we send must be a hello
+          match self.fsm {
+            FSM::Handshaking { ref hello, .. } => buf_write.push_back(hello),
+            FSM::Connected => buf_write.push_back(&[])
+          };
           continue;
         }
 
         // Called with buf_write locked, to prevent a "write then hangup" race
-        if status.app_has_hup() { return terminal(buf_read); }
+        if status.app_has_hup() {
+          send_disconnect(io, self.socket_id, control::reason::CLOSED, deps).ok();
+          status.set_fin_sent();
+          buf_write.notify_all();
+          return terminal(buf_read, status, readiness);
+        }
+
+        // Fully flushed; wake anyone waiting on that
+        buf_write.notify_all();
+        drop(buf_write);
+        readiness.signal();
         return Ok(true);
       }
 
-      let buf = &mut *buf_write;
-
-      // TODO: Add CRC?
-      // NOTE: buf_local MUST be large enough to hold the packet header
-      deps.buffer_mut(header::MAGIC_BYTES_RANGE).copy_from_slice(&header::MAGIC_BYTES);
-      deps.buffer_mut(header::LOCAL_SEQ_NO_RANGE).copy_from_slice(&self.sequence.local_seq_no.to_be_bytes());
-      deps.buffer_mut(header::REMOTE_SEQ_NO_RANGE).copy_from_slice(&self.sequence.remote_seq_no.to_be_bytes());
-      deps.buffer_mut(header::REMOTE_SEQ_TAIL_RANGE).copy_from_slice(&self.sequence.remote_seq_tail.to_be_bytes());
-
-      // This attempts to peek+send the front blob of the write buffer
-      match buf.front(deps.buffer_mut(header::SIZE_BYTES..)).map(|mut front| {
-        front.with(|payload_size_bytes| {
-          let send = io.send_to(deps.buffer(..header::SIZE_BYTES + payload_size_bytes), peer_addr);
-          let opt = match send { Ok(_) => WithOpt::Pop, Err(_) => WithOpt::Peek };
-          (send, opt)
-        })
-      }) {
-        /* Write OK */
-        Some(Ok(total_size_bytes)) => {
-          let when = deps.now();
-          let sent_seq_no = self.sequence.local_seq_no;
-          let sent_idx = sent_seq_no as usize % SENT_SEQ_BUF_SIZE;
-
-          // Only notify for contentful packets
-          let prev_sent_seq_no = if total_size_bytes > header::SIZE_BYTES {
-            deps.on_packet_sent((self.local_addr, peer_addr), header::SIZE_BYTES..total_size_bytes, sent_seq_no);
-
-            // Swap with previous at this location. If exists and unacked, it's a lost packet
-            self.sequence.sent_seq_buf[sent_idx].replace(SentSeqNo::new(sent_seq_no, when))
-          } else {
-            // Erase the 'old' buffer entry for rare cases of high packet loss leading to unintentional acks
-            self.sequence.sent_seq_buf[sent_idx].take()
-          };
-
-          if let Some(ssn) = prev_sent_seq_no {
-            if !ssn.acked {
-              netstat_out.loss.store(self.netstat.loss.lost(1), OSeqCst);
-            }
-          };
-          self.last_send = when;
-
-          // Bump to the next unsent sequence number
-          self.sequence.local_seq_no = self.sequence.local_seq_no.wrapping_add(1);
-        }
+      match self.send_front(&mut buf_write, io, peer_addr, deps) {
+        Some(Ok(())) => { }
 
         /* Could not peek at the front of the write buffer */
-        // TODO: If our buf is too small, should we truncate? return Err:WriteZero?
-        // Otherwise maybe change buflocal to a vec and only grow it if we get massive packets?
-        None => buf_write.clear(), // For now just empty the buffer
+        // Sends are capped at the max payload, so only an oversized hello can land here.
+        // Drop just that blob rather than everything queued behind it
+        None => { buf_write.skip_front(); }
 
         /* Write Err */
         // This may be a safe WouldBlock. Err results do NOT indicate that listeners have been notified/timers cleared, etc.
@@ -100,4 +93,113 @@ impl State {
       }
     }
   }
+
+  // Like write, for a connection a handler serves on this thread. Nothing else ever touches what it sent,
+  // so it is flushed without taking the write buffer's lock
+  fn write_handled<D: Deps, O: Output>(&mut self, io: &O, peer_addr: SocketAddr, deps: &mut D) -> io::Result<bool> {
+    let shared = Arc::clone(&self.shared);
+    let (_, _, ref status, _, _, _) = *shared;
+    status.clear_write_pending();
+
+    let mut sends = match self.handling {
+      Some(ref mut handling) => mem::replace(&mut handling.sends, Bring::from_vec(vec![])),
+      None => return Ok(true)
+    };
+    let written = loop {
+      if sends.count() <= 0 {
+        if (deps.now() - self.last_send) >= time_ms::HEARTBEAT {
+          match self.fsm {
+            FSM::Handshaking { ref hello, .. } => sends.push_back(hello),
+            FSM::Connected => sends.push_back(&[])
+          };
+          continue;
+        }
+
+        // Set by the handler closing the connection, or by shutdown
+        if status.app_has_hup() {
+          send_disconnect(io, self.socket_id, control::reason::CLOSED, deps).ok();
+          status.set_fin_sent();
+          break Ok(false);
+        }
+        break Ok(true);
+      }
+
+      match self.send_front(&mut sends, io, peer_addr, deps) {
+        Some(Ok(())) => { }
+        None => { sends.skip_front(); }
+        Some(Err(e)) => break Err(e)
+      }
+    };
+
+    if let Some(ref mut handling) = self.handling { handling.sends = sends; }
+    written
+  }
+
+  // Sends the front of the buffer as one packet, popping it once sent.
+  // None if it doesn't fit in a packet
+  fn send_front<D: Deps, O: Output>(&mut self, buf: &mut Bring, io: &O, peer_addr: SocketAddr, deps: &mut D) -> Option<io::Result<()>> {
+    let (_, _, _, ref netstat_out, _, _) = *self.shared;
+
+    // While handshaking, packets are wrapped in a hello echoing the listener's cookie
+    let offset = match self.fsm {
+      FSM::Handshaking { ref cookie, .. } => {
+        deps.buffer_mut(control::MAGIC_BYTES_RANGE).copy_from_slice(&control::MAGIC_BYTES);
+        deps.buffer_mut(..control::HELLO_SIZE_BYTES)[control::KIND_OFFSET] = control::KIND_HELLO;
+        deps.buffer_mut(control::COOKIE_RANGE).copy_from_slice(cookie);
+        control::HELLO_SIZE_BYTES
+      },
+      FSM::Connected => 0
+    };
+
+    // TODO: Add CRC?
+    // NOTE: buf_local MUST be large enough to hold the packet header
+    let packet = deps.buffer_mut(offset..);
+    packet[header::MAGIC_BYTES_RANGE].copy_from_slice(&header::MAGIC_BYTES);
+    packet[header::LOCAL_SEQ_NO_RANGE].copy_from_slice(&self.sequence.local_seq_no.to_be_bytes());
+    packet[header::REMOTE_SEQ_NO_RANGE].copy_from_slice(&self.sequence.remote_seq_no.to_be_bytes());
+    packet[header::REMOTE_SEQ_TAIL_RANGE].copy_from_slice(&self.sequence.remote_seq_tail.to_be_bytes());
+
+    // This attempts to peek+send the front blob of the write buffer
+    let sent = buf.front(deps.buffer_mut(offset + header::SIZE_BYTES..)).map(|mut front| {
+      front.with(|payload_size_bytes| {
+        let send = deps.send_to(io, self.socket_id, offset + header::SIZE_BYTES + payload_size_bytes);
+        let opt = match send { Ok(_) => WithOpt::Pop, Err(_) => WithOpt::Peek };
+        (send, opt)
+      })
+    });
+    let total_size_bytes = match sent {
+      Some(Ok(total_size_bytes)) => total_size_bytes,
+      other => return other.map(|send| send.map(|_| ()))
+    };
+    let when = deps.now();
+    let sent_seq_no = self.sequence.local_seq_no;
+    let sent_idx = sent_seq_no as usize % SENT_SEQ_BUF_SIZE;
+
+    // Only notify for contentful packets
+    let prev_sent_seq_no = if total_size_bytes > offset + header::SIZE_BYTES {
+      deps.on_packet_sent((self.local_addr, peer_addr), offset + header::SIZE_BYTES..total_size_bytes, sent_seq_no);
+
+      // Swap with previous at this location. If exists and unacked, it's a lost packet
+      self.sequence.sent_seq_buf[sent_idx].replace(SentSeqNo::new(sent_seq_no, when))
+    } else {
+      // Erase the 'old' buffer entry for rare cases of high packet loss leading to unintentional acks
+      self.sequence.sent_seq_buf[sent_idx].take()
+    };
+
+    if let Some(ssn) = prev_sent_seq_no {
+      if !ssn.acked {
+        netstat_out.loss.store(self.netstat.loss.lost(1), OSeqCst);
+      }
+    };
+    self.last_send = when;
+
+    // Bump to the next unsent sequence number. Until the peer replies, every packet is a resent hello
+    // under the same sequence number, so the peer can tell retries from the first
+    match self.fsm {
+      FSM::Handshaking { ref mut hello_sent, .. } => *hello_sent = true,
+      FSM::Connected => self.sequence.local_seq_no = self.sequence.local_seq_no.wrapping_add(1)
+    }
+
+    Some(Ok(()))
+  }
 }
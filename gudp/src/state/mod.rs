@@ -1,12 +1,21 @@
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::net::SocketAddr;
 
+use bring::Bring;
+
 use crate::socket::{self, ConnOpts};
+use crate::cookie::Cookie;
+use crate::ratelimit::Buckets;
+use crate::timer::Armed;
+use crate::constants::CONFIG_BUF_SIZE_BYTES;
 
 pub use status::Status;
-pub use shared::Shared;
+pub use shared::{Shared, lock_buf};
 pub use deps::Deps;
+pub use signal::{Signal, Notify};
+pub use events::control::{is_control, is_hello, send_challenge, send_disconnect};
+pub use events::accept::{verify_hello, admit, Admitted};
 use netstat::NetStat;
 use sequence::{Sequence, SentSeqNo};
 
@@ -17,6 +26,7 @@ mod events;
 mod util;
 mod deps;
 mod netstat;
+mod signal;
 
 /// Connection state
 /// Tracks all the behavior of a given connection
@@ -28,10 +38,33 @@ pub struct State {
   pub last_send: Instant,
   pub sequence: Sequence,
   pub netstat: NetStat,
+  pub rate_limit: Option<Buckets>,
+  pub armed: Armed,
+  // Only for connections a listener's Handler serves on the daemon thread
+  pub handling: Option<Handling>,
   pub fsm: FSM,
 }
 
+// A connection with no app thread on the other end, so nothing here is ever shared or locked
+pub struct Handling {
+  // The connection just came up, and the handler has yet to hear of it
+  pub connecting: bool,
+  // The size of the datagram just read, whose payload the handler has yet to see in the daemon's buffer
+  pub received: Option<usize>,
+  // What the handler sent, flushed in place of the write buffer
+  pub sends: Bring
+}
+
+impl Handling {
+  // Like the write buffer, starts out with the hello, the first packet the peer hears from us
+  pub fn new(hello: &[u8]) -> Handling {
+    let mut sends = Bring::from_vec(vec![0u8; CONFIG_BUF_SIZE_BYTES]);
+    sends.push_back(hello);
+    Handling { connecting: false, received: None, sends }
+  }
+}
+
 pub enum FSM {
-  Handshaking { conn_opts: ConnOpts },
+  Handshaking { conn_opts: ConnOpts, retry: Duration, hello: Vec<u8>, cookie: Cookie, hello_sent: bool },
   Connected
 }
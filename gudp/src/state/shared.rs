@@ -1,27 +1,34 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::sync::atomic::AtomicU32;
 
+use log::warn;
 use bring::Bring;
-use cond_mutex::CondMutex;
+use cond_mutex::{CondMutex, CondMutexGuard};
 
-use crate::state::{netstat, Status};
-use crate::types::READ_BUFFER_TAG;
+use crate::state::{netstat, Status, Signal};
+use crate::types::{READ_BUFFER_TAG, WRITE_BUFFER_TAG};
 use crate::constants::CONFIG_BUF_SIZE_BYTES;
 
 // TODO: Nominal type? Though ergonomics of destructuring tuple is nice...
 pub type Shared = (
   /*BufRead*/   CondMutex<Bring, READ_BUFFER_TAG>,
-  /*BufWrite*/  Mutex<Bring>,
+  /*BufWrite*/  CondMutex<Bring, WRITE_BUFFER_TAG>,
 
   // Atomics
   /*Status*/    Status,
-  /*NetStat*/   netstat::Shared
+  /*NetStat*/   netstat::Shared,
+
+  // Largest payload a single send may carry
+  /*MaxPayload*/ usize,
+
+  // Readiness for the app's own event loop
+  /*Readiness*/ Signal
 );
 
-fn initial_write_ring_buf() -> Bring {
+fn initial_write_ring_buf(hello: &[u8]) -> Bring {
   let buf_write_vec = vec![0u8; CONFIG_BUF_SIZE_BYTES];
   let mut ring_buf = Bring::from_vec(buf_write_vec);
-  ring_buf.push_back(&[]);
+  ring_buf.push_back(hello);
   ring_buf
 }
 
@@ -30,11 +37,49 @@ fn initial_read_ring_buf() -> Bring {
   Bring::from_vec(buf_read_vec)
 }
 
-pub fn new() -> Arc<Shared> {
+// Locks a buffer on the daemon side, which must not panic because some app thread did.
+// A poisoned lock marks just this connection as closed; the daemon cleans it up on its next event.
+pub fn lock_buf<'a>(buf: &'a CondMutex<Bring>, status: &Status) -> CondMutexGuard<'a, Bring> {
+  let (guard, poisoned) = buf.lock_recover();
+  if poisoned && !status.is_poisoned() {
+    warn!("An app thread panicked while holding a connection buffer. Closing the connection.");
+    status.set_poisoned();
+  }
+  guard
+}
+
+pub fn new(hello: &[u8], max_payload: usize) -> Arc<Shared> {
   let buf_read = CondMutex::new(initial_read_ring_buf());
-  let buf_write = Mutex::new(initial_write_ring_buf());
+  let buf_write = CondMutex::new(initial_write_ring_buf(hello));
   let status = Status::new();
   let rtt_ms = AtomicU32::new(100);
   let loss_pct = AtomicU32::new(0);
-  Arc::new((buf_read, buf_write, status, netstat::Shared { rtt: rtt_ms, loss: loss_pct }))
+  Arc::new((buf_read, buf_write, status, netstat::Shared { rtt: rtt_ms, loss: loss_pct }, max_payload, Signal::new()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use super::*;
+
+  #[test]
+  fn lock_recovers_and_closes_on_poison() {
+    let shared = new(&[], 64);
+    let (ref buf_read, _, ref status, _, _, _) = *shared;
+    drop(lock_buf(buf_read, status));
+    assert!(status.is_open());
+
+    let poisoner = Arc::clone(&shared);
+    thread::spawn(move || {
+      let _lock = poisoner.0.lock().unwrap();
+      panic!("poisoning the read buffer");
+    }).join().unwrap_err();
+
+    let buf = lock_buf(buf_read, status);
+    assert_eq!(buf.count(), 0);
+    drop(buf);
+    assert!(status.is_poisoned());
+    assert!(status.is_closed());
+    assert_eq!(status.check_err().unwrap_err().kind(), std::io::ErrorKind::Other);
+  }
 }
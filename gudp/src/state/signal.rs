@@ -0,0 +1,191 @@
+use std::io;
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst as OSeqCst};
+use std::time::{Duration, Instant};
+
+use mio::{Interest, Registry, Token};
+
+#[cfg(unix)]
+use mio::net::UnixDatagram;
+
+// Readiness an app can wait on without blocking on one connection's condvar. Nothing is allocated or
+// signalled until an app asks, so connections nobody waits on this way pay only an atomic load.
+// The daemon signals when reads arrive, writes flush, a listener has connections to accept or anything closes.
+#[derive(Debug, Default)]
+pub struct Signal {
+  armed: AtomicBool,
+  target: Mutex<Option<Target>>
+}
+
+#[derive(Debug)]
+enum Target {
+  // A socket pair: the app's mio registry watches one end, and the daemon writes a byte to the other.
+  // NOTE: mio only permits one Waker per Poll, so a Waker per connection would not do
+  #[cfg(unix)]
+  Pair(UnixDatagram, UnixDatagram),
+  // Many signals share one Notify, each under its own key, so a ConnectionSet needs no fd per connection
+  Notify(Arc<Notify>, usize)
+}
+
+// The notification primitive shared by every member of a ConnectionSet: the keys signalled since it last waited
+#[derive(Debug, Default)]
+pub struct Notify {
+  ready: Mutex<HashSet<usize>>,
+  condvar: Condvar
+}
+
+impl Notify {
+  fn push(&self, key: usize) {
+    lock(&self.ready).insert(key);
+    self.condvar.notify_one();
+  }
+
+  // Takes every key signalled so far, waiting up to the timeout (or forever) for the first
+  pub fn wait(&self, timeout: Option<Duration>) -> Vec<usize> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut ready = lock(&self.ready);
+    while ready.is_empty() {
+      ready = match deadline {
+        None => self.condvar.wait(ready).unwrap_or_else(|poisoned| poisoned.into_inner()),
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline { break; }
+          self.condvar.wait_timeout(ready, deadline - now).unwrap_or_else(|poisoned| poisoned.into_inner()).0
+        }
+      };
+    }
+    ready.drain().collect()
+  }
+}
+
+impl Signal {
+  pub fn new() -> Signal {
+    Signal::default()
+  }
+
+  // Wakes the registry with the token whenever the daemon signals. Registers once, then signals straight away
+  // so anything that became ready beforehand is noticed
+  #[cfg(unix)]
+  pub fn register(&self, registry: &Registry, token: Token) -> io::Result<()> {
+    let mut target = lock(&self.target);
+    if !matches!(*target, Some(Target::Pair(..))) {
+      let (rx, tx) = UnixDatagram::pair()?;
+      *target = Some(Target::Pair(rx, tx));
+    }
+    if let Some(Target::Pair(ref mut rx, _)) = *target {
+      registry.register(rx, token, Interest::READABLE)?;
+    }
+    drop(target);
+
+    self.armed.store(true, OSeqCst);
+    self.signal();
+    Ok(())
+  }
+
+  #[cfg(not(unix))]
+  pub fn register(&self, _registry: &Registry, _token: Token) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Readiness registration is only supported on unix"))
+  }
+
+  pub fn deregister(&self, registry: &Registry) -> io::Result<()> {
+    match *lock(&self.target) {
+      #[cfg(unix)]
+      Some(Target::Pair(ref mut rx, _)) => {
+        self.armed.store(false, OSeqCst);
+        registry.deregister(rx)
+      },
+      _ => Ok(())
+    }
+  }
+
+  // Pushes the key to the shared notify whenever the daemon signals, starting with once straight away
+  pub fn attach(&self, notify: Arc<Notify>, key: usize) {
+    *lock(&self.target) = Some(Target::Notify(notify, key));
+    self.armed.store(true, OSeqCst);
+    self.signal();
+  }
+
+  pub fn detach(&self) {
+    self.armed.store(false, OSeqCst);
+    *lock(&self.target) = None;
+  }
+
+  // Best effort, and never blocks the daemon. At most one byte is ever left queued in a socket pair: every
+  // signal first drains the last, so each one still arrives as a fresh edge without the app ever reading it
+  pub fn signal(&self) {
+    if !self.armed.load(OSeqCst) { return; }
+
+    match *lock(&self.target) {
+      #[cfg(unix)]
+      Some(Target::Pair(ref rx, ref tx)) => {
+        let mut byte = [0u8; 1];
+        while rx.recv(&mut byte).is_ok() { }
+        tx.send(&byte).ok();
+      },
+      Some(Target::Notify(ref notify, key)) => notify.push(key),
+      None => { }
+    }
+  }
+}
+
+// Neither lock holds an invariant a panic could break
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[cfg(unix)]
+  #[test]
+  fn signals_each_edge_once_armed() {
+    use mio::{Events, Poll};
+
+    fn woken(poll: &mut Poll, events: &mut Events) -> bool {
+      poll.poll(events, Some(Duration::from_millis(10))).unwrap();
+      events.iter().any(|event| event.token() == Token(7))
+    }
+
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(8);
+    let signal = Signal::new();
+
+    // Unarmed signals go nowhere
+    signal.signal();
+    assert!(!signal.armed.load(OSeqCst));
+
+    // Registering wakes once, for anything already ready
+    signal.register(poll.registry(), Token(7)).unwrap();
+    assert!(woken(&mut poll, &mut events));
+    assert!(!woken(&mut poll, &mut events));
+
+    for _ in 0..3 {
+      signal.signal();
+      assert!(woken(&mut poll, &mut events));
+    }
+
+    signal.deregister(poll.registry()).unwrap();
+    signal.signal();
+    assert!(!woken(&mut poll, &mut events));
+  }
+
+  #[test]
+  fn notify_collects_keys() {
+    let notify = Arc::new(Notify::default());
+    let (a, b) = (Signal::new(), Signal::new());
+    assert!(notify.wait(Some(Duration::from_millis(1))).is_empty());
+
+    a.attach(Arc::clone(&notify), 1);
+    b.attach(Arc::clone(&notify), 2);
+    a.signal();
+    let mut keys = notify.wait(None);
+    keys.sort();
+    assert_eq!(keys, vec![1, 2]);
+
+    b.detach();
+    b.signal();
+    assert!(notify.wait(Some(Duration::from_millis(1))).is_empty());
+  }
+}
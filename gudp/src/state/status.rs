@@ -6,13 +6,14 @@
 /// Writes that change the connection from Closed->Open are disallowed.
 /// Therefore two clients may race to close a connection, but once a Closed connection is observed, no future writes will ever bring it back to Open.
 ///
-/// Similarly, the OS Error field is for fatal errors and will be set once and only once
+/// Similarly, the OS Error field is for fatal errors and will be set once and only once,
+/// as is the cause field recording why a hangup happened.
 
 use std::sync::atomic::{AtomicI32, AtomicU32};
 use std::sync::atomic::Ordering::SeqCst as OSeqCst;
 use std::io;
 
-use crate::error;
+use crate::error::{Error, Reason};
 
 // The app gracefully dropped their end of the connection
 // IO can still be flushed to the socket before the connection ends.
@@ -29,30 +30,49 @@ const FLAG_PEER_HUP: u32 = 1u32.rotate_right(2);
 // IO can still be flushed to the app before the connection ends.
 const FLAG_IO_ERR: u32 = 1u32.rotate_right(3);
 
+// The app dropped every handle able to receive, but may still be sending.
+// Reads from the peer no longer need to be buffered for the app.
+// This flag has no bearing on whether the connection is open or closed.
+const FLAG_APP_RECV_HUP: u32 = 1u32.rotate_right(5);
+
+// The daemon told the peer we hung up. Nothing further will be sent.
+const FLAG_FIN_SENT: u32 = 1u32.rotate_right(6);
+
+// An app thread panicked while holding one of the connection's buffer locks.
+// The buffers may be half-updated, so the daemon gives up on the connection.
+const FLAG_POISONED: u32 = 1u32.rotate_right(7);
+
+// The app has queued writes and already woke the daemon to flush them.
+// Cleared by the daemon right before it flushes, so further app writes only wake it again after that point.
+// This flag has no bearing on whether the connection is open or closed.
+const FLAG_WRITE_PENDING: u32 = 1u32.rotate_right(4);
+
 // The socket is in the closed state for any reason
 const FLAGS_CLOSED: u32 =
   FLAG_APP_HUP |
   FLAG_PEER_HUP |
-  FLAG_IO_ERR;
-
-// The socket was gracefully closed by either side
-const FLAGS_HUP: u32 =
-  FLAG_APP_HUP |
-  FLAG_PEER_HUP;
+  FLAG_IO_ERR |
+  FLAG_POISONED;
 
 const ERRNO_CLEAR: i32 = 0;
 
+// Hangup causes. Below 256, the peer's disconnect reason byte
+const CAUSE_CLEAR: u32 = u32::MAX;
+const CAUSE_SHUTDOWN: u32 = 0x100;
+
 #[derive(Debug)]
 pub struct Status {
   status: AtomicU32,
   errno: AtomicI32,
+  cause: AtomicU32,
 }
 
 impl Status {
   pub fn new() -> Status {
     Status {
       status: AtomicU32::new(0),
-      errno: AtomicI32::new(ERRNO_CLEAR)
+      errno: AtomicI32::new(ERRNO_CLEAR),
+      cause: AtomicU32::new(CAUSE_CLEAR)
     }
   }
 
@@ -62,12 +82,39 @@ impl Status {
     self.status.fetch_or(FLAG_APP_HUP, OSeqCst);
   }
 
+  // Indicate the daemon is shutting down, which hangs up on the app's behalf
+  pub fn set_shutdown(&self) {
+    self.set_cause(CAUSE_SHUTDOWN);
+    self.status.fetch_or(FLAG_APP_HUP, OSeqCst);
+  }
+
+  // Indicate the app has closed the receiving half of their connection end
+  pub fn set_app_recv_hup(&self) {
+    self.status.fetch_or(FLAG_APP_RECV_HUP, OSeqCst);
+  }
+
+  // Indicate the daemon has sent the peer its disconnect notice
+  pub fn set_fin_sent(&self) {
+    self.status.fetch_or(FLAG_FIN_SENT, OSeqCst);
+  }
+
   // Indicate the socket has gracefully closed their connection end
   pub fn set_peer_hup(&self) {
     // Set the io hangup flag and preserve the rest
     self.status.fetch_or(FLAG_PEER_HUP, OSeqCst);
   }
 
+  // Indicate the peer told us it hung up, and why
+  pub fn set_peer_disconnect(&self, reason: u8) {
+    self.set_cause(reason as u32);
+    self.status.fetch_or(FLAG_PEER_HUP, OSeqCst);
+  }
+
+  // Only the first cause sticks, like errno
+  fn set_cause(&self, cause: u32) {
+    self.cause.compare_exchange(CAUSE_CLEAR, cause, OSeqCst, OSeqCst).ok();
+  }
+
   // Indicate the socket encountered a fatal error.
   // NOTE: The sequencing here is important
   pub fn set_io_err(&self, err: Option<i32>) {
@@ -82,6 +129,22 @@ impl Status {
     self.status.fetch_or(FLAG_IO_ERR, OSeqCst);
   }
 
+  // Indicate a buffer lock was found poisoned
+  pub fn set_poisoned(&self) {
+    self.status.fetch_or(FLAG_POISONED, OSeqCst);
+  }
+
+  // Indicate the app has writes awaiting a flush.
+  // Returns true only if no flush was already pending, meaning the daemon needs a wakeup.
+  pub fn set_write_pending(&self) -> bool {
+    (self.status.fetch_or(FLAG_WRITE_PENDING, OSeqCst) & FLAG_WRITE_PENDING) == 0
+  }
+
+  // Indicate the daemon is about to flush all pending writes
+  pub fn clear_write_pending(&self) {
+    self.status.fetch_and(!FLAG_WRITE_PENDING, OSeqCst);
+  }
+
   // Will check various flags to determine if the connection qualifes as closed
   // NOTE: Once connections are considered closed, they will never unclose.
   // So if is_closed() == true, you know no races will occur to unclose the conn.
@@ -97,33 +160,59 @@ impl Status {
     (self.status.load(OSeqCst) & FLAG_APP_HUP) != 0
   }
 
+  pub fn app_recv_has_hup(&self) -> bool {
+    (self.status.load(OSeqCst) & FLAG_APP_RECV_HUP) != 0
+  }
+
+  pub fn is_poisoned(&self) -> bool {
+    (self.status.load(OSeqCst) & FLAG_POISONED) != 0
+  }
+
   pub fn peer_has_hup(&self) -> bool {
     (self.status.load(OSeqCst) & FLAG_PEER_HUP) != 0
   }
 
+  // True once the daemon is finished with the connection: either the disconnect notice went out,
+  // or the connection ended from the socket side first.
+  pub fn is_finished(&self) -> bool {
+    (self.status.load(OSeqCst) & (FLAG_FIN_SENT | FLAG_PEER_HUP | FLAG_IO_ERR | FLAG_POISONED)) != 0
+  }
+
   pub fn check_err(&self) -> io::Result<()> {
-    let status = self.status.load(OSeqCst);
-    self.check_flag_io_err(status)?;
-    self.check_flags_hup(status)?;
-    Ok(())
+    match self.close_reason() {
+      Some(e) => Err(e.into()),
+      None => Ok(())
+    }
   }
 
-  fn check_flag_io_err(&self, status: u32) -> io::Result<()> {
+  // Why the connection closed, or None while it's open.
+  // Errors win over hangups, and the peer's side of a hangup wins over ours
+  pub fn close_reason(&self) -> Option<Error> {
+    let status = self.status.load(OSeqCst);
+    if (status & FLAG_POISONED) != 0 { return Some(Error::Poisoned); }
+
     if (status & FLAG_IO_ERR) != 0 {
-      match self.errno.load(OSeqCst) {
-        ERRNO_CLEAR => Err(error::unknown()),
-        e => Err(io::Error::from_raw_os_error(e))
-      }
-    } else {
-      Ok(())
+      return match self.errno.load(OSeqCst) {
+        ERRNO_CLEAR => Some(Error::Unknown),
+        errno => Some(Error::Io(errno))
+      };
+    }
+
+    let cause = self.cause.load(OSeqCst);
+    if (status & FLAG_PEER_HUP) != 0 {
+      return match cause {
+        CAUSE_CLEAR | CAUSE_SHUTDOWN => Some(Error::PeerTimedOut),
+        reason => Some(Error::PeerDisconnected(Reason::from(reason as u8)))
+      };
     }
-  }
 
-  fn check_flags_hup(&self, status: u32) -> io::Result<()> {
-    if (status & FLAGS_HUP) != 0 {
-      Err(error::use_after_hup())
-    } else {
-      Ok(())
+    if (status & FLAG_APP_HUP) != 0 {
+      return match cause {
+        CAUSE_SHUTDOWN => Some(Error::DaemonGone),
+        _ => Some(Error::LocalClosed)
+      };
     }
+
+    None
   }
 }
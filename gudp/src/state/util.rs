@@ -1,10 +1,56 @@
-use crate::state::State;
+use std::time::Instant;
+
+use crate::state::{State, Deps, lock_buf};
 
 impl State {
+  // Returns false if an inbound datagram of this size is over the connection's rate limit
+  pub fn take_inbound(&mut self, now: Instant, size: usize) -> bool {
+    self.rate_limit.as_mut()
+      .map(|buckets| buckets.take(now, size))
+      .unwrap_or(true)
+  }
+
+  // The connection is being torn down; its timers would only fire for nothing
+  pub fn cancel_timers<D: Deps>(&mut self, deps: &mut D) {
+    self.armed.cancel(deps.timers(), self.socket_id);
+  }
+
   pub fn on_io_error(&self, errno: Option<i32>) {
-    let (ref buf_read, ref _buf_write, ref status, _) = *self.shared;
-    let lock = buf_read.lock().expect("Could not acquire unpoisoned read lock");
+    let (ref buf_read, ref _buf_write, ref status, _, _, _) = *self.shared;
+    let lock = lock_buf(buf_read, status);
     status.set_io_err(errno);
     lock.notify_all();
+    drop(lock);
+    self.notify_write_waiters();
+  }
+
+  // The daemon is shutting down. Like an app hangup, queued writes are still flushed before the peer is told
+  pub fn on_shutdown(&self) {
+    let (ref buf_read, _, ref status, _, _, ref readiness) = *self.shared;
+    let lock = lock_buf(buf_read, status);
+    status.set_shutdown();
+    lock.notify_all();
+    drop(lock);
+    readiness.signal();
+  }
+
+  // The daemon gave up on flushing; nothing more will be sent
+  pub fn on_abandoned(&self) {
+    let (ref buf_read, _, ref status, _, _, _) = *self.shared;
+    let lock = lock_buf(buf_read, status);
+    status.set_fin_sent();
+    lock.notify_all();
+    drop(lock);
+    self.notify_write_waiters();
+  }
+
+  // Wakes app threads waiting on the write buffer (see Connection::flush and close)
+  // so they can observe a drained buffer or a changed status. Also wakes any app event loop registered for it
+  pub fn notify_write_waiters(&self) {
+    let (_, ref buf_write, ref status, _, _, ref readiness) = *self.shared;
+    let lock = lock_buf(buf_write, status);
+    lock.notify_all();
+    drop(lock);
+    readiness.signal();
   }
 }
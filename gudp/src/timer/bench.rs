@@ -0,0 +1,60 @@
+// Timer upkeep as the daemon does it, with every connection re-arming a heartbeat each second and a timeout
+// every fifteen, stepped a millisecond at a time. Only built with the bench feature; run them with
+//   cargo test --release -p gudp --features bench timer::bench -- --nocapture
+use std::time::{Duration, Instant};
+
+use crate::constants::time_ms;
+use crate::timer::{self, Timers, TimerKind};
+use crate::timer::list::TimerList;
+
+const STEP: Duration = Duration::from_millis(1);
+
+// Returns how many timers fired
+fn run<W>(timers: &mut W, start: Instant, connections: usize, span: Duration) -> usize
+where W: for<'a> Timers<'a, Item = (usize, TimerKind)> {
+  // Connections arrive spread over the first heartbeat, as they would under load
+  for conn in 0..connections {
+    let joined = start + time_ms::HEARTBEAT * conn as u32 / connections as u32;
+    timers.add((conn, TimerKind::Heartbeat), joined + time_ms::HEARTBEAT);
+    timers.add((conn, TimerKind::Timeout), joined + time_ms::TIMEOUT);
+  }
+
+  let mut fired = 0;
+  let mut expired = Vec::with_capacity(connections);
+  let mut now = start;
+  while now < start + span {
+    now += STEP;
+    expired.extend(timers.expire(now));
+    fired += expired.len();
+    for (conn, kind) in expired.drain(..) {
+      let period = if kind == TimerKind::Heartbeat { time_ms::HEARTBEAT } else { time_ms::TIMEOUT };
+      timers.add((conn, kind), now + period);
+    }
+  }
+  fired
+}
+
+fn bench<W>(name: &str, mut timers: W, start: Instant, connections: usize, span: Duration)
+where W: for<'a> Timers<'a, Item = (usize, TimerKind)> {
+  let began = Instant::now();
+  let fired = run(&mut timers, start, connections, span);
+  let took = began.elapsed();
+  println!(
+    "{} x {} connections over {:?}: {} fired in {:?} ({:?} per timer)",
+    name, connections, span, fired, took, took / fired.max(1) as u32
+  );
+}
+
+#[test]
+fn bench_10k() {
+  let start = Instant::now();
+  bench("list", TimerList::new(), start, 10_000, Duration::from_secs(5));
+  bench("wheel", timer::Wheel::new(start), start, 10_000, Duration::from_secs(5));
+}
+
+#[test]
+fn bench_100k() {
+  let start = Instant::now();
+  bench("list", TimerList::new(), start, 100_000, Duration::from_secs(2));
+  bench("wheel", timer::Wheel::new(start), start, 100_000, Duration::from_secs(2));
+}
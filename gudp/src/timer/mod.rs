@@ -1,8 +1,14 @@
+use std::hash::Hash;
 use std::time::Instant;
 
+// The sorted list the wheel replaced, kept to benchmark against.
+// Both are only built with the bench feature, so the lib's tests don't carry them
+#[cfg(all(test, feature = "bench"))]
 mod list;
-pub use list::TimerList as List;
-pub use list::Expired as Expired;
+#[cfg(all(test, feature = "bench"))]
+mod bench;
+mod wheel;
+pub use wheel::TimerWheel as Wheel;
 
 pub trait Timers<'a> {
   type Item: PartialEq + PartialOrd + Copy;
@@ -16,26 +22,62 @@ pub trait Timers<'a> {
   fn expire(&'a mut self, now: Instant) -> Self::Expired;
 }
 
-#[derive(Copy, Clone, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum TimerKind {
   Heartbeat,
-  Timeout
+  Timeout,
+  Handshake
 }
 
-impl PartialEq for TimerKind {
-  fn eq(&self, other: &TimerKind) -> bool {
-    (*self) as i32 == (*other) as i32
-  }
+impl TimerKind {
+  // Every kind, in the order of their discriminants
+  pub const ALL: [TimerKind; 3] = [TimerKind::Heartbeat, TimerKind::Timeout, TimerKind::Handshake];
 }
 
-impl PartialOrd for TimerKind {
-  fn partial_cmp(&self, other: &TimerKind) -> Option<std::cmp::Ordering> {
-    Some(self.cmp(other))
+// When each kind of one connection's timers is due, while it waits in the wheel, so it can be replaced or cancelled
+#[derive(Debug, Default)]
+pub struct Armed([Option<Instant>; 3]);
+
+impl Armed {
+  // Arms the kind of timer, in place of any already armed
+  pub fn arm<I>(&mut self, timers: &mut Wheel<(I, TimerKind)>, id: I, kind: TimerKind, when: Instant)
+  where I: Hash + Eq + PartialOrd + Copy {
+    if let Some(armed) = self.0[kind as usize].replace(when) { timers.remove((id, kind), armed); }
+    timers.add((id, kind), when);
+  }
+
+  // The kind of timer fired, which takes it out of the wheel
+  pub fn fired(&mut self, kind: TimerKind) {
+    self.0[kind as usize] = None;
+  }
+
+  // Removes every timer still armed, once the connection is gone
+  pub fn cancel<I>(&mut self, timers: &mut Wheel<(I, TimerKind)>, id: I)
+  where I: Hash + Eq + PartialOrd + Copy {
+    for kind in TimerKind::ALL.iter() {
+      if let Some(armed) = self.0[*kind as usize].take() { timers.remove((id, *kind), armed); }
+    }
   }
 }
 
-impl Ord for TimerKind {
-  fn cmp(&self, other: &TimerKind) -> std::cmp::Ordering {
-    ((*self) as i32).cmp(&((*other) as i32))
+#[cfg(test)]
+mod tests {
+  use super::{Armed, TimerKind, Timers, Wheel};
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn rearming_replaces_and_cancel_removes() {
+    let start = Instant::now();
+    let mut timers: Wheel<(usize, TimerKind)> = Wheel::new(start);
+    let mut armed = Armed::default();
+    armed.arm(&mut timers, 0, TimerKind::Timeout, start + Duration::from_millis(10));
+    armed.arm(&mut timers, 0, TimerKind::Timeout, start + Duration::from_millis(20));
+    armed.arm(&mut timers, 0, TimerKind::Heartbeat, start + Duration::from_millis(30));
+    assert_eq!(timers.len(), 2);
+    assert_eq!(timers.expire(start + Duration::from_millis(15)).count(), 0);
+
+    armed.cancel(&mut timers, 0);
+    assert!(timers.is_empty());
+    assert_eq!(timers.expire(start + Duration::from_millis(100)).count(), 0);
   }
 }
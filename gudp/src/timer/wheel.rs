@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use super::Timers;
+
+// Each level is a ring of 64 slots, each slot spanning 64 times the ticks of a slot on the level below.
+// Level 0 slots are a single millisecond tick; six levels reach out a little over two years.
+// Timers any further out wait on an overflow list until the wheel turns that far.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+const OVERFLOW: usize = LEVELS * SLOTS;
+
+#[derive(Debug)]
+struct Entry<T> {
+  what: T,
+  when: Instant,
+  tick: u64,
+  list: usize,
+  prev: Option<usize>,
+  next: Option<usize>
+}
+
+// A slot (or the overflow list) that is next to come due, and the tick it does
+struct Expiration {
+  list: usize,
+  deadline: u64
+}
+
+/// A hierarchical timing wheel. Adding and removing a timer are O(1), and each timer cascades down
+/// through at most one slot per level on its way to expiring, rather than every timer shifting on every add.
+///
+/// Time is kept in whole millisecond ticks since the wheel was made. A timer never fires early,
+/// but may fire up to a tick late.
+#[derive(Debug)]
+pub struct TimerWheel<T: Hash + Eq + Copy> {
+  start: Instant,
+  // The tick every slot up to has been expired, and which the slots are placed relative to
+  elapsed: u64,
+  // One bit per non-empty slot, per level
+  occupied: [u64; LEVELS],
+  // Each slot's list of entries, then the overflow list, linked through the entries themselves
+  heads: Vec<Option<usize>>,
+  entries: Vec<Option<Entry<T>>>,
+  free: Vec<usize>,
+  index: HashMap<(T, Instant), usize>,
+  fired: Vec<T>
+}
+
+impl<T: Hash + Eq + Copy> TimerWheel<T> {
+  // Times before `start` are all due straight away
+  pub fn new(start: Instant) -> TimerWheel<T> {
+    TimerWheel {
+      start,
+      elapsed: 0,
+      occupied: [0; LEVELS],
+      heads: vec![None; OVERFLOW + 1],
+      entries: vec![],
+      free: vec![],
+      index: HashMap::new(),
+      fired: vec![]
+    }
+  }
+
+  #[cfg(test)]
+  pub fn len(&self) -> usize {
+    self.index.len()
+  }
+
+  #[cfg(test)]
+  pub fn is_empty(&self) -> bool {
+    self.index.is_empty()
+  }
+
+  // Rounds up, so a timer is never due before its time
+  fn tick_at(&self, when: Instant) -> u64 {
+    let since = when.saturating_duration_since(self.start);
+    let ms = since.as_millis() as u64;
+    if since > Duration::from_millis(ms) { ms + 1 } else { ms }
+  }
+
+  // Rounds down, so only timers whose time has come are due
+  fn tick_now(&self, now: Instant) -> u64 {
+    now.saturating_duration_since(self.start).as_millis() as u64
+  }
+
+  // Which list an entry due at the tick belongs on. The level is the highest 6 bit group the tick differs from elapsed in
+  fn list_for(&self, tick: u64) -> usize {
+    let masked = (self.elapsed ^ tick) | (SLOTS as u64 - 1);
+    let level = (63 - masked.leading_zeros()) / SLOT_BITS;
+    if level as usize >= LEVELS { return OVERFLOW; }
+    let slot = (tick >> (level * SLOT_BITS)) as usize % SLOTS;
+    level as usize * SLOTS + slot
+  }
+
+  fn link(&mut self, idx: usize) {
+    let tick = self.entries[idx].as_ref().map_or(self.elapsed, |entry| entry.tick);
+    let list = self.list_for(tick);
+    let head = self.heads[list].replace(idx);
+    if let Some(head) = head {
+      if let Some(ref mut next) = self.entries[head] { next.prev = Some(idx); }
+    }
+    if let Some(ref mut entry) = self.entries[idx] {
+      entry.list = list;
+      entry.prev = None;
+      entry.next = head;
+    }
+    if list != OVERFLOW { self.occupied[list / SLOTS] |= 1 << (list % SLOTS); }
+  }
+
+  fn unlink(&mut self, idx: usize) {
+    let (list, prev, next) = match self.entries[idx] {
+      Some(ref entry) => (entry.list, entry.prev, entry.next),
+      None => return
+    };
+    match prev {
+      Some(prev) => if let Some(ref mut entry) = self.entries[prev] { entry.next = next; },
+      None => self.heads[list] = next
+    }
+    if let Some(next) = next {
+      if let Some(ref mut entry) = self.entries[next] { entry.prev = prev; }
+    }
+    if list != OVERFLOW && self.heads[list].is_none() {
+      self.occupied[list / SLOTS] &= !(1 << (list % SLOTS));
+    }
+  }
+
+  // Detaches a whole list, returning its entries
+  fn take_list(&mut self, list: usize) -> Vec<usize> {
+    let mut taken = vec![];
+    let mut cursor = self.heads[list].take();
+    while let Some(idx) = cursor {
+      taken.push(idx);
+      cursor = self.entries[idx].as_ref().and_then(|entry| entry.next);
+    }
+    if list != OVERFLOW { self.occupied[list / SLOTS] &= !(1 << (list % SLOTS)); }
+    taken
+  }
+
+  // Lower levels always come due first, since every entry on a level lies in a later slot of the level above
+  fn next_expiration(&self) -> Option<Expiration> {
+    for level in 0..LEVELS {
+      let occupied = self.occupied[level];
+      if occupied == 0 { continue; }
+
+      let shift = level as u32 * SLOT_BITS;
+      let slot_range = 1u64 << shift;
+      let level_range = slot_range << SLOT_BITS;
+      let now_slot = (self.elapsed >> shift) % SLOTS as u64;
+      let slot = (now_slot + occupied.rotate_right(now_slot as u32).trailing_zeros() as u64) % SLOTS as u64;
+
+      let level_start = self.elapsed & !(level_range - 1);
+      let mut deadline = level_start + slot * slot_range;
+      if deadline < self.elapsed { deadline += level_range; }
+      return Some(Expiration { list: level * SLOTS + slot as usize, deadline });
+    }
+
+    // Only once the wheel is empty can anything on the overflow list be next
+    let mut cursor = self.heads[OVERFLOW];
+    let mut deadline = None;
+    while let Some(idx) = cursor {
+      let entry = self.entries[idx].as_ref()?;
+      deadline = Some(deadline.map_or(entry.tick, |deadline: u64| deadline.min(entry.tick)));
+      cursor = entry.next;
+    }
+    deadline.map(|deadline| Expiration { list: OVERFLOW, deadline })
+  }
+
+  fn release(&mut self, idx: usize) -> Option<Entry<T>> {
+    let entry = self.entries[idx].take()?;
+    self.index.remove(&(entry.what, entry.when));
+    self.free.push(idx);
+    Some(entry)
+  }
+}
+
+impl<'a, T> Timers<'a> for TimerWheel<T>
+where T: 'a + Hash + Eq + PartialOrd + Copy {
+  type Item = T;
+  type Expired = std::vec::Drain<'a, T>;
+
+  fn add(&mut self, what: T, when: Instant) {
+    if self.index.contains_key(&(what, when)) { return; }
+
+    let tick = u64::max(self.tick_at(when), self.elapsed);
+    let entry = Entry { what, when, tick, list: 0, prev: None, next: None };
+    let idx = match self.free.pop() {
+      Some(idx) => { self.entries[idx] = Some(entry); idx },
+      None => { self.entries.push(Some(entry)); self.entries.len() - 1 }
+    };
+    self.index.insert((what, when), idx);
+    self.link(idx);
+  }
+
+  fn remove(&mut self, what: T, when: Instant) {
+    if let Some(&idx) = self.index.get(&(what, when)) {
+      self.unlink(idx);
+      self.release(idx);
+    }
+  }
+
+  // May be earlier than the soonest timer, when that timer has yet to cascade down to the bottom level.
+  // Expiring then only cascades it, after which this is closer
+  fn when_next(&self) -> Option<Instant> {
+    self.next_expiration().map(|expiration| self.start + Duration::from_millis(expiration.deadline))
+  }
+
+  fn expire(&mut self, now: Instant) -> std::vec::Drain<'_, T> {
+    let now_tick = self.tick_now(now);
+
+    while let Some(expiration) = self.next_expiration() {
+      if expiration.deadline > now_tick { break; }
+      self.elapsed = expiration.deadline;
+
+      // A bottom slot is a single tick, so all of it is due. Anything else moves closer to the bottom
+      let due = expiration.list < SLOTS;
+      for idx in self.take_list(expiration.list) {
+        if due {
+          if let Some(entry) = self.release(idx) { self.fired.push(entry.what); }
+        } else {
+          self.link(idx);
+        }
+      }
+    }
+
+    // Moving into the next turn of the top level brings the overflow list in range again
+    let top = |tick: u64| tick >> (LEVELS as u32 * SLOT_BITS);
+    let turned = top(now_tick) > top(self.elapsed);
+    self.elapsed = u64::max(self.elapsed, now_tick);
+    if turned {
+      for idx in self.take_list(OVERFLOW) { self.link(idx); }
+    }
+    self.fired.drain(..)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::timer::Timers;
+  use super::TimerWheel;
+  use std::time::{Duration, Instant};
+
+  fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+  }
+
+  #[test]
+  fn empty_wheel() {
+    let timers: TimerWheel<usize> = TimerWheel::new(Instant::now());
+    assert_eq!(timers.when_next(), None);
+  }
+
+  #[test]
+  fn fires_in_order_and_never_early() {
+    let start = Instant::now();
+    let mut timers: TimerWheel<usize> = TimerWheel::new(start);
+    // Spread across the bottom three levels, and a sub-millisecond time that rounds up
+    let at = [ms(3), ms(70), ms(70), ms(5_000), Duration::from_micros(9_500)];
+    for (what, when) in at.iter().enumerate() {
+      timers.add(what, start + *when);
+    }
+    timers.add(1, start + ms(70)); // Intentional repeat is ignored
+    assert_eq!(timers.len(), 5);
+
+    assert_eq!(timers.expire(start + ms(2)).count(), 0);
+    assert_eq!(timers.expire(start + ms(9)).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(timers.expire(start + ms(10)).collect::<Vec<_>>(), vec![4]);
+    let mut expired: Vec<usize> = timers.expire(start + ms(4_999)).collect();
+    expired.sort();
+    assert_eq!(expired, vec![1, 2]);
+
+    // The last timer waits on the second level; expiring at each reported deadline cascades it down until it fires
+    let mut steps = 0;
+    loop {
+      let next = timers.when_next().expect("Lost a timer");
+      assert!(next <= start + ms(5_000));
+      if !timers.expire(next).collect::<Vec<_>>().is_empty() { break; }
+      steps += 1;
+      assert!(steps < 4, "Never cascaded down");
+    }
+    assert!(timers.is_empty());
+    assert_eq!(timers.when_next(), None);
+  }
+
+  #[test]
+  fn removes_without_leftovers() {
+    let start = Instant::now();
+    let mut timers: TimerWheel<usize> = TimerWheel::new(start);
+    for what in 0..100 {
+      timers.add(what, start + ms(what as u64 * 50));
+    }
+    for what in (0..100).filter(|what| what % 2 == 0) {
+      timers.remove(what, start + ms(what as u64 * 50));
+    }
+    timers.remove(1, start + ms(1)); // Not there, so nothing happens
+    assert_eq!(timers.len(), 50);
+
+    let expired: Vec<usize> = timers.expire(start + ms(10_000)).collect();
+    assert_eq!(expired.len(), 50);
+    assert!(expired.iter().all(|what| what % 2 == 1));
+    assert!(timers.is_empty());
+  }
+
+  #[test]
+  fn past_and_distant_timers() {
+    let start = Instant::now();
+    let mut timers: TimerWheel<usize> = TimerWheel::new(start + ms(100));
+    timers.add(0, start);
+    let distant = start + Duration::from_secs(60 * 60 * 24 * 365 * 3);
+    timers.add(1, distant);
+
+    assert_eq!(timers.expire(start + ms(100)).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(timers.when_next(), Some(distant));
+    assert_eq!(timers.expire(distant).collect::<Vec<_>>(), vec![1]);
+  }
+}
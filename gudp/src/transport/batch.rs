@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+
+use super::{Transport, Output};
+
+/// Buffers a transport receives a batch of datagrams into, handed out one at a time.
+/// A buffer may hold several datagrams from one sender back to back, all the same size but the last,
+/// as when the kernel coalesces a burst (UDP GRO).
+#[derive(Debug)]
+pub struct Inbox {
+  bufs: Vec<Vec<u8>>,
+  // For each filled buffer, in order: which it is, its length, sender and the size of the datagrams within it
+  filled: Vec<(usize, usize, SocketAddr, usize)>,
+  // The next datagram to hand out: which filled buffer, and where in it
+  next: (usize, usize)
+}
+
+impl Inbox {
+  pub fn new(count: usize, size: usize) -> Inbox {
+    Inbox { bufs: vec![vec![0u8; size]; usize::max(count, 1)], filled: vec![], next: (0, 0) }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.next.0 >= self.filled.len()
+  }
+
+  pub fn clear(&mut self) {
+    self.filled.clear();
+    self.next = (0, 0);
+  }
+
+  // Every buffer, to receive into. Only valid to fill while the inbox is empty
+  pub fn bufs_mut(&mut self) -> &mut [Vec<u8>] {
+    &mut self.bufs
+  }
+
+  // Marks a buffer as holding this many bytes from the sender, cut into datagrams of the segment size.
+  // A segment size of zero, or of the whole length, is a single datagram. Buffers are handed out in the order filled
+  pub fn fill(&mut self, buf: usize, len: usize, addr: SocketAddr, segment: usize) {
+    let segment = if segment == 0 { len } else { segment };
+    self.filled.push((buf, len, addr, segment));
+  }
+
+  // Copies out the next datagram, which as with recv_from is truncated to fit
+  pub fn pop(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    let (idx, start) = self.next;
+    let (filled, len, addr, segment) = *self.filled.get(idx)?;
+    let end = usize::min(start + segment, len);
+    let size = usize::min(end - start, buf.len());
+    buf[..size].copy_from_slice(&self.bufs[filled][start..start + size]);
+
+    self.next = if end >= len { (idx + 1, 0) } else { (idx, end) };
+    if self.is_empty() { self.clear(); }
+    Some((size, addr))
+  }
+}
+
+/// Datagrams waiting to go out through one socket, packed back to back in the order they were sent
+#[derive(Debug, Default)]
+pub struct Outbox {
+  data: Vec<u8>,
+  datagrams: Vec<(SocketAddr, Range<usize>)>,
+  // How many from the front have gone already
+  sent: usize
+}
+
+impl Outbox {
+  pub fn len(&self) -> usize {
+    self.datagrams.len() - self.sent
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // The datagrams yet to go, oldest first
+  pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+    self.datagrams[self.sent..].iter().map(move |(addr, range)| (&self.data[range.clone()], *addr))
+  }
+
+  // Like iter, as ranges of the packed data. Consecutive datagrams are contiguous within it
+  pub(crate) fn unsent(&self) -> (&[u8], &[(SocketAddr, Range<usize>)]) {
+    (&self.data, &self.datagrams[self.sent..])
+  }
+
+  pub(crate) fn push(&mut self, buf: &[u8], addr: SocketAddr) {
+    let start = self.data.len();
+    self.data.extend_from_slice(buf);
+    self.datagrams.push((addr, start..self.data.len()));
+  }
+
+  // Sends until empty. Fails with WouldBlock if the transport won't take the rest yet, which stay queued
+  pub(crate) fn flush<T: Transport>(&mut self, io: &T) -> io::Result<()> {
+    while !self.is_empty() {
+      let sent = io.send_batch(self)?;
+      if sent == 0 { return Err(io::ErrorKind::WouldBlock.into()); }
+      self.sent += usize::min(sent, self.len());
+    }
+    self.data.clear();
+    self.datagrams.clear();
+    self.sent = 0;
+    Ok(())
+  }
+}
+
+// A socket's transport, with sends collecting in its outbox so the daemon can flush a run of writes in one batch.
+// Once a full batch is waiting it goes out first, and sends fail with WouldBlock while the transport won't take it
+pub struct Batched<'a, T: Transport> {
+  io: &'a T,
+  outbox: RefCell<&'a mut Outbox>,
+  size: usize
+}
+
+impl<'a, T: Transport> Batched<'a, T> {
+  pub fn new(io: &'a T, outbox: &'a mut Outbox, size: usize) -> Batched<'a, T> {
+    Batched { io, outbox: RefCell::new(outbox), size: usize::max(size, 1) }
+  }
+
+  pub fn flush(self) -> io::Result<()> {
+    self.outbox.into_inner().flush(self.io)
+  }
+}
+
+impl<'a, T: Transport> Output for Batched<'a, T> {
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    let mut outbox = self.outbox.borrow_mut();
+    if outbox.len() >= self.size { outbox.flush(self.io)?; }
+    outbox.push(buf, addr);
+    Ok(buf.len())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::SocketAddr;
+
+  use super::{Inbox, Outbox};
+
+  fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  #[test]
+  fn inbox_splits_segments() {
+    let mut inbox = Inbox::new(2, 16);
+    assert!(inbox.is_empty());
+    inbox.bufs_mut()[0][..10].copy_from_slice(b"aaaabbbbcc");
+    inbox.fill(0, 10, addr(1), 4);
+    inbox.bufs_mut()[1][..3].copy_from_slice(b"ddd");
+    inbox.fill(1, 3, addr(2), 0);
+
+    let mut buf = [0u8; 3];
+    let mut popped = vec![];
+    while let Some((size, from)) = inbox.pop(&mut buf) {
+      popped.push((buf[..size].to_vec(), from.port()));
+    }
+    // Datagrams too large for the buffer are truncated to fit
+    assert_eq!(popped, vec![(b"aaa".to_vec(), 1), (b"bbb".to_vec(), 1), (b"cc".to_vec(), 1), (b"ddd".to_vec(), 2)]);
+    assert!(inbox.is_empty());
+  }
+
+  #[test]
+  fn outbox_keeps_order() {
+    let mut outbox = Outbox::default();
+    outbox.push(b"one", addr(1));
+    outbox.push(b"two", addr(2));
+    assert_eq!(outbox.len(), 2);
+    let queued: Vec<(&[u8], SocketAddr)> = outbox.iter().collect();
+    assert_eq!(queued, vec![(&b"one"[..], addr(1)), (&b"two"[..], addr(2))]);
+
+    let (data, datagrams) = outbox.unsent();
+    assert_eq!(&data[datagrams[0].1.start..datagrams[1].1.end], b"onetwo");
+  }
+}
@@ -0,0 +1,268 @@
+// Batched UDP syscalls for Linux: recvmmsg and sendmmsg, and optionally segmentation offload,
+// where one large send goes out as a run of equal datagrams (UDP_SEGMENT) and a run received coalesces into one (UDP_GRO)
+
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use super::{Inbox, Outbox};
+
+// Most datagrams a single call moves
+pub const MAX_BATCH: usize = 64;
+
+// Most datagrams the kernel will cut one segmented send into, and the most bytes it may carry
+const MAX_SEGMENTS: usize = 64;
+const MAX_SEGMENTED_BYTES: usize = 65_000;
+
+// Large enough for any one coalesced receive
+pub const GRO_BUF_SIZE: usize = 1 << 16;
+
+// Room for one cmsg carrying a u16 or i32, suitably aligned
+type Cmsg = [u64; 4];
+
+// Checks the kernel knows segmented sends, then turns on receive coalescing
+pub fn enable_offload(fd: RawFd) -> io::Result<()> {
+  let mut segment: libc::c_int = 0;
+  let mut len = mem::size_of_val(&segment) as libc::socklen_t;
+  let res = unsafe {
+    libc::getsockopt(fd, libc::SOL_UDP, libc::UDP_SEGMENT, &mut segment as *mut _ as *mut libc::c_void, &mut len)
+  };
+  if res < 0 { return Err(io::Error::last_os_error()); }
+
+  let on: libc::c_int = 1;
+  let res = unsafe {
+    libc::setsockopt(fd, libc::SOL_UDP, libc::UDP_GRO, &on as *const _ as *const libc::c_void, mem::size_of_val(&on) as libc::socklen_t)
+  };
+  if res < 0 { return Err(io::Error::last_os_error()); }
+  Ok(())
+}
+
+pub fn recv(fd: RawFd, inbox: &mut Inbox, gro: bool) -> io::Result<usize> {
+  let bufs = inbox.bufs_mut();
+  let count = usize::min(bufs.len(), MAX_BATCH);
+
+  let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+  let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+  let mut cmsgs: [Cmsg; MAX_BATCH] = [[0; 4]; MAX_BATCH];
+  let mut hdrs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+  for i in 0..count {
+    iovecs[i] = libc::iovec { iov_base: bufs[i].as_mut_ptr() as *mut libc::c_void, iov_len: bufs[i].len() };
+    let hdr = &mut hdrs[i].msg_hdr;
+    hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+    hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    hdr.msg_iov = &mut iovecs[i];
+    hdr.msg_iovlen = 1;
+    if gro {
+      hdr.msg_control = cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+      hdr.msg_controllen = mem::size_of::<Cmsg>() as _;
+    }
+  }
+
+  // Never waits on more than the first, even should the socket block
+  let received = unsafe { libc::recvmmsg(fd, hdrs.as_mut_ptr(), count as libc::c_uint, libc::MSG_WAITFORONE, ptr::null_mut()) };
+  if received < 0 { return Err(io::Error::last_os_error()); }
+
+  for i in 0..received as usize {
+    // Anything but IP is not from a peer
+    let addr = match to_socket_addr(&addrs[i]) { Some(addr) => addr, None => continue };
+    let len = usize::min(hdrs[i].msg_len as usize, iovecs[i].iov_len);
+    let segment = if gro { segment_size(&hdrs[i].msg_hdr).unwrap_or(0) } else { 0 };
+    inbox.fill(i, len, addr, segment);
+  }
+  Ok(received as usize)
+}
+
+fn segment_size(hdr: &libc::msghdr) -> Option<usize> {
+  unsafe {
+    let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+    while !cmsg.is_null() {
+      if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+        let segment = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+        return Some(segment as usize);
+      }
+      cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+    }
+  }
+  None
+}
+
+// Returns how many of the outbox's datagrams went. With gso, runs of same sized datagrams to one peer go as a single message
+pub fn send(fd: RawFd, outbox: &Outbox, gso: bool) -> io::Result<usize> {
+  let (data, datagrams) = outbox.unsent();
+
+  let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+  let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+  let mut cmsgs: [Cmsg; MAX_BATCH] = [[0; 4]; MAX_BATCH];
+  let mut hdrs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+  // How many datagrams each message carries
+  let mut runs = [0usize; MAX_BATCH];
+
+  let mut count = 0;
+  let mut next = 0;
+  while next < datagrams.len() && count < MAX_BATCH {
+    let (addr, ref first) = datagrams[next];
+    let run = if gso { run_len(&datagrams[next..]) } else { 1 };
+    let range = first.start..datagrams[next + run - 1].1.end;
+
+    iovecs[count] = libc::iovec { iov_base: data[range.clone()].as_ptr() as *mut libc::c_void, iov_len: range.len() };
+    let hdr = &mut hdrs[count].msg_hdr;
+    hdr.msg_name = &mut addrs[count] as *mut _ as *mut libc::c_void;
+    hdr.msg_namelen = from_socket_addr(addr, &mut addrs[count]);
+    hdr.msg_iov = &mut iovecs[count];
+    hdr.msg_iovlen = 1;
+    if run > 1 {
+      hdr.msg_control = cmsgs[count].as_mut_ptr() as *mut libc::c_void;
+      hdr.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+      unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(hdr);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, first.len() as u16);
+      }
+    }
+
+    runs[count] = run;
+    count += 1;
+    next += run;
+  }
+
+  let sent = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), count as libc::c_uint, 0) };
+  if sent < 0 { return Err(io::Error::last_os_error()); }
+  Ok(runs[..sent as usize].iter().sum())
+}
+
+// How many datagrams from the front can go as one segmented send: all to one peer and the same size,
+// but for a last that may be smaller
+fn run_len(datagrams: &[(SocketAddr, std::ops::Range<usize>)]) -> usize {
+  let (addr, ref first) = datagrams[0];
+  let segment = first.len();
+  if segment == 0 || segment > u16::MAX as usize { return 1; }
+
+  let mut run = 1;
+  let mut bytes = segment;
+  for (next_addr, range) in datagrams[1..].iter() {
+    if run >= MAX_SEGMENTS || *next_addr != addr || range.len() > segment || bytes + range.len() > MAX_SEGMENTED_BYTES { break; }
+    run += 1;
+    bytes += range.len();
+    if range.len() < segment { break; }
+  }
+  run
+}
+
+fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+  match storage.ss_family as libc::c_int {
+    libc::AF_INET => {
+      let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+      let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+      Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+    },
+    libc::AF_INET6 => {
+      let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+      let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+      Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+    },
+    _ => None
+  }
+}
+
+fn from_socket_addr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+  match addr {
+    SocketAddr::V4(addr) => {
+      let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+      sin.sin_family = libc::AF_INET as libc::sa_family_t;
+      sin.sin_port = addr.port().to_be();
+      sin.sin_addr = libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() };
+      mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+    },
+    SocketAddr::V6(addr) => {
+      let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+      sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+      sin6.sin6_port = addr.port().to_be();
+      sin6.sin6_flowinfo = addr.flowinfo();
+      sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+      sin6.sin6_scope_id = addr.scope_id();
+      mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{SocketAddr, UdpSocket};
+  use std::os::unix::io::AsRawFd;
+
+  use super::{recv, send, run_len, enable_offload};
+  use crate::transport::{Inbox, Outbox};
+
+  fn pair() -> (UdpSocket, UdpSocket) {
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    b.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+    (a, b)
+  }
+
+  #[test]
+  fn sends_and_receives_a_batch() {
+    let (a, b) = pair();
+    let mut outbox = Outbox::default();
+    for i in 0..10u8 {
+      outbox.push(&[i; 8], b.local_addr().unwrap());
+    }
+    assert_eq!(send(a.as_raw_fd(), &outbox, false).unwrap(), 10);
+
+    // Wait on the first, so the rest are all queued by the time of the batched receive
+    let mut first = [0u8; 8];
+    assert_eq!(b.peek(&mut first).unwrap(), 8);
+    let mut inbox = Inbox::new(16, 64);
+    let mut buf = [0u8; 64];
+    let mut received = vec![];
+    while received.len() < 10 {
+      assert!(recv(b.as_raw_fd(), &mut inbox, false).unwrap() > 0);
+      while let Some((size, from)) = inbox.pop(&mut buf) {
+        assert_eq!(from, a.local_addr().unwrap());
+        received.push(buf[..size].to_vec());
+      }
+    }
+    assert_eq!(received, (0..10u8).map(|i| vec![i; 8]).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn offload_splits_and_coalesces() {
+    let (a, b) = pair();
+    // Kernels before 5.0 can't
+    if enable_offload(a.as_raw_fd()).is_err() || enable_offload(b.as_raw_fd()).is_err() { return; }
+
+    let mut outbox = Outbox::default();
+    for i in 0..5u8 {
+      outbox.push(&[i; 100], b.local_addr().unwrap());
+    }
+    outbox.push(&[5; 40], b.local_addr().unwrap());
+    assert_eq!(send(a.as_raw_fd(), &outbox, true).unwrap(), 6);
+
+    // However the kernel delivers them, they come out of the inbox one by one
+    let mut inbox = Inbox::new(4, super::GRO_BUF_SIZE);
+    let mut buf = [0u8; 128];
+    let mut received = vec![];
+    while received.len() < 6 {
+      recv(b.as_raw_fd(), &mut inbox, true).unwrap();
+      while let Some((size, _)) = inbox.pop(&mut buf) {
+        received.push(buf[..size].to_vec());
+      }
+    }
+    let mut expected: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 100]).collect();
+    expected.push(vec![5; 40]);
+    assert_eq!(received, expected);
+  }
+
+  #[test]
+  fn runs_split_on_peer_and_size() {
+    let a: SocketAddr = ([127, 0, 0, 1], 1).into();
+    let b: SocketAddr = ([127, 0, 0, 1], 2).into();
+    let datagrams = vec![(a, 0..4), (a, 4..8), (a, 8..10), (a, 10..14), (b, 14..18)];
+    assert_eq!(run_len(&datagrams), 3);
+    assert_eq!(run_len(&datagrams[3..]), 1);
+  }
+}
@@ -0,0 +1,187 @@
+#[cfg(target_os = "linux")]
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use crossbeam::channel;
+use mio::{Interest, Registry, Token, Waker};
+use mio::net::UdpSocket as MioUdpSocket;
+
+pub mod sim;
+mod batch;
+#[cfg(target_os = "linux")]
+mod mmsg;
+
+pub use batch::{Inbox, Outbox};
+pub(crate) use batch::Batched;
+
+// Most datagrams moved per batch, where the transport can batch at all
+#[cfg(target_os = "linux")]
+pub const MAX_BATCH: usize = mmsg::MAX_BATCH;
+#[cfg(not(target_os = "linux"))]
+pub const MAX_BATCH: usize = 64;
+
+// Receive buffers must be this large while offload is on
+#[cfg(target_os = "linux")]
+pub const OFFLOAD_BUF_SIZE: usize = mmsg::GRO_BUF_SIZE;
+#[cfg(not(target_os = "linux"))]
+pub const OFFLOAD_BUF_SIZE: usize = 0;
+
+/// A datagram socket the daemon can drive. The daemon is generic over it,
+/// so the same protocol runs over real UDP or an in-memory network.
+///
+/// Like a nonblocking UdpSocket, sends and receives should fail with WouldBlock rather than block.
+/// Sockets are told when they become readable in one of two ways: via a mio registry,
+/// or for sockets mio knows nothing about, by notifying the daemon through the given Readiness.
+pub trait Transport: Debug + Send + 'static {
+  // What the app hands to Service::listen and Service::connect
+  type Socket: Debug + Send + 'static;
+
+  fn open(socket: Self::Socket) -> Self;
+
+  fn local_addr(&self) -> io::Result<SocketAddr>;
+
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+  // A datagram larger than buf is truncated to fit, as with UDP
+  fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+  fn register(&mut self, registry: &Registry, token: Token, readiness: &Readiness) -> io::Result<()>;
+
+  fn deregister(&mut self, registry: &Registry) -> io::Result<()>;
+
+  // Receives as many datagrams as the inbox has buffers for, in as few calls as the transport can, and at least one.
+  // Fails like recv_from, without having received any. Unless overridden, it takes just one datagram
+  fn recv_batch(&self, inbox: &mut Inbox) -> io::Result<usize> {
+    let (size, addr) = self.recv_from(&mut inbox.bufs_mut()[0])?;
+    inbox.fill(0, size, addr, 0);
+    Ok(1)
+  }
+
+  // Sends datagrams from the front of the outbox, returning how many went.
+  // Fails like send_to only if none did. Unless overridden, it sends them one by one
+  fn send_batch(&self, outbox: &Outbox) -> io::Result<usize> {
+    let mut sent = 0;
+    for (datagram, addr) in outbox.iter() {
+      match self.send_to(datagram, addr) {
+        Ok(_) => sent += 1,
+        Err(e) if sent == 0 => return Err(e),
+        Err(_) => break
+      }
+    }
+    Ok(sent)
+  }
+
+  // Asks the transport to have the kernel split large sends and coalesce bursts received, before it is registered.
+  // Once on, received datagrams only come apart through recv_batch, with inbox buffers of OFFLOAD_BUF_SIZE.
+  // Returns whether it is on
+  fn offload(&mut self) -> bool {
+    false
+  }
+}
+
+// Where a connection's datagrams go. Every transport is one, and so is the outbox of a sans-IO endpoint
+pub trait Output {
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+}
+
+impl<T: Transport> Output for T {
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    Transport::send_to(self, buf, addr)
+  }
+}
+
+/// Tells the daemon a socket may have datagrams to read
+#[derive(Clone, Debug)]
+pub struct Readiness {
+  tx_ready: channel::Sender<Token>,
+  waker: Arc<Waker>
+}
+
+impl Readiness {
+  pub(crate) fn new(tx_ready: channel::Sender<Token>, waker: Arc<Waker>) -> Readiness {
+    Readiness { tx_ready, waker }
+  }
+
+  // The daemon reads the socket until WouldBlock, so spurious or repeated notifications are harmless
+  pub fn readable(&self, token: Token) -> io::Result<()> {
+    self.tx_ready.send(token).map_err(crate::error::cannot_send_to_daemon)?;
+    self.waker.wake()
+  }
+}
+
+/// Real UDP, polled by mio. On Linux it moves datagrams in batches with recvmmsg and sendmmsg,
+/// and with offload on, has the kernel segment sends (UDP_SEGMENT) and coalesce receives (UDP_GRO)
+#[derive(Debug)]
+pub struct Udp {
+  socket: MioUdpSocket,
+  #[cfg(target_os = "linux")]
+  gro: bool,
+  // Turned off for good should the kernel or device refuse a segmented send
+  #[cfg(target_os = "linux")]
+  gso: Cell<bool>
+}
+
+impl Transport for Udp {
+  type Socket = std::net::UdpSocket;
+
+  fn open(socket: std::net::UdpSocket) -> Udp {
+    Udp {
+      socket: MioUdpSocket::from_std(socket),
+      #[cfg(target_os = "linux")]
+      gro: false,
+      #[cfg(target_os = "linux")]
+      gso: Cell::new(false)
+    }
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.socket.local_addr()
+  }
+
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    self.socket.send_to(buf, addr)
+  }
+
+  fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    self.socket.recv_from(buf)
+  }
+
+  fn register(&mut self, registry: &Registry, token: Token, _readiness: &Readiness) -> io::Result<()> {
+    registry.register(&mut self.socket, token, Interest::READABLE | Interest::WRITABLE)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+    registry.deregister(&mut self.socket)
+  }
+
+  #[cfg(target_os = "linux")]
+  fn recv_batch(&self, inbox: &mut Inbox) -> io::Result<usize> {
+    mmsg::recv(self.socket.as_raw_fd(), inbox, self.gro)
+  }
+
+  #[cfg(target_os = "linux")]
+  fn send_batch(&self, outbox: &Outbox) -> io::Result<usize> {
+    if !self.gso.get() { return mmsg::send(self.socket.as_raw_fd(), outbox, false); }
+    match mmsg::send(self.socket.as_raw_fd(), outbox, true) {
+      // Segments larger than the path allows, or a device without checksum offload
+      Err(e) if e.raw_os_error() == Some(libc::EINVAL) || e.raw_os_error() == Some(libc::EIO) => {
+        self.gso.set(false);
+        mmsg::send(self.socket.as_raw_fd(), outbox, false)
+      },
+      sent => sent
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  fn offload(&mut self) -> bool {
+    let on = mmsg::enable_offload(self.socket.as_raw_fd()).is_ok();
+    self.gro = on;
+    self.gso.set(on);
+    on
+  }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use mio::{Registry, Token};
+
+use crate::transport::{Transport, Readiness};
+use crate::constants::{SIM_QUEUE_MAX, SIM_EPHEMERAL_PORT_MIN};
+
+/// An in-process network of datagram sockets with virtual addresses.
+/// Services built with Builder::build_with_transport::<sim::Socket>() talk over it with no real sockets,
+/// so tests can run many of them side by side, driven by a mock clock.
+///
+/// Delivery is instant and in order. Datagrams to an address nobody is bound to are silently lost,
+/// as are datagrams beyond a socket's queue limit.
+#[derive(Clone, Debug, Default)]
+pub struct Network {
+  hub: Arc<Mutex<Hub>>
+}
+
+#[derive(Debug, Default)]
+struct Hub {
+  endpoints: HashMap<SocketAddr, Endpoint>,
+  next_port: u16
+}
+
+#[derive(Debug, Default)]
+struct Endpoint {
+  queue: VecDeque<(Vec<u8>, SocketAddr)>,
+  ready: Option<(Readiness, Token)>
+}
+
+impl Network {
+  pub fn new() -> Network {
+    Network::default()
+  }
+
+  // Binds a socket to the given virtual address. Port 0 picks an unused port
+  pub fn bind(&self, addr: SocketAddr) -> io::Result<Socket> {
+    let mut hub = self.hub.lock().map_err(|_| poisoned())?;
+    let mut local_addr = addr;
+    if local_addr.port() == 0 {
+      local_addr.set_port(hub.ephemeral_port(addr)?);
+    }
+    if hub.endpoints.contains_key(&local_addr) {
+      return Err(io::Error::new(io::ErrorKind::AddrInUse, "Address is already bound on the simulated network"));
+    }
+
+    hub.endpoints.insert(local_addr, Endpoint::default());
+    Ok(Socket { network: self.clone(), local_addr })
+  }
+}
+
+impl Hub {
+  fn ephemeral_port(&mut self, addr: SocketAddr) -> io::Result<u16> {
+    // Up to and including u16::MAX, so the span itself may not fit a u16
+    let span = u32::from(u16::MAX - SIM_EPHEMERAL_PORT_MIN) + 1;
+    for _ in 0..span {
+      let port = SIM_EPHEMERAL_PORT_MIN + (u32::from(self.next_port) % span) as u16;
+      self.next_port = self.next_port.wrapping_add(1);
+      if !self.endpoints.contains_key(&SocketAddr::new(addr.ip(), port)) { return Ok(port); }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "No ports left on the simulated network"))
+  }
+}
+
+/// A socket on a simulated Network. Unbinds when dropped
+#[derive(Debug)]
+pub struct Socket {
+  network: Network,
+  local_addr: SocketAddr
+}
+
+impl Drop for Socket {
+  fn drop(&mut self) {
+    if let Ok(mut hub) = self.network.hub.lock() {
+      hub.endpoints.remove(&self.local_addr);
+    }
+  }
+}
+
+impl Transport for Socket {
+  type Socket = Socket;
+
+  fn open(socket: Socket) -> Socket {
+    socket
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    Ok(self.local_addr)
+  }
+
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    let mut hub = self.network.hub.lock().map_err(|_| poisoned())?;
+    if let Some(endpoint) = hub.endpoints.get_mut(&addr) {
+      if endpoint.queue.len() < SIM_QUEUE_MAX {
+        endpoint.queue.push_back((buf.to_vec(), self.local_addr));
+        if let Some((ref readiness, token)) = endpoint.ready {
+          // Nobody to tell is the same as a socket nobody reads
+          readiness.readable(token).ok();
+        }
+      }
+    }
+    Ok(buf.len())
+  }
+
+  fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    let mut hub = self.network.hub.lock().map_err(|_| poisoned())?;
+    let endpoint = hub.endpoints.get_mut(&self.local_addr).ok_or_else(not_bound)?;
+    match endpoint.queue.pop_front() {
+      Some((datagram, from)) => {
+        let size = usize::min(datagram.len(), buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, from))
+      },
+      None => Err(io::Error::from(io::ErrorKind::WouldBlock))
+    }
+  }
+
+  fn register(&mut self, _registry: &Registry, token: Token, readiness: &Readiness) -> io::Result<()> {
+    let mut hub = self.network.hub.lock().map_err(|_| poisoned())?;
+    let endpoint = hub.endpoints.get_mut(&self.local_addr).ok_or_else(not_bound)?;
+    endpoint.ready = Some((readiness.clone(), token));
+
+    // Anything that arrived before registering is ready now
+    if !endpoint.queue.is_empty() { readiness.readable(token)?; }
+    Ok(())
+  }
+
+  fn deregister(&mut self, _registry: &Registry) -> io::Result<()> {
+    let mut hub = self.network.hub.lock().map_err(|_| poisoned())?;
+    let endpoint = hub.endpoints.get_mut(&self.local_addr).ok_or_else(not_bound)?;
+    endpoint.ready = None;
+    Ok(())
+  }
+}
+
+fn poisoned() -> io::Error {
+  io::Error::new(io::ErrorKind::Other, "Simulated network lock was poisoned")
+}
+
+fn not_bound() -> io::Error {
+  io::Error::new(io::ErrorKind::NotFound, "Socket is no longer bound on the simulated network")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn delivers_between_sockets() {
+    let network = Network::new();
+    let a = network.bind("10.0.0.1:9000".parse().unwrap()).unwrap();
+    let b = network.bind("10.0.0.2:0".parse().unwrap()).unwrap();
+    assert!(network.bind("10.0.0.1:9000".parse().unwrap()).is_err());
+
+    let b_addr = b.local_addr().unwrap();
+    assert_ne!(b_addr.port(), 0);
+    a.send_to(b"hello", b_addr).unwrap();
+
+    let mut buf = [0u8; 3];
+    assert_eq!(b.recv_from(&mut buf).unwrap(), (3, a.local_addr().unwrap()));
+    assert_eq!(&buf, b"hel");
+    assert_eq!(b.recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    // Dropping a socket frees its address
+    drop(a);
+    network.bind("10.0.0.1:9000".parse().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn hands_out_every_ephemeral_port() {
+    let network = Network::new();
+    let mut sockets = vec![];
+    while let Ok(socket) = network.bind("10.0.0.1:0".parse().unwrap()) {
+      sockets.push(socket);
+    }
+    assert_eq!(sockets.len(), usize::from(u16::MAX - SIM_EPHEMERAL_PORT_MIN) + 1);
+    assert!(sockets.iter().any(|socket| socket.local_addr().unwrap().port() == u16::MAX));
+  }
+}
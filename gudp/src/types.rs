@@ -1,13 +1,20 @@
 use std::sync::Arc;
-use std::net::{UdpSocket, SocketAddr};
+use std::net::SocketAddr;
 use std::io;
+use std::time::Duration;
 
 use crossbeam::channel::Sender;
 use crate::state;
+use crate::error::Reason;
+use crate::transport::Transport;
+use crate::handler::Handler;
 
 #[allow(non_camel_case_types)]
 pub type READ_BUFFER_TAG = ();
 
+#[allow(non_camel_case_types)]
+pub type WRITE_BUFFER_TAG = ();
+
 // Connection callback on write
 pub type OnWrite = dyn Fn(usize) -> io::Result<usize> + Send + Sync;
 
@@ -15,12 +22,16 @@ pub type OnWrite = dyn Fn(usize) -> io::Result<usize> + Send + Sync;
 pub type OnClose = dyn Fn() -> io::Result<()> + Send + Sync;
 
 #[derive(Debug)]
-pub enum ToDaemon {
-  Listen(UdpSocket, Sender<FromDaemon>),
-  Connect(UdpSocket, Sender<FromDaemon>, SocketAddr)
+pub enum ToDaemon<T: Transport> {
+  // Connections go to the handler on the daemon thread, if there is one, rather than to the app
+  Listen(T::Socket, Sender<FromDaemon>, Option<Box<dyn Handler>>),
+  Connect(T::Socket, Sender<FromDaemon>, SocketAddr, Vec<u8>),
+  Shutdown(Duration)
 }
 
 pub enum FromDaemon {
-  Listener(Box<OnClose>),
-  Connection(Arc<OnWrite>, Arc<state::Shared>, (SocketAddr, SocketAddr))
+  Listener(Box<OnClose>, Arc<state::Signal>),
+  Connection(Arc<OnWrite>, Arc<state::Shared>, (SocketAddr, SocketAddr)),
+  // The peer hung up on a direct connection before it was ever established
+  Refused(Reason)
 }
@@ -0,0 +1,102 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::bind;
+
+#[test]
+fn test_max_peers() {
+  let service = gudp::Builder::new()
+    .max_peers(1)
+    .build()
+    .expect("Could not initialize gudp service");
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+
+  let _first = service.connect_timeout(bind(), listen_addr, Duration::from_secs(1)).expect("Could not connect");
+  let _accepted = listener.accept_timeout(Duration::from_secs(1)).expect("Could not accept");
+
+  // Accepting doesn't make room; only the first peer leaving would
+  let started = Instant::now();
+  let err = service.connect_timeout(bind(), listen_addr, Duration::from_secs(5)).err().expect("Connected despite max peers");
+  assert_eq!(gudp::Error::from_io(&err), Some(gudp::Error::ServerFull));
+  assert!(started.elapsed() < Duration::from_secs(5));
+  assert_eq!(service.metrics().denied_peers(), 1);
+}
+
+#[test]
+fn test_admit_join_token() {
+  let service = gudp::Builder::new()
+    .admit(Box::new(|_addr, payload| {
+      if payload == b"let me in" { gudp::Admission::Accept } else { gudp::Admission::Reject }
+    }))
+    .build()
+    .expect("Could not initialize gudp service");
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+
+  let err = service.connect_with_token(bind(), listen_addr, b"wrong", Duration::from_secs(1)).err().expect("Connected with wrong token");
+  assert_eq!(gudp::Error::from_io(&err), Some(gudp::Error::Denied));
+  assert!(listener.try_accept().is_none());
+
+  let client = service.connect_with_token(bind(), listen_addr, b"let me in", Duration::from_secs(1)).expect("Could not connect");
+  let server = listener.accept_timeout(Duration::from_secs(1)).expect("Could not accept");
+
+  // The token is the first thing the accepted peer receives
+  let mut buf = [0u8; 16];
+  let size = server.recv_timeout(&mut buf, Duration::from_secs(1)).expect("Could not recv token");
+  assert_eq!(&buf[..size], b"let me in");
+
+  client.send(b"hello").expect("Could not send");
+  let size = server.recv_timeout(&mut buf, Duration::from_secs(1)).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello");
+}
+
+#[test]
+fn test_deny_list() {
+  let service = gudp::Builder::new()
+    .allow("127.0.0.0/8".parse().unwrap())
+    .deny("127.0.0.1".parse().unwrap())
+    .build()
+    .expect("Could not initialize gudp service");
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let _listener = service.listen(listen_socket).expect("Could not start listener");
+
+  // Denied peers are ignored outright, so the peer can only time out
+  let err = service.connect_timeout(bind(), listen_addr, Duration::from_millis(300)).err().expect("Connected despite deny list");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+  assert!(service.metrics().denied_peers() >= 1);
+}
+
+#[test]
+fn test_retried_token_delivered_once() {
+  let service = gudp::Builder::new()
+    .handshake_retry(Duration::from_millis(10), 1, Duration::from_millis(10))
+    .build()
+    .expect("Could not initialize gudp service");
+
+  // Hold off listening so the client retries its hello a few times first
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let (client, server, _listener) = std::thread::scope(|scope| {
+    let connecting = scope.spawn(|| service.connect_with_token(bind(), listen_addr, b"token", Duration::from_secs(2)));
+    std::thread::sleep(Duration::from_millis(100));
+    let listener = service.listen(listen_socket).expect("Could not start listener");
+    let server = listener.accept_timeout(Duration::from_secs(1)).expect("Could not accept");
+    (connecting.join().unwrap().expect("Could not connect"), server, listener)
+  });
+
+  let mut buf = [0u8; 16];
+  let size = server.recv_timeout(&mut buf, Duration::from_secs(1)).expect("Could not recv token");
+  assert_eq!(&buf[..size], b"token");
+
+  client.send(b"hello").expect("Could not send");
+  let size = server.recv_timeout(&mut buf, Duration::from_secs(1)).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello");
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::bind;
+
+#[test]
+fn test_full_backlog_refuses_peers() {
+  let service = gudp::Builder::new()
+    .accept_backlog(1)
+    .build()
+    .expect("Could not initialize gudp service");
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+
+  // Nobody calls accept, so the first peer fills the backlog
+  let _first = service.connect_timeout(bind(), listen_addr, Duration::from_secs(1)).expect("Could not connect");
+
+  // The second peer is told the server is full rather than left to time out
+  let started = Instant::now();
+  assert!(service.connect_timeout(bind(), listen_addr, Duration::from_secs(5)).is_err());
+  assert!(started.elapsed() < Duration::from_secs(5));
+  assert_eq!(service.metrics().refused_peers(), 1);
+
+  // Making room in the backlog lets new peers in again
+  listener.accept_timeout(Duration::from_secs(1)).expect("Could not accept");
+  service.connect_timeout(bind(), listen_addr, Duration::from_secs(1)).expect("Could not connect");
+}
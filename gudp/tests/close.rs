@@ -0,0 +1,64 @@
+mod common;
+
+use std::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_flush() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  for n in 0..50u8 {
+    pair.client.send(&[n; 100]).expect("Could not send");
+  }
+  pair.client.flush().expect("Could not flush");
+
+  let mut buf = [0u8; 128];
+  for n in 0..50u8 {
+    let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+    assert_eq!(&buf[..size], &[n; 100][..]);
+  }
+}
+
+#[test]
+fn test_close_notifies_peer() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  pair.client.send(b"goodbye").expect("Could not send");
+  pair.client.close(WAIT).expect("Could not close");
+
+  // Queued data still arrives, then the peer observes the hangup well before any timeout
+  let mut buf = [0u8; 64];
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"goodbye");
+  match pair.server.recv_timeout(&mut buf, WAIT) {
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+    _ => panic!("Expected ConnectionReset")
+  }
+  assert_eq!(pair.server.close_reason(), Some(gudp::Error::PeerDisconnected(gudp::Reason::Closed)));
+}
+
+#[test]
+fn test_close_releases_only_its_handle() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  // Other handles keep the connection open
+  let clone = pair.client.clone();
+  pair.client.close(WAIT).expect("Could not close");
+  clone.send(b"still open").expect("Could not send");
+
+  let mut buf = [0u8; 64];
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"still open");
+
+  // Closing the last one hangs up
+  clone.close(WAIT).expect("Could not close");
+  match pair.server.recv_timeout(&mut buf, WAIT) {
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+    _ => panic!("Expected ConnectionReset")
+  }
+}
+
@@ -0,0 +1,28 @@
+// Shared by the integration tests, each of which uses only some of it
+#![allow(dead_code)]
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+pub fn bind() -> UdpSocket {
+  let socket = UdpSocket::bind("127.0.0.1:0").expect("Could not bind");
+  socket.set_nonblocking(true).expect("Could not set nonblocking!");
+  socket
+}
+
+// A directly connected client and the connection its peer accepted, over loopback
+pub struct Pair {
+  pub listener: gudp::Listener,
+  pub client: gudp::Connection,
+  pub server: gudp::Connection
+}
+
+pub fn new(service: &gudp::Service) -> Pair {
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().expect("Could not get local addr");
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+  let client = service.connect_timeout(bind(), listen_addr, Duration::from_secs(1)).expect("Could not connect");
+  let server = listener.accept_timeout(Duration::from_secs(1)).expect("Could not accept");
+
+  Pair { listener, client, server }
+}
@@ -0,0 +1,73 @@
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clock::mock;
+use gudp::{sim, Conditions, Loss};
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_conditioned_delay() {
+  let network = sim::Network::new();
+  let server_clock = mock::Clock::new(Instant::now());
+  let client_clock = mock::Clock::new(Instant::now());
+  let server_service = gudp::Builder::new()
+    .clock(server_clock)
+    .build_with_transport::<sim::Socket>()
+    .expect("Could not initialize gudp service");
+  let client_service = gudp::Builder::new()
+    .clock(client_clock.clone())
+    .outbound_conditions(Conditions { delay: Duration::from_millis(100), ..Conditions::default() })
+    .conditioner_seed(42)
+    .build_with_transport::<sim::Socket>()
+    .expect("Could not initialize gudp service");
+
+  let listen_addr: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+  let listener = server_service.listen(network.bind(listen_addr).unwrap()).expect("Could not start listener");
+
+  // Every hello is held back until the client's clock says it has crossed the link
+  let (tx, rx) = mpsc::channel();
+  let socket = network.bind("10.0.0.2:0".parse().unwrap()).unwrap();
+  let connecting = client_service.clone();
+  thread::spawn(move || tx.send(connecting.connect_timeout(socket, listen_addr, WAIT)));
+  let client = loop {
+    if let Ok(result) = rx.recv_timeout(Duration::from_millis(10)) { break result.expect("Could not connect"); }
+    client_clock.tick_ms(50);
+    client_service.wake().expect("Could not wake");
+  };
+  let server = listener.accept_timeout(WAIT).expect("Could not accept");
+
+  let mut buf = [0u8; 64];
+  client.send(b"late").expect("Could not send");
+  assert!(server.recv_timeout(&mut buf, Duration::from_millis(50)).is_err());
+
+  client_clock.tick_ms(100);
+  client_service.wake().expect("Could not wake");
+  let size = server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"late");
+}
+
+#[test]
+fn test_conditioned_loss() {
+  let network = sim::Network::new();
+  let clock = mock::Clock::new(Instant::now());
+  let server_service = gudp::Builder::new()
+    .clock(clock.clone())
+    .inbound_conditions(Conditions { loss: Loss::Random { pct: 100.0 }, ..Conditions::default() })
+    .build_with_transport::<sim::Socket>()
+    .expect("Could not initialize gudp service");
+  let client_service = gudp::Builder::new()
+    .clock(clock)
+    .build_with_transport::<sim::Socket>()
+    .expect("Could not initialize gudp service");
+
+  let listen_addr: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+  let _listener = server_service.listen(network.bind(listen_addr).unwrap()).expect("Could not start listener");
+
+  // The listener never hears the hello, so never answers it
+  let socket = network.bind("10.0.0.2:0".parse().unwrap()).unwrap();
+  let err = client_service.connect_timeout(socket, listen_addr, Duration::from_millis(50)).err().expect("Connected over a dead link");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use gudp::{ConnectionCtx, Handler};
+use common::bind;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+enum Seen {
+  Connect,
+  Disconnect(gudp::Error),
+  Tick
+}
+
+// Echoes every message from the daemon thread, hanging up on "bye"
+struct Echo {
+  tx: mpsc::Sender<Seen>,
+  ticked: bool
+}
+
+impl Handler for Echo {
+  fn on_connect(&mut self, _ctx: &mut ConnectionCtx) {
+    self.tx.send(Seen::Connect).ok();
+  }
+
+  fn on_message(&mut self, ctx: &mut ConnectionCtx, payload: &[u8]) {
+    if payload == b"bye" {
+      ctx.close();
+      // Nothing more goes out once the handler hangs up
+      assert!(ctx.send(payload).is_err());
+      return;
+    }
+    ctx.send(payload).expect("Could not echo");
+  }
+
+  fn on_disconnect(&mut self, _ctx: &mut ConnectionCtx, reason: gudp::Error) {
+    self.tx.send(Seen::Disconnect(reason)).ok();
+  }
+
+  fn on_tick(&mut self, _ctx: &mut ConnectionCtx) {
+    if !self.ticked { self.tx.send(Seen::Tick).ok(); }
+    self.ticked = true;
+  }
+}
+
+fn echo_server(service: &gudp::Service) -> (gudp::Listener, std::net::SocketAddr, mpsc::Receiver<Seen>) {
+  let (tx, rx) = mpsc::channel();
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen_with_handler(listen_socket, Echo { tx, ticked: false }).expect("Could not start listener");
+  (listener, listen_addr, rx)
+}
+
+#[test]
+fn test_handler_echoes() {
+  let service = gudp::Builder::new()
+    .handler_tick(Duration::from_millis(10))
+    .build()
+    .expect("Could not initialize gudp service");
+  let (_listener, listen_addr, seen) = echo_server(&service);
+
+  let client = service.connect_timeout(bind(), listen_addr, WAIT).expect("Could not connect");
+  assert_eq!(seen.recv_timeout(WAIT), Ok(Seen::Connect));
+
+  let mut buf = [0u8; 64];
+  for message in [&b"hello"[..], b"again"].iter() {
+    client.send(message).expect("Could not send");
+    let size = client.recv_timeout(&mut buf, WAIT).expect("Never echoed");
+    assert_eq!(&buf[..size], *message);
+  }
+  assert_eq!(seen.recv_timeout(WAIT), Ok(Seen::Tick));
+
+  client.close(WAIT).expect("Could not close");
+  assert_eq!(seen.recv_timeout(WAIT), Ok(Seen::Disconnect(gudp::Error::PeerDisconnected(gudp::Reason::Closed))));
+}
+
+#[test]
+fn test_handler_closes() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let (_listener, listen_addr, seen) = echo_server(&service);
+
+  let client = service.connect_timeout(bind(), listen_addr, WAIT).expect("Could not connect");
+  assert_eq!(seen.recv_timeout(WAIT), Ok(Seen::Connect));
+
+  client.send(b"bye").expect("Could not send");
+  assert_eq!(seen.recv_timeout(WAIT), Ok(Seen::Disconnect(gudp::Error::LocalClosed)));
+
+  let mut buf = [0u8; 64];
+  assert_eq!(gudp::Error::from_io(&client.recv_timeout(&mut buf, WAIT).unwrap_err()), Some(gudp::Error::PeerDisconnected(gudp::Reason::Closed)));
+}
@@ -0,0 +1,17 @@
+use std::time::Duration;
+
+#[test]
+fn test_started_and_running() {
+  let service = gudp::Builder::new()
+    .build()
+    .expect("Could not initialize gudp service");
+
+  match service.events().recv_timeout(Duration::from_secs(1)) {
+    Ok(gudp::Event::Started) => {},
+    other => panic!("Expected Started, got {:?}", other)
+  }
+  assert_eq!(service.health(), gudp::Health::Running);
+
+  // Clones share the one daemon
+  assert_eq!(service.clone().health(), gudp::Health::Running);
+}
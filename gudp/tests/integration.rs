@@ -28,21 +28,89 @@ fn test_wrong_protocol_id() {
 
 #[test]
 /*
-LOG Description: A packet with the right payload is sent. We receive an echo reply
-SENT 0001: 0ns - de ad be ef 00 00 00 00 00 00 00 00 00 00 00 00
-RECEIVED 0000: 0ns - de ad be ef 00 00 00 00 00 00 00 00 00 00 00 00
+LOG Description: A packet with the right payload is sent inside a hello. We are challenged, echo the cookie and receive an echo reply
+SENT 0001: 0ns - de ad c0 de 03 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 de ad be ef 00 00 00 00 00 00 00 00 00 00 00 00
+RECEIVED 0000: 0ns - de ad c0 de 02 <16 byte cookie>
+SENT 0002: 0ns - de ad c0 de 03 <16 byte cookie> de ad be ef 00 00 00 00 00 00 00 00 00 00 00 00
+RECEIVED 0001: 0ns - de ad be ef 00 00 00 00 00 00 00 00 00 00 00 00
 */
 
 fn test_right_protocol_id() {
   let mut buf = vec![0u8; 4096];
   let harness = harness::new(8000, 9000);
-  let mut send = gudp::PROTOCOL_ID.to_vec();
-  send.extend(hex::decode_unsafe("00 00 00 00 00 00 00 00 00 00 00 00"));
+  let hello = hex::decode_unsafe("de ad c0 de 03");
+  let mut packet = gudp::PROTOCOL_ID.to_vec();
+  packet.extend(hex::decode_unsafe("00 00 00 00 00 00 00 00 00 00 00 00"));
   let mut expected = gudp::PROTOCOL_ID.to_vec();
   expected.extend(hex::decode_unsafe("00 00 00 00 00 00 00 00 00 00 00 00"));
 
+  let mut send = hello.clone();
+  send.extend(&[0u8; 16]);
+  send.extend(&packet);
+  harness.socket.send(&send).expect("Could not send");
+  std::thread::sleep(std::time::Duration::from_millis(1));
+  let size = harness.socket.recv(&mut buf).expect("Could not recv challenge");
+  assert_eq!(size, 21);
+  assert_eq!(&buf[..5], &hex::decode_unsafe("de ad c0 de 02")[..]);
+
+  let mut send = hello.clone();
+  send.extend(&buf[5..size]);
+  send.extend(&packet);
   harness.socket.send(&send).expect("Could not send");
   std::thread::sleep(std::time::Duration::from_millis(1));
   let size = harness.socket.recv(&mut buf).expect("Could not recv");
   assert_eq!(&buf[..size], &expected);
 }
+
+#[test]
+/*
+LOG Description: Packets from an unverified address get no reply, except a challenge no larger than a hello
+SENT 0001: 0ns - de ad be ef 00 00 00 00 00 00 00 00 00 00 00 00
+SENT 0002: 0ns - de ad c0 de 03 00 00 00 00
+*/
+
+fn test_unverified_peer() {
+  let mut buf = vec![0u8; 4096];
+  let harness = harness::new(8001, 9001);
+  let mut send = gudp::PROTOCOL_ID.to_vec();
+  send.extend(hex::decode_unsafe("00 00 00 00 00 00 00 00 00 00 00 00"));
+  harness.socket.send(&send).expect("Could not send");
+  harness.socket.send(&hex::decode_unsafe("de ad c0 de 03 00 00 00 00")).expect("Could not send");
+
+  std::thread::sleep(std::time::Duration::from_millis(5));
+  match harness.socket.recv(&mut buf) {
+    Err(e) => {
+      assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock)
+    },
+    _ => panic!("Expected WouldBlock"),
+  }
+}
+
+#[test]
+// Two direct sockets connecting to each other each complete their handshake with the other's hello
+fn test_direct_sockets_connect_to_each_other() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let bind = || {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("Could not bind");
+    socket.set_nonblocking(true).expect("Could not set nonblocking!");
+    socket
+  };
+  let (socket_a, socket_b) = (bind(), bind());
+  let (addr_a, addr_b) = (socket_a.local_addr().unwrap(), socket_b.local_addr().unwrap());
+  let wait = std::time::Duration::from_secs(1);
+
+  let (a, b) = std::thread::scope(|scope| {
+    let a = scope.spawn(|| service.connect_timeout(socket_a, addr_b, wait));
+    let b = scope.spawn(|| service.connect_timeout(socket_b, addr_a, wait));
+    (a.join().unwrap().expect("Could not connect a"), b.join().unwrap().expect("Could not connect b"))
+  });
+
+  let mut buf = [0u8; 16];
+  a.send(b"from a").expect("Could not send");
+  let size = b.recv_timeout(&mut buf, wait).expect("Could not recv");
+  assert_eq!(&buf[..size], b"from a");
+
+  b.send(b"from b").expect("Could not send");
+  let size = a.recv_timeout(&mut buf, wait).expect("Could not recv");
+  assert_eq!(&buf[..size], b"from b");
+}
@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use clock::mock;
+use gudp::{sim, Daemon, Health};
+
+type Stepped = Daemon<mock::Clock, sim::Socket>;
+
+fn service(clock: &mock::Clock) -> (gudp::Service<sim::Socket>, Stepped) {
+  gudp::Builder::new()
+    .clock(clock.clone())
+    .build_manual_with_transport::<sim::Socket>()
+    .expect("Could not initialize gudp service")
+}
+
+// Steps both daemons in turn, as a game loop would each frame
+fn step(a: &mut Stepped, b: &mut Stepped, now: Instant) {
+  assert!(a.update(now).expect("Could not step daemon"));
+  assert!(b.update(now).expect("Could not step daemon"));
+}
+
+#[test]
+fn test_manual_step() {
+  let network = sim::Network::new();
+  let start = Instant::now();
+  let clock = mock::Clock::new(start);
+  let (_server_service, mut server_daemon) = service(&clock);
+  let (_client_service, mut client_daemon) = service(&clock);
+
+  let listen_addr: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+  let listener = server_daemon.listen(network.bind(listen_addr).unwrap()).expect("Could not start listener");
+  let connecting = client_daemon.connect(network.bind("10.0.0.2:0".parse().unwrap()).unwrap(), listen_addr).expect("Could not connect");
+
+  // Nothing happens between steps, and the handshake takes a few round trips
+  let mut steps = 0;
+  let client = loop {
+    if let Some(client) = connecting.try_finish() { break client.expect("Could not connect"); }
+    assert!(steps < 10, "Handshake never finished");
+    step(&mut client_daemon, &mut server_daemon, start);
+    steps += 1;
+  };
+  assert!(steps > 1);
+  let server = listener.try_accept().expect("Nothing to accept").expect("Could not accept");
+
+  let mut buf = [0u8; 64];
+  client.send(b"hello").expect("Could not send");
+  assert!(server.try_recv(&mut buf).is_none());
+  step(&mut client_daemon, &mut server_daemon, start);
+  let size = server.try_recv(&mut buf).expect("Nothing received").expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello");
+
+  // Updating to a later time is all it takes for the peer to time out; the mock clock itself never moves
+  assert!(client_daemon.next_deadline().is_some());
+  assert!(client_daemon.update(start + Duration::from_secs(20)).unwrap());
+  assert_eq!(client.close_reason(), Some(gudp::Error::PeerTimedOut));
+
+  // The connection is already over, so closing it needs nothing from the daemon, even once it's gone
+  drop(client_daemon);
+  client.close(Duration::from_millis(10)).expect("Could not close");
+}
+
+#[test]
+fn test_manual_service_does_not_block() {
+  let network = sim::Network::new();
+  let (service, _daemon) = service(&mock::Clock::new(Instant::now()));
+
+  // Nothing would step the daemon while these waited on it
+  let listen_addr: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+  let err = service.listen(network.bind(listen_addr).unwrap()).err().expect("Listened on a stepped daemon");
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  let err = service.connect(network.bind("10.0.0.2:0".parse().unwrap()).unwrap(), listen_addr).err().expect("Connected on a stepped daemon");
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_manual_shutdown() {
+  let start = Instant::now();
+  let (service, mut daemon) = service(&mock::Clock::new(start));
+
+  service.shutdown(Duration::from_millis(10)).expect("Could not shut down");
+  assert_eq!(service.health(), Health::Running);
+
+  assert!(!daemon.update(start).expect("Could not step daemon"));
+  assert!(!daemon.poll_once(None).expect("Stepped a finished daemon"));
+  assert_eq!(service.health(), Health::Stopped);
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::time::Duration;
+
+use common::bind;
+
+#[test]
+fn test_conn_rate_limit() {
+  let service = gudp::Builder::new()
+    .conn_rate_limit(5, 64 * 1024)
+    .build()
+    .expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  for _ in 0..50 {
+    pair.client.send(b"flood").expect("Could not send");
+  }
+
+  let mut buf = [0u8; 16];
+  let mut received = 0;
+  while pair.server.recv_timeout(&mut buf, Duration::from_millis(100)).is_ok() {
+    received += 1;
+  }
+
+  // The burst allowance is at most one second's worth
+  assert!(received > 0 && received < 10, "received {}", received);
+  assert!(service.metrics().throttled_packets() > 40);
+  let by_ip = service.metrics().throttled_by_ip();
+  let throttled = by_ip.get(&"127.0.0.1".parse().unwrap()).expect("Localhost not throttled");
+  assert_eq!(throttled.packets, service.metrics().throttled_packets());
+}
+
+#[test]
+fn test_ip_connect_rate() {
+  let service = gudp::Builder::new()
+    .ip_connect_rate(1)
+    .build()
+    .expect("Could not initialize gudp service");
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let _listener = service.listen(listen_socket).expect("Could not start listener");
+
+  let _first = service.connect_timeout(bind(), listen_addr, Duration::from_secs(1)).expect("Could not connect");
+
+  // A new port doesn't make for a new source
+  let err = service.connect_timeout(bind(), listen_addr, Duration::from_millis(300)).err().expect("Connected despite connect rate");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+  assert!(service.metrics().throttled_packets() >= 1);
+}
@@ -0,0 +1,86 @@
+#![cfg(unix)]
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, Token};
+
+use common::bind;
+
+const LISTENER: Token = Token(0);
+const WAIT: Duration = Duration::from_secs(1);
+
+// Polls until the token is woken, or panics at the deadline
+fn wait_for(poll: &mut Poll, events: &mut Events, token: Token) {
+  let deadline = Instant::now() + WAIT;
+  loop {
+    let now = Instant::now();
+    assert!(now < deadline, "Never woken for {:?}", token);
+    poll.poll(events, Some(deadline - now)).expect("Could not poll");
+    if events.iter().any(|event| event.token() == token) { return; }
+  }
+}
+
+#[test]
+fn test_readiness_from_app_poll() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let mut poll = Poll::new().expect("Could not create poll");
+  let mut events = Events::with_capacity(64);
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+  listener.register(poll.registry(), LISTENER).expect("Could not register listener");
+  wait_for(&mut poll, &mut events, LISTENER); // Registering always wakes once
+  assert!(listener.try_accept().is_none());
+
+  // Several clients, all multiplexed onto this one thread's poll
+  let clients: Vec<gudp::Connection> = (0..3)
+    .map(|_| {
+      let connecting = service.clone();
+      thread::spawn(move || connecting.connect_timeout(bind(), listen_addr, WAIT))
+    })
+    .collect::<Vec<_>>()
+    .into_iter()
+    .map(|handle| handle.join().unwrap().expect("Could not connect"))
+    .collect();
+
+  let mut servers = vec![];
+  while servers.len() < clients.len() {
+    wait_for(&mut poll, &mut events, LISTENER);
+    while let Some(server) = listener.try_accept() {
+      let server = server.expect("Could not accept");
+      server.register(poll.registry(), Token(1 + servers.len())).expect("Could not register connection");
+      servers.push(server);
+    }
+  }
+
+  let mut buf = [0u8; 64];
+  for (i, client) in clients.iter().enumerate() {
+    client.send(&[i as u8]).expect("Could not send");
+  }
+  let mut received = vec![];
+  while received.len() < clients.len() {
+    poll.poll(&mut events, Some(WAIT)).expect("Could not poll");
+    assert!(!events.is_empty(), "Never woken for a recv");
+    for event in events.iter() {
+      if event.token() == LISTENER { continue; }
+      while let Some(size) = servers[event.token().0 - 1].try_recv(&mut buf) {
+        received.push(buf[..size.expect("Could not recv")].to_vec());
+      }
+    }
+  }
+  received.sort();
+  assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+
+  // Closing wakes too
+  let server = servers.pop().unwrap();
+  let token = Token(servers.len() + 1);
+  for client in clients {
+    client.close(WAIT).expect("Could not close");
+  }
+  wait_for(&mut poll, &mut events, token);
+  while server.close_reason().is_none() { wait_for(&mut poll, &mut events, token); }
+  assert_eq!(server.close_reason(), Some(gudp::Error::PeerDisconnected(gudp::Reason::Closed)));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use std::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_peek() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+  pair.client.send(b"hello world").expect("Could not send");
+
+  assert_eq!(pair.server.peek_len().expect("Could not peek len"), 11);
+
+  let mut small = [0u8; 5];
+  match pair.server.recv(&mut small) {
+    Err(e) => {
+      assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+      assert_eq!(gudp::Error::from_io(&e), Some(gudp::Error::BufferTooSmall { needed: 11 }));
+    },
+    _ => panic!("Expected UnexpectedEof")
+  }
+
+  let mut buf = [0u8; 64];
+  let size = pair.server.peek(&mut buf).expect("Could not peek");
+  assert_eq!(&buf[..size], b"hello world");
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello world");
+}
+
+#[test]
+fn test_recv_with() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+  let payloads: Vec<Vec<u8>> = (0..20u8).map(|n| vec![n; 1000]).collect();
+  for payload in payloads.iter() {
+    pair.client.send(payload).expect("Could not send");
+  }
+
+  // However a packet lies in the read buffer, the two halves of its view together hold all of it
+  for payload in payloads.iter() {
+    let received = pair.server.recv_with(|data: gudp::SlicePair<u8>| {
+      let (front, back) = data.as_slices();
+      assert_eq!(front.len() + back.len(), data.len());
+      data.to_vec()
+    }).expect("Could not recv");
+    assert_eq!(&received, payload);
+  }
+
+  // The view pops the packet, so the next recv finds the next one
+  pair.client.send(b"last").expect("Could not send");
+  assert_eq!(pair.server.recv_with(|data| data.len()).expect("Could not recv"), 4);
+  let mut buf = [0u8; 64];
+  assert!(pair.server.try_recv(&mut buf).is_none());
+}
+
+#[test]
+fn test_recv_with_timeout() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  // Nothing sent, so the closure never runs
+  let err = pair.server.recv_with_timeout(|_| panic!("Ran without a packet"), Duration::from_millis(20)).err().expect("Received nothing");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+  pair.client.send(b"hello").expect("Could not send");
+  let received = pair.server.recv_with_deadline(|data| data.to_vec(), std::time::Instant::now() + WAIT).expect("Could not recv");
+  assert_eq!(&received, b"hello");
+}
+
+#[test]
+fn test_recv_vec() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+  pair.client.send(&[7u8; 300]).expect("Could not send");
+
+  assert_eq!(pair.server.recv_vec().expect("Could not recv"), vec![7u8; 300]);
+}
+
+#[test]
+fn test_recv_many() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+  for n in 0..5u8 {
+    pair.client.send(&[n]).expect("Could not send");
+  }
+
+  let mut bufs = vec![];
+  while bufs.len() < 5 {
+    let received = pair.server.recv_many(&mut bufs, 3).expect("Could not recv");
+    assert!((1..=3).contains(&received));
+  }
+
+  assert_eq!(bufs, vec![vec![0], vec![1], vec![2], vec![3], vec![4]]);
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use std::io::IoSlice;
+use std::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_send_batch() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  let sent = pair.client.send_batch(&[b"one", b"two", b"three"]).expect("Could not send");
+  assert_eq!(sent, 11);
+
+  let mut buf = [0u8; 64];
+  for expected in [&b"one"[..], b"two", b"three"].iter() {
+    let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+    assert_eq!(&buf[..size], *expected);
+  }
+}
+
+#[test]
+fn test_send_vectored() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  let sent = pair.client.send_vectored(&[IoSlice::new(b"hello"), IoSlice::new(b" "), IoSlice::new(b"world")]).expect("Could not send");
+  assert_eq!(sent, 11);
+
+  let mut buf = [0u8; 64];
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello world");
+}
+
+#[test]
+fn test_coalesced_sends_all_arrive() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  // Most of these sends find a wakeup already pending and skip waking the daemon
+  for n in 0..200u8 {
+    pair.client.send(&[n]).expect("Could not send");
+  }
+
+  let mut buf = [0u8; 64];
+  for n in 0..200u8 {
+    let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+    assert_eq!(&buf[..size], &[n]);
+  }
+}
+
+#[test]
+fn test_offloaded_bursts_all_arrive() {
+  let service = gudp::Builder::new()
+    .max_datagram_size(1200)
+    .batch_size(64)
+    .udp_offload(true)
+    .build()
+    .expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  // Same sized datagrams to one peer are what the kernel segments and coalesces, where it can
+  for n in 0..200u8 {
+    pair.client.send(&[n; 100]).expect("Could not send");
+  }
+
+  let mut buf = [0u8; 256];
+  for n in 0..200u8 {
+    let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+    assert_eq!(&buf[..size], &[n; 100][..]);
+  }
+}
+
+#[test]
+fn test_invalid_batch_size() {
+  for size in [0, 1000].iter() {
+    let err = gudp::Builder::new().batch_size(*size).build().err().expect("Built with an invalid batch size");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  }
+}
+
+#[test]
+fn test_send_too_large() {
+  let service = gudp::Builder::new()
+    .max_datagram_size(256)
+    .build()
+    .expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  // Nothing oversized gets queued, and what's queued after it still goes out
+  let err = pair.client.send(&[0u8; 256]).err().expect("Sent an oversized payload");
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  assert_eq!(gudp::Error::from_io(&err), Some(gudp::Error::PayloadTooLarge { max: 240 }));
+  assert!(pair.client.send_batch(&[b"small", &[0u8; 241]]).is_err());
+
+  pair.client.send(&[1u8; 240]).expect("Could not send");
+  let mut buf = [0u8; 256];
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], &[1u8; 240][..]);
+}
+
+#[test]
+fn test_recv_truncated() {
+  let small_service = gudp::Builder::new()
+    .max_datagram_size(256)
+    .build()
+    .expect("Could not initialize gudp service");
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+
+  let listen_socket = common::bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = small_service.listen(listen_socket).expect("Could not start listener");
+  let client = service.connect_timeout(common::bind(), listen_addr, WAIT).expect("Could not connect");
+  let server = listener.accept_timeout(WAIT).expect("Could not accept");
+
+  // The listener can't take a packet this large, so it's dropped rather than delivered cut short
+  client.send(&[0u8; 1024]).expect("Could not send");
+  client.send(b"fits").expect("Could not send");
+
+  let mut buf = [0u8; 256];
+  let size = server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"fits");
+  assert_eq!(small_service.metrics().truncated_packets(), 1);
+}
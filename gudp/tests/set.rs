@@ -0,0 +1,90 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::bind;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_set_accepts_and_recvs() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+  let mut set = gudp::ConnectionSet::with_listener(listener);
+
+  let clients: Vec<gudp::Connection> = (0..3)
+    .map(|_| service.connect_timeout(bind(), listen_addr, WAIT).expect("Could not connect"))
+    .collect();
+  for (i, client) in clients.iter().enumerate() {
+    client.send(&[i as u8]).expect("Could not send");
+  }
+
+  // One thread accepts every client and hears from each, without ever blocking on a single connection
+  let deadline = Instant::now() + WAIT;
+  let mut buf = [0u8; 64];
+  let mut received = vec![];
+  while received.len() < clients.len() {
+    assert!(Instant::now() < deadline, "Never heard from every client");
+    for ready in set.wait(Some(Duration::from_millis(100))).expect("Could not wait") {
+      let conn = set.get(ready.key).expect("Ready connection not in set");
+      while let Some(size) = conn.try_recv(&mut buf) {
+        received.push(buf[..size.expect("Could not recv")].to_vec());
+      }
+    }
+  }
+  received.sort();
+  assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+  assert_eq!(set.len(), 3);
+
+  // Hanging up is reported as well
+  for client in clients {
+    client.close(WAIT).expect("Could not close");
+  }
+  let deadline = Instant::now() + WAIT;
+  let mut closed = 0;
+  while closed < 3 {
+    assert!(Instant::now() < deadline, "Never saw every client close");
+    for ready in set.wait(Some(Duration::from_millis(100))).expect("Could not wait") {
+      if ready.closed { closed += 1; set.remove(ready.key).expect("Closed connection not in set"); }
+    }
+  }
+  assert!(set.is_empty());
+}
+
+#[test]
+fn test_set_wait_times_out() {
+  let mut set = gudp::ConnectionSet::new();
+  let started = Instant::now();
+  assert!(set.wait(Some(Duration::from_millis(20))).expect("Could not wait").is_empty());
+  assert!(started.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_set_reports_listener_stop_after_ready() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+  let mut set = gudp::ConnectionSet::with_listener(listener);
+
+  let _client = service.connect_timeout(bind(), listen_addr, WAIT).expect("Could not connect");
+  let deadline = Instant::now() + WAIT;
+  while set.is_empty() {
+    assert!(Instant::now() < deadline, "Never accepted the client");
+    set.wait(Some(Duration::from_millis(100))).expect("Could not wait");
+  }
+
+  // Shutting down closes the accepted connection and stops the listener; neither is lost to the other
+  service.shutdown(WAIT).expect("Could not shut down");
+  let deadline = Instant::now() + WAIT;
+  let (mut closed, mut stopped) = (false, false);
+  while !(closed && stopped) {
+    assert!(Instant::now() < deadline, "Never saw both the close and the listener stop");
+    match set.wait(Some(Duration::from_millis(100))) {
+      Ok(ready) => closed |= ready.iter().any(|ready| ready.closed),
+      Err(_) => stopped = true
+    }
+  }
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::bind;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_shutdown_flushes_and_hangs_up() {
+  let server_service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let client_service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = server_service.listen(listen_socket).expect("Could not start listener");
+  let client = client_service.connect_timeout(bind(), listen_addr, WAIT).expect("Could not connect");
+  let server = listener.accept_timeout(WAIT).expect("Could not accept");
+
+  client.send(b"goodbye").expect("Could not send");
+  client_service.shutdown(WAIT).expect("Could not shut down");
+  assert_eq!(client_service.health(), gudp::Health::Stopped);
+
+  // Queued data still arrives, then the peer observes the hangup
+  let mut buf = [0u8; 64];
+  let size = server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"goodbye");
+  match server.recv_timeout(&mut buf, WAIT) {
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+    _ => panic!("Expected ConnectionReset")
+  }
+
+  // Nothing works on a stopped service, but shutting down again is harmless
+  assert_eq!(client.close_reason(), Some(gudp::Error::DaemonGone));
+  assert!(client.send(b"too late").is_err());
+  assert!(client_service.connect_timeout(bind(), listen_addr, WAIT).is_err());
+  client_service.shutdown(WAIT).expect("Could not shut down twice");
+}
+
+#[test]
+fn test_shutdown_stops_listeners() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  service.shutdown(WAIT).expect("Could not shut down");
+  assert!(pair.listener.accept_timeout(WAIT).is_err());
+  assert!(pair.server.send(b"too late").is_err());
+
+  // The listener's channel is already closed, so dropping it never waits
+  let started = Instant::now();
+  drop(pair.listener);
+  assert!(started.elapsed() < WAIT);
+}
+
+#[test]
+fn test_listener_close_timeout() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+
+  listener.close_timeout(WAIT).expect("Could not close listener");
+  let err = service.connect_timeout(bind(), listen_addr, Duration::from_millis(300)).err().expect("Connected to closed listener");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
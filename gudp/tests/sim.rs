@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use clock::mock;
+use gudp::sim;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+fn service(clock: &mock::Clock) -> gudp::Service<sim::Socket> {
+  gudp::Builder::new()
+    .clock(clock.clone())
+    .build_with_transport::<sim::Socket>()
+    .expect("Could not initialize gudp service")
+}
+
+#[test]
+fn test_sim_network() {
+  let network = sim::Network::new();
+  let server_clock = mock::Clock::new(Instant::now());
+  let client_clock = mock::Clock::new(Instant::now());
+  let server_service = service(&server_clock);
+  let client_service = service(&client_clock);
+  let other_service = service(&client_clock);
+
+  let listen_addr: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+  let listener = server_service.listen(network.bind(listen_addr).unwrap()).expect("Could not start listener");
+
+  // Several services share the one network, each on its own virtual address
+  let client = client_service.connect_timeout(network.bind("10.0.0.2:0".parse().unwrap()).unwrap(), listen_addr, WAIT).expect("Could not connect");
+  let server = listener.accept_timeout(WAIT).expect("Could not accept");
+  let other = other_service.connect_timeout(network.bind("10.0.0.3:0".parse().unwrap()).unwrap(), listen_addr, WAIT).expect("Could not connect");
+  let other_server = listener.accept_timeout(WAIT).expect("Could not accept");
+  assert_eq!(server.peer_addr(), client.local_addr());
+  assert_eq!(other_server.peer_addr(), other.local_addr());
+
+  let mut buf = [0u8; 64];
+  client.send(b"hello").expect("Could not send");
+  let size = server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello");
+
+  server.send(b"world").expect("Could not send");
+  let size = client.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"world");
+
+  // Time only passes when the test says so. The client's peer goes quiet past the timeout as far as it can tell
+  client_clock.tick_ms(20_000);
+  client_service.wake().expect("Could not wake");
+  assert!(client.recv_timeout(&mut buf, WAIT).is_err());
+  assert_eq!(client.close_reason(), Some(gudp::Error::PeerTimedOut));
+}
+
+#[test]
+fn test_sim_unreachable() {
+  let network = sim::Network::new();
+  let clock = mock::Clock::new(Instant::now());
+  let client_service = service(&clock);
+
+  // Nobody is bound there, so the handshake goes nowhere
+  let socket = network.bind("10.0.0.2:0".parse().unwrap()).unwrap();
+  let err = client_service.connect_timeout(socket, "10.0.0.1:9000".parse::<SocketAddr>().unwrap(), Duration::from_millis(50)).err().expect("Connected to nobody");
+  assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_dropping_clone_keeps_connection() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  let reader = pair.client.clone();
+  drop(reader);
+
+  pair.client.send(b"still here").expect("Dropping a clone should not hang up");
+  let mut buf = [0u8; 64];
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"still here");
+}
+
+#[test]
+fn test_split_halves() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+  let (send_half, recv_half) = pair.client.split();
+
+  let reader = std::thread::spawn(move || {
+    let mut buf = [0u8; 64];
+    let size = recv_half.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+    buf[..size].to_vec()
+  });
+
+  send_half.send(b"ping").expect("Could not send");
+  let mut buf = [0u8; 64];
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"ping");
+
+  pair.server.send(b"pong").expect("Could not send");
+  assert_eq!(reader.join().expect("Reader panicked"), b"pong");
+
+  // The receiving half is gone, but we can still send
+  send_half.send(b"half-closed").expect("Could not send");
+  let size = pair.server.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"half-closed");
+}
+
+#[test]
+fn test_half_close_send() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+  let (send_half, recv_half) = pair.client.split();
+  drop(send_half);
+
+  // The sending half is gone, but we can still receive
+  pair.server.send(b"hello").expect("Could not send");
+  let mut buf = [0u8; 64];
+  let size = recv_half.recv_timeout(&mut buf, WAIT).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello");
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use common::bind;
+
+#[test]
+fn test_accept_timeout() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let listener = service.listen(bind()).expect("Could not start listener");
+
+  assert!(listener.try_accept().is_none());
+  match listener.accept_timeout(Duration::from_millis(20)) {
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+    _ => panic!("Expected TimedOut")
+  }
+}
+
+#[test]
+fn test_connect_timeout() {
+  // Nobody is listening on the peer socket, so no reply ever arrives
+  let silent_peer = UdpSocket::bind("127.0.0.1:0").expect("Could not bind");
+  silent_peer.set_nonblocking(true).expect("Could not set nonblocking!");
+  let service = gudp::Builder::new()
+    .handshake_retry(Duration::from_millis(5), 2, Duration::from_millis(20))
+    .build()
+    .expect("Could not initialize gudp service");
+
+  match service.connect_timeout(bind(), silent_peer.local_addr().unwrap(), Duration::from_millis(100)) {
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+    _ => panic!("Expected TimedOut")
+  }
+
+  // The initial packet should have been resent while we waited
+  let mut buf = [0u8; 64];
+  let mut received = 0;
+  while silent_peer.recv(&mut buf).is_ok() { received += 1; }
+  assert!(received > 1, "Expected handshake retries, received {} packets", received);
+}
+
+#[test]
+fn test_invalid_handshake_retry() {
+  let builders = [
+    gudp::Builder::new().handshake_retry(Duration::from_millis(5), 0, Duration::from_millis(20)),
+    gudp::Builder::new().handshake_retry(Duration::from_millis(50), 2, Duration::from_millis(20))
+  ];
+  for builder in builders {
+    let err = builder.build().err().expect("Built with an invalid handshake retry");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  }
+}
+
+#[test]
+fn test_recv_timeout() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let pair = common::new(&service);
+
+  let mut buf = [0u8; 64];
+  match pair.server.recv_timeout(&mut buf, Duration::from_millis(20)) {
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+    _ => panic!("Expected TimedOut")
+  }
+
+  pair.client.send(b"hello").expect("Could not send");
+  let size = pair.server.recv_timeout(&mut buf, Duration::from_secs(1)).expect("Could not recv");
+  assert_eq!(&buf[..size], b"hello");
+}
+
+#[test]
+fn test_incoming() {
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
+  let listen_socket = bind();
+  let listen_addr = listen_socket.local_addr().unwrap();
+  let listener = service.listen(listen_socket).expect("Could not start listener");
+  let client = service.connect_timeout(bind(), listen_addr, Duration::from_secs(1)).expect("Could not connect");
+
+  let accepted = listener.incoming().next().expect("Listener closed").expect("Could not accept");
+  assert_eq!(accepted.peer_addr(), client.local_addr());
+}
@@ -21,6 +21,11 @@ impl<'a, T> SlicePair<'a, T> {
     self.slice_front.len() + self.slice_back.len()
   }
 
+  // Both halves in order, to read without copying. The back is empty unless the pair really is split
+  pub fn as_slices(&self) -> (&'a [T], &'a [T]) {
+    (self.slice_front, self.slice_back)
+  }
+
   pub fn range<R: RangeBounds<usize>>(&self, bounds: R) -> SlicePair<T> {
     match (bounds.start_bound(), bounds.end_bound()) {
       (Bound::Unbounded, Bound::Unbounded) => SlicePair::new(self.slice_front, self.slice_back),
@@ -576,4 +581,15 @@ mod tests {
       assert_eq!(pair.to_vec(), src);
     }
   }
+
+  mod slices_tests {
+    #[test]
+    fn slices_of_a_range_span_both_halves() {
+      let front_nums = [1, 2, 3];
+      let back_nums = [4, 5, 6];
+      let pair: crate::SlicePair<u8> = crate::SlicePair::new(&front_nums, &back_nums);
+      assert_eq!(pair.range(1..4).as_slices(), (&[2u8, 3][..], &[4u8][..]));
+      assert_eq!(pair.range(..2).as_slices(), (&[1u8, 2][..], &[][..]));
+    }
+  }
 }
@@ -9,7 +9,7 @@ fn main() {
   let range_start = range_start_string.parse::<u16>().expect(usage);
 
   let dst_addr = format!("127.0.0.1:{}", dst_port);
-  let service = gudp::Service::initialize().expect("Could not initialize gudp service");
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
   let mut threads = vec![];
 
   for src_port in range_start..(range_start + range_len) {
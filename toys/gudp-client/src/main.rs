@@ -25,50 +25,37 @@ fn main() {
   }
 }
 
+// One thread serves every connection: the set accepts new ones and reports which have messages waiting
 fn listen(listener: gudp::Listener, src_port: u16) {
   println!("Listening on {} for messages", src_port);
-  let mut n_remaining: Option<usize> = None;
-  let mut threads = vec![];
+  let mut set = gudp::ConnectionSet::with_listener(listener);
+  let mut heartbeats = std::collections::HashMap::new();
+  let mut buf = [0u8; 1000];
 
   loop {
-    match n_remaining {
-      // If we eventually run out of connections, break and join all threads before exiting
-      Some(n) if n <= 0 => break,
-      Some(ref mut n) => {
-        let conn = listener.accept().expect("Could not accept connection on listener");
-        threads.push(std::thread::spawn(move || { on_accept(conn) }));
-        *n -= 1;
-      },
-
-      // If we handle unlimited connections, simply loop forever and discard join handles
-      None => {
-        let conn = listener.accept().expect("Could not accept connection on listener");
-        std::thread::spawn(move || { on_accept(conn) });
+    for ready in set.wait(None).expect("Listener stopped") {
+      let conn = set.get(ready.key).expect("Ready connection not in set");
+      if ready.accepted {
+        println!("Accepted connection on {} for messages from {}", conn.local_addr().port(), conn.peer_addr().port());
+      }
+      if ready.readable { on_readable(conn, heartbeats.entry(ready.key).or_insert(0), &mut buf); }
+      if ready.closed {
+        set.remove(ready.key);
+        heartbeats.remove(&ready.key);
       }
     }
   }
-
-  println!("Reached limit on accepting listener connections. Closing listener");
-  drop(listener);
-  for thread in threads {
-    thread.join().expect("Could not join accepted connection thread").ok();
-  }
 }
 
-fn on_accept(conn: gudp::Connection) -> std::io::Result<()> {
-  let src_port = conn.local_addr().port();
+fn on_readable(conn: &gudp::Connection, heartbeats: &mut usize, buf: &mut [u8]) {
   let dst_port = conn.peer_addr().port();
-  println!("Accepted connection on {} for messages from {}", src_port, dst_port);
-  let mut buf = [0u8; 1000];
-  let mut heartbeats = 0;
-  loop {
-    let recv_len = conn.recv(&mut buf)?;
+  while let Some(Ok(recv_len)) = conn.try_recv(buf) {
     let recv_str = std::str::from_utf8(&buf[..recv_len]).expect("Did not recv utf8");
     if recv_str == "ping" {
-      heartbeats += 1;
+      *heartbeats += 1;
     } else {
       println!("[From {} ({})]: {}", dst_port, heartbeats, recv_str);
-      heartbeats = 0;
+      *heartbeats = 0;
     }
   }
 }
@@ -79,7 +66,7 @@ fn ping(conn: gudp::Connection) {
   let dst_port = conn.peer_addr().port();
   println!("Sending stdin from {} to {}", src_port, dst_port);
   let mut buf = [0u8; 1000];
-  let stdin = std::io::stdin();
+  //let stdin = std::io::stdin();
   let tzero = std::time::Instant::now();
   std::thread::spawn(move || loop {
     match reader.recv(&mut buf) {
@@ -8,29 +8,34 @@ fn main() {
   let socket = std::net::UdpSocket::bind(listen_addr).expect("Could not bind");
   socket.set_nonblocking(true).expect("Could not set nonblocking!");
 
-  let service = gudp::Service::initialize().expect("Could not initialize gudp service");
+  let service = gudp::Builder::new().build().expect("Could not initialize gudp service");
   let listener = service.listen(socket).expect("Could not start listener");
   listen(listener, listen_port);
 }
 
+// One thread serves every connection: the set accepts new ones and reports which have something to echo
 fn listen(listener: gudp::Listener, src_port: u16) {
   println!("Echoing messages from {}", src_port);
+  let mut set = gudp::ConnectionSet::with_listener(listener);
+  let mut buf = [0u8; 1000];
   loop {
-    let conn = listener.accept().expect("Could not accept connection on listener");
-    std::thread::spawn(move || { on_accept(conn) });
+    for ready in set.wait(None).expect("Listener stopped") {
+      if ready.accepted {
+        let conn = set.get(ready.key).expect("Accepted connection not in set");
+        println!("Accepted connection on {} for messages from {}", conn.local_addr().port(), conn.peer_addr().port());
+      }
+      if ready.readable { echo(set.get(ready.key).expect("Ready connection not in set"), &mut buf); }
+      if ready.closed { set.remove(ready.key); }
+    }
   }
 }
 
-fn on_accept(conn: gudp::Connection) -> std::io::Result<()> {
-  let src_port = conn.local_addr().port();
-  let dst_port = conn.peer_addr().port();
-  println!("Accepted connection on {} for messages from {}", src_port, dst_port);
-  let mut buf = [0u8; 1000];
-  loop {
-    let recv_len = conn.recv(&mut buf)?;
+fn echo(conn: &gudp::Connection, buf: &mut [u8]) {
+  while let Some(Ok(recv_len)) = conn.try_recv(buf) {
     let recv_str = std::str::from_utf8(&buf[..recv_len]).expect("Did not recv utf8");
     if recv_str != "ping" {
-      conn.send(recv_str.as_bytes()).expect("Could not send");
+      // A connection closing under us mustn't take down every other one
+      if conn.send(recv_str.as_bytes()).is_err() { return; }
     }
   }
 }
@@ -41,54 +41,54 @@ fn main() {
   std::fs::write("out.txt", output_string).expect("Could not write");
 }
 
+// One thread serves every worker: the set accepts them and reports which have lines waiting
 fn listen(listener: gudp::Listener, workers: usize, tx: Sender<(usize, String)>) {
-  let mut handles = vec![];
-  for n in 0..workers {
-    let conn = listener.accept().expect("Could not accept connection on listener");
-    {
-      let tx = tx.clone();
-      handles.push(std::thread::spawn(move || {
-        let res = on_accept(conn, tx);
-        println!("Finished with conn {}: {:?}", n, res);
-        res
-      }));
-    }
-  }
-  drop(listener);
+  let mut set = gudp::ConnectionSet::with_listener(listener);
+  let mut buf = [0u8; 1024];
+  let mut counter = 0;
+  let mut finished = 0;
 
-  for handle in handles.into_iter() {
-    handle.join().expect("Thread failed");
+  while finished < workers {
+    for ready in set.wait(None).expect("Listener stopped") {
+      let conn = match set.get(ready.key) {
+        Some(conn) => conn,
+        None => continue
+      };
+      if ready.accepted { println!("Accepted {}", conn.peer_addr()); }
+
+      let res = if ready.readable { on_readable(conn, &tx, &mut counter, &mut buf) } else { Ok(false) };
+      if ready.closed || !matches!(res, Ok(false)) {
+        println!("Finished with conn {}: {:?}", ready.key, res);
+        set.remove(ready.key);
+        finished += 1;
+      }
+    }
   }
 }
 
-fn on_accept(conn: gudp::Connection, tx: Sender<(usize, String)>) -> std::io::Result<()> {
-  println!("Accepted {}", conn.peer_addr());
-  let mut buf = [0u8; 1024];
+// Returns true once the worker is done
+fn on_readable(conn: &gudp::Connection, tx: &Sender<(usize, String)>, counter: &mut usize, buf: &mut [u8]) -> std::io::Result<bool> {
   let mut bytes = [0u8; 8];
-  let mut counter = 0;
-  loop {
-    match conn.recv(&mut buf) {
-      Err(e) => return Err(e),
-      Ok(recv_len) => {
-        if recv_len < 8 { continue }
-        counter += 1;
-        if counter % 10000 == 0 {
-          println!("Recv checkpoint {}", counter);
-        }
+  while let Some(recv_len) = conn.try_recv(buf) {
+    let recv_len = recv_len?;
+    if recv_len < 8 { continue }
+    *counter += 1;
+    if *counter % 10000 == 0 {
+      println!("Recv checkpoint {}", counter);
+    }
 
-        if &buf[8..recv_len] == b"!done" {
-          conn.send(b"");
-          return Ok(());
-        }
+    if &buf[8..recv_len] == b"!done" {
+      conn.send(b"");
+      return Ok(true);
+    }
 
-        bytes.copy_from_slice(&buf[0..8]);
-        let idx = usize::from_be_bytes(bytes);
-        let rest = String::from_utf8_lossy(&buf[8..recv_len]);
-        tx.send((idx, rest.to_string())).expect("Could not send");
+    bytes.copy_from_slice(&buf[0..8]);
+    let idx = usize::from_be_bytes(bytes);
+    let rest = String::from_utf8_lossy(&buf[8..recv_len]);
+    tx.send((idx, rest.to_string())).expect("Could not send");
 
-        // Ack with a heartbeat
-        conn.send(b"");
-      }
-    }
+    // Ack with a heartbeat
+    conn.send(b"");
   }
+  Ok(false)
 }